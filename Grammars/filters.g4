@@ -16,10 +16,12 @@ bag_expression
     | intersection
     | union
     | bag
+    | limit
+    | offset
     // Spatial Operators
     | inside
     | outside
-    //| shape
+    | shape
     ;
 
 /**********************************************************************/
@@ -90,6 +92,16 @@ bag
     : 'bag' '{' bag_expression (',' bag_expression )* '}'
     ;
 
+/* Returns the first n points of the bag, in the order of its results. */
+limit
+    : 'limit' '(' natural ',' bag_expression ')'
+    ;
+
+/* Returns the points of the bag, except its first n ones. */
+offset
+    : 'offset' '(' natural ',' bag_expression ')'
+    ;
+
 /**********************************************************************/
 /* SPATIAL OPERATORS                                                  */
 /**********************************************************************/
@@ -158,25 +170,28 @@ point
  *   nifti{
  *     lower_corner: position,  // Optional, default to the origin
  *     rotation: [ position+ ], // Optional, no rotation by default
- *     bytes: uri(STRING),      // uri to the NIfTI object
- *     spaceId: string
+ *     bytes: uri(STRING),      // uri to the NIfTI object, or
+ *            bytes(STRING),    // in-line base64 encoded NIfTI object
+ *     spaceId: string          // Optional, default to the universe
  *   }
  */
 nifti
     : 'nifti' '{'
         (position ',' )?
         ( '[' position ( ',' position )* ']' ',' )?
-        byte_provider ','
-        STRING
+        byte_provider
+        ( ',' STRING )?
       '}'
     ;
 
 /* TODO: STRING is assumed to be a well-formed URI, fully specify here?
  *
- * TODO: Add a provider for in-line raw-byte stream.
+ * The in-line raw-byte stream is base64 encoded, and optionally
+ * gzip-compressed.
  */
 byte_provider
     : 'uri' '(' STRING ')'
+    | 'bytes' '(' STRING ')'
     ;
 
 /**********************************************************************/
@@ -257,15 +272,25 @@ fragment SAFECODEPOINT
 
 /* No optional leading '+' */
 json_number
-    : '-'? NUM
+    : '-'? ( INT | NUM )
     ;
 
 positive_number
-    : '+'? NUM
+    : '+'? ( INT | NUM )
     ;
 
 number
-    : ( '+' | '-' )? NUM
+    : ( '+' | '-' )? ( INT | NUM )
+    ;
+
+/* Numbers of points, which are integers. */
+natural
+    : INT
+    ;
+
+/* Defined before NUM, so that integers are INT tokens. */
+INT
+    : INTEGER
     ;
 
 NUM
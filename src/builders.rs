@@ -0,0 +1,388 @@
+use std::convert::TryFrom;
+use std::ops::Not;
+
+use mercator_db::space::Space;
+
+use super::error::Span;
+use super::symbols::*;
+
+// Fluent construction of queries, producing the same expressions as the
+// parser. Optional arguments, such as the reference spaces, take their
+// default values, and can be changed afterwards.
+//
+// For example, `filter(<(.[0], [1]), inside(point{[0, 0], "space"}))`
+// is built with:
+//
+//     Bag::inside(Shape::point(vec![0.0, 0.0]).in_space("space"))
+//         .filter(Predicate::less(LiteralSelector::root().index(0), vec![1.0]))
+
+fn universe() -> String {
+    Space::universe().name().clone()
+}
+
+/**********************************************************************/
+/* FORMATTING DATA                                                    */
+/**********************************************************************/
+impl Projection {
+    /// Values of the positions in `bag`, as a NIfTI volume. Without
+    /// selector, each position has the value one.
+    pub fn nifti(selector: Option<LiteralSelector>, bag: Bag) -> Self {
+        let selector = selector.unwrap_or_else(|| LiteralSelector(vec![]));
+        Projection::Nifti(universe(), selector, bag, Span::default())
+    }
+
+    /// Objects in `bag`, formatted as JSON by `format`.
+    pub fn json<J: Into<JsonValue>>(format: J, bag: Bag) -> Self {
+        Projection::Json(universe(), format.into(), bag, Span::default())
+    }
+
+    /// Set the reference space of the output.
+    pub fn in_space(self, space: &str) -> Self {
+        match self {
+            Projection::Nifti(_, selector, bag, span) => {
+                Projection::Nifti(space.to_string(), selector, bag, span)
+            }
+            Projection::Json(_, format, bag, span) => {
+                Projection::Json(space.to_string(), format, bag, span)
+            }
+        }
+    }
+}
+
+impl TryFrom<serde_json::Value> for JsonValue {
+    type Error = String;
+
+    /// Template producing `value` as is. Fails on numbers which are
+    /// neither 64-bit integers nor floats.
+    fn try_from(value: serde_json::Value) -> Result<Self, Self::Error> {
+        Ok(match value {
+            serde_json::Value::Null => JsonValue::Null,
+            serde_json::Value::Bool(b) => JsonValue::Bool(b),
+            serde_json::Value::Number(n) => match (n.as_i64(), n.as_f64()) {
+                (Some(i), _) => JsonValue::JsonNumber(LiteralNumber::Int(i)),
+                (None, Some(f)) => JsonValue::JsonNumber(LiteralNumber::Float(f)),
+                (None, None) => return Err(format!("Invalid number '{}'", n)),
+            },
+            serde_json::Value::String(s) => JsonValue::String(s),
+            serde_json::Value::Array(values) => JsonValue::Array(
+                values
+                    .into_iter()
+                    .map(JsonValue::try_from)
+                    .collect::<Result<_, _>>()?,
+            ),
+            serde_json::Value::Object(map) => JsonValue::Object(
+                map.into_iter()
+                    .map(|(k, v)| Ok((k, JsonValue::try_from(v)?)))
+                    .collect::<Result<_, String>>()?,
+            ),
+        })
+    }
+}
+
+impl From<LiteralSelector> for JsonValue {
+    fn from(selector: LiteralSelector) -> Self {
+        JsonValue::Selector(selector)
+    }
+}
+
+impl From<Aggregation> for JsonValue {
+    fn from(aggregation: Aggregation) -> Self {
+        JsonValue::Aggregation(aggregation)
+    }
+}
+
+impl Aggregation {
+    pub fn count(selector: LiteralSelector) -> Self {
+        Aggregation::Count(false, selector)
+    }
+
+    pub fn count_distinct(selector: LiteralSelector) -> Self {
+        Aggregation::Count(true, selector)
+    }
+
+    pub fn sum(selector: LiteralSelector) -> Self {
+        Aggregation::Sum(selector)
+    }
+
+    pub fn min(selector: LiteralSelector) -> Self {
+        Aggregation::Min(selector)
+    }
+
+    pub fn max(selector: LiteralSelector) -> Self {
+        Aggregation::Max(selector)
+    }
+}
+
+/**********************************************************************/
+/* SELECTING / FILTERING DATA                                         */
+/**********************************************************************/
+impl Bag {
+    /// Objects inside the shape, surface included.
+    pub fn inside(shape: Shape) -> Self {
+        Bag::Inside(shape, Span::default())
+    }
+
+    /// Objects outside the shape, surface included.
+    pub fn outside(shape: Shape) -> Self {
+        Bag::Outside(shape, Span::default())
+    }
+
+    /// Positions of the shape, instead of the objects within it.
+    pub fn shape(shape: Shape) -> Self {
+        Bag::Shape(shape, Span::default())
+    }
+
+    /// Objects of all the bags.
+    pub fn bag(bags: Vec<Bag>) -> Self {
+        Bag::Bag(bags, Span::default())
+    }
+
+    pub fn distinct(self) -> Self {
+        Bag::Distinct(Box::new(self), Span::default())
+    }
+
+    /// First `n` objects of the bag.
+    pub fn limit(self, n: usize) -> Self {
+        Bag::Limit(n, Box::new(self), Span::default())
+    }
+
+    /// Objects of the bag, except the first `n` ones.
+    pub fn offset(self, n: usize) -> Self {
+        Bag::Offset(n, Box::new(self), Span::default())
+    }
+
+    pub fn complement(self) -> Self {
+        Bag::Complement(Box::new(self), Span::default())
+    }
+
+    pub fn intersection(self, other: Bag) -> Self {
+        Bag::Intersection(Box::new(self), Box::new(other), Span::default())
+    }
+
+    pub fn union(self, other: Bag) -> Self {
+        Bag::Union(Box::new(self), Box::new(other), Span::default())
+    }
+
+    /// Objects of the bag which respect the predicate.
+    pub fn filter(self, predicate: Predicate) -> Self {
+        Bag::Filter(Some(predicate), Box::new(self), Span::default())
+    }
+}
+
+impl Predicate {
+    pub fn less<P: Into<Position>, L: Into<LiteralPosition>>(position: P, literal: L) -> Self {
+        Predicate::Less(position.into(), literal.into())
+    }
+
+    pub fn greater<P: Into<Position>, L: Into<LiteralPosition>>(position: P, literal: L) -> Self {
+        Predicate::Greater(position.into(), literal.into())
+    }
+
+    pub fn equal<P: Into<Position>, L: Into<LiteralPosition>>(position: P, literal: L) -> Self {
+        Predicate::Equal(position.into(), literal.into())
+    }
+
+    pub fn and(self, other: Predicate) -> Self {
+        Predicate::And(Box::new(self), Box::new(other))
+    }
+
+    pub fn or(self, other: Predicate) -> Self {
+        Predicate::Or(Box::new(self), Box::new(other))
+    }
+}
+
+impl Not for Predicate {
+    type Output = Predicate;
+
+    fn not(self) -> Self::Output {
+        Predicate::Not(Box::new(self))
+    }
+}
+
+/**********************************************************************/
+/* SPATIAL OPERATORS                                                  */
+/**********************************************************************/
+impl Shape {
+    pub fn point<P: Into<LiteralPosition>>(position: P) -> Self {
+        Shape::Point(universe(), position.into())
+    }
+
+    /// Hyperrectangle aligned with the axes, defined by two opposite
+    /// vertices.
+    pub fn hyperrectangle<P: Into<LiteralPosition>>(low: P, high: P) -> Self {
+        Shape::HyperRectangle(universe(), vec![low.into(), high.into()])
+    }
+
+    /// Arbitrarily oriented hyperrectangle, defined by all its 2^k
+    /// vertices, in any order. Fails when the vertices do not define a
+    /// hyperrectangle.
+    pub fn oriented_hyperrectangle<P: Into<LiteralPosition>>(
+        vertices: Vec<P>,
+    ) -> Result<Self, String> {
+        let vertices = vertices.into_iter().map(Into::into).collect::<Vec<_>>();
+        OrientedBox::new(&vertices)?;
+
+        Ok(Shape::HyperRectangle(universe(), vertices))
+    }
+
+    pub fn sphere<P: Into<LiteralPosition>, R: Into<LiteralNumber>>(center: P, radius: R) -> Self {
+        Shape::HyperSphere(universe(), center.into(), radius.into())
+    }
+
+    pub fn label(id: &str) -> Self {
+        Shape::Label(universe(), id.to_string())
+    }
+
+    /// Non-zero voxels of a NIfTI volume, placed at the origin, without
+    /// rotation.
+    pub fn nifti(provider: ByteProvider) -> Self {
+        let transform = Transform {
+            reference: universe(),
+            offset: vec![],
+            rotation: vec![],
+        };
+
+        Shape::Nifti(transform, provider)
+    }
+
+    /// Set the reference space of the shape.
+    pub fn in_space(self, space: &str) -> Self {
+        let space = space.to_string();
+        match self {
+            Shape::Point(_, position) => Shape::Point(space, position),
+            Shape::HyperRectangle(_, vertices) => Shape::HyperRectangle(space, vertices),
+            Shape::HyperSphere(_, center, radius) => Shape::HyperSphere(space, center, radius),
+            Shape::Label(_, id) => Shape::Label(space, id),
+            Shape::Nifti(transform, provider) => Shape::Nifti(
+                Transform {
+                    reference: space,
+                    ..transform
+                },
+                provider,
+            ),
+        }
+    }
+}
+
+/**********************************************************************/
+/* POSITIONS                                                          */
+/**********************************************************************/
+impl Position {
+    /// Compare lexicographically the string designated by `selector`
+    /// with `value`.
+    pub fn str_cmp(selector: LiteralSelector, value: &str) -> Self {
+        Position::StrCmp(selector, value.to_string())
+    }
+
+    /// Same as `str_cmp`, ignoring the case.
+    pub fn str_cmp_ignore_case(selector: LiteralSelector, value: &str) -> Self {
+        Position::StrCmpIgnoreCase(selector, value.to_string())
+    }
+}
+
+impl From<LiteralSelector> for Position {
+    fn from(selector: LiteralSelector) -> Self {
+        Position::Selector(selector)
+    }
+}
+
+impl From<LiteralPosition> for Position {
+    fn from(position: LiteralPosition) -> Self {
+        Position::Literal(position)
+    }
+}
+
+impl From<Vec<f64>> for Position {
+    fn from(position: Vec<f64>) -> Self {
+        Position::Literal(position.into())
+    }
+}
+
+impl From<Vec<i64>> for Position {
+    fn from(position: Vec<i64>) -> Self {
+        Position::Literal(position.into())
+    }
+}
+
+// Whether `name` can be written as a field of a selector.
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+impl LiteralSelector {
+    /// The object itself, written `.`.
+    pub fn root() -> Self {
+        LiteralSelector(vec![Field(String::new(), None)])
+    }
+
+    /// Select the field `name` of the current value, if `name` is an
+    /// identifier: ASCII letters, digits and underscores, not starting
+    /// with a digit, as in the queries.
+    pub fn try_field(self, name: &str) -> Result<Self, String> {
+        if !is_identifier(name) {
+            return Err(format!("Invalid field name '{}'", name));
+        }
+
+        let LiteralSelector(mut fields) = self;
+        if let [Field(root, None)] = fields.as_slice() {
+            if root.is_empty() {
+                fields.clear();
+            }
+        }
+        fields.push(Field(name.to_string(), None));
+
+        Ok(LiteralSelector(fields))
+    }
+
+    /// Select the field `name` of the current value. Use `try_field` for
+    /// names which are not known in advance.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` is not an identifier, see `try_field`.
+    pub fn field(self, name: &str) -> Self {
+        match self.try_field(name) {
+            Ok(selector) => selector,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Select the element `index` of the current value.
+    pub fn index(self, index: usize) -> Self {
+        let LiteralSelector(mut fields) = self;
+        match fields.last_mut() {
+            Some(Field(_, i @ None)) => *i = Some(index),
+            _ => fields.push(Field(String::new(), Some(index))),
+        }
+
+        LiteralSelector(fields)
+    }
+}
+
+impl From<f64> for LiteralNumber {
+    /// # Panics
+    ///
+    /// Panics if `x` is not finite, as the queries cannot express it.
+    fn from(x: f64) -> Self {
+        assert!(x.is_finite(), "Invalid number '{}'", x);
+        LiteralNumber::Float(x)
+    }
+}
+
+impl From<i64> for LiteralNumber {
+    fn from(x: i64) -> Self {
+        LiteralNumber::Int(x)
+    }
+}
+
+impl From<Vec<i64>> for LiteralPosition {
+    fn from(v: Vec<i64>) -> Self {
+        LiteralPosition(v.into_iter().map(LiteralNumber::Int).collect())
+    }
+}
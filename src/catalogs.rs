@@ -0,0 +1,121 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+
+use mercator_db::CoreQueryParameters;
+
+use super::histograms::Histograms;
+use super::labels::LabelExtent;
+use super::symbols::*;
+
+// Upper bound on the number of label extents kept by a catalog.
+const MAX_LABELS: usize = 1 << 10;
+
+/// Knowledge of the data of a core, gathered beforehand by the caller,
+/// to choose how to execute the queries without reading the data.
+///
+/// Without it, the costs of the queries are predicted from the volumes
+/// of their shapes only.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Catalog {
+    histograms: Option<Histograms>,
+    labels: HashMap<String, LabelExtent>,
+    // Labels in the order they were inserted, the oldest first.
+    inserted: VecDeque<String>,
+}
+
+impl Catalog {
+    /// Estimate the number of objects of the queries from `histograms`.
+    pub fn with_histograms(mut self, histograms: Histograms) -> Self {
+        self.histograms = Some(histograms);
+        self
+    }
+
+    /// Histograms of the reference spaces of the core, if known.
+    pub fn histograms(&self) -> Option<&Histograms> {
+        self.histograms.as_ref()
+    }
+
+    /// Use `extent` as the extent of the label `id`. At most `MAX_LABELS`
+    /// extents are kept, the oldest ones being forgotten first.
+    pub fn insert_label(&mut self, id: &str, extent: LabelExtent) {
+        if self.labels.insert(id.to_string(), extent).is_some() {
+            return;
+        }
+
+        self.inserted.push_back(id.to_string());
+        while self.inserted.len() > MAX_LABELS {
+            if let Some(oldest) = self.inserted.pop_front() {
+                self.labels.remove(&oldest);
+            }
+        }
+    }
+
+    /// Extent of the label `id`, if known.
+    pub fn label(&self, id: &str) -> Option<&LabelExtent> {
+        self.labels.get(id)
+    }
+
+    /// Look up in the core `core_id` the extents of the labels used by
+    /// `bag`, which are not known yet.
+    pub fn lookup_labels(
+        &mut self,
+        core_id: &str,
+        parameters: &CoreQueryParameters,
+        bag: &Bag,
+    ) -> Result<(), String> {
+        let mut ids = vec![];
+        labels(bag, &mut ids);
+
+        for id in ids {
+            if self.label(id).is_none() {
+                let extent = LabelExtent::lookup(core_id, parameters, id)?;
+                self.insert_label(id, extent);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The catalog, completed with the extents of the labels used by
+    /// `bag` it does not know yet, looked up in the core `core_id`. The
+    /// catalog is only copied when labels are missing. Labels which
+    /// cannot be looked up are left unknown, for the execution to report
+    /// them.
+    pub fn with_labels_of(
+        &self,
+        core_id: &str,
+        parameters: &CoreQueryParameters,
+        bag: &Bag,
+    ) -> Cow<'_, Catalog> {
+        let mut ids = vec![];
+        labels(bag, &mut ids);
+        ids.retain(|id| self.label(id).is_none());
+        if ids.is_empty() {
+            return Cow::Borrowed(self);
+        }
+
+        let mut catalog = self.clone();
+        for id in ids {
+            if let Ok(extent) = LabelExtent::lookup(core_id, parameters, id) {
+                catalog.insert_label(id, extent);
+            }
+        }
+
+        Cow::Owned(catalog)
+    }
+}
+
+// Identifiers of the labels used by `bag`.
+fn labels<'b>(bag: &'b Bag, ids: &mut Vec<&'b str>) {
+    match bag {
+        Bag::Inside(Shape::Label(_, id), _)
+        | Bag::Outside(Shape::Label(_, id), _)
+        | Bag::Shape(Shape::Label(_, id), _) => ids.push(id),
+        _ => {
+            for operand in bag.operands() {
+                labels(operand, ids);
+            }
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use std::fmt::Display;
+
+use super::symbols::*;
+
+/// Position in the results of a query, to read them one page after the
+/// other.
+///
+/// The results of a paged query are produced in a stable order: the
+/// same query on the same core returns the same objects in the same
+/// order, whatever the catalog. A page can thus be resumed where the
+/// previous one ended.
+///
+/// The optimizer, however, changes the order of the results, for
+/// example when it pushes filters down or flattens bags. All the pages
+/// of a query have to be read with the same optimization setting, and
+/// the cursor is given the query as executed: optimized or not. The
+/// tokens of an optimized query then belong to another query than the
+/// unoptimized one, whenever the optimizer rewrites it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cursor {
+    // Fingerprint of the text of the query.
+    query: u64,
+    /// Number of objects of the results before the cursor.
+    pub offset: usize,
+}
+
+// FNV-1a hash of the text of the query. Unlike the hasher of the
+// standard library, it is stable across releases, so tokens remain
+// valid when the service is updated.
+fn fingerprint<Q: Display>(query: &Q) -> u64 {
+    query
+        .to_string()
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+        })
+}
+
+impl Cursor {
+    /// Cursor at the start of the results of `query`.
+    pub fn start<Q: Display>(query: &Q) -> Self {
+        Cursor {
+            query: fingerprint(query),
+            offset: 0,
+        }
+    }
+
+    /// Cursor described by `token`, as produced by `Cursor::token` for
+    /// the same `query`.
+    pub fn resume<Q: Display>(query: &Q, token: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid cursor '{}'", token);
+        if token.len() <= 16 || !token.is_ascii() {
+            return Err(invalid());
+        }
+
+        let (hash, offset) = token.split_at(16);
+        let hash = u64::from_str_radix(hash, 16).map_err(|_| invalid())?;
+        let offset = usize::from_str_radix(offset, 16).map_err(|_| invalid())?;
+
+        if hash != fingerprint(query) {
+            return Err(format!("Cursor '{}' belongs to another query", token));
+        }
+
+        Ok(Cursor {
+            query: hash,
+            offset,
+        })
+    }
+
+    /// Opaque token, to resume reading the results from the cursor.
+    pub fn token(&self) -> String {
+        format!("{:016x}{:x}", self.query, self.offset)
+    }
+
+    /// Cursor after `count` more objects. A page with fewer objects than
+    /// requested is the last one.
+    pub fn advance(&self, count: usize) -> Self {
+        Cursor {
+            query: self.query,
+            offset: self.offset.saturating_add(count),
+        }
+    }
+}
+
+impl Bag {
+    /// At most `size` objects of the bag, starting at `cursor`.
+    pub fn page(self, cursor: &Cursor, size: usize) -> Self {
+        self.offset(cursor.offset).limit(size)
+    }
+
+    /// Whether only some of the objects of the bag, or of one of its
+    /// operands, are read. The results are then combined in an order
+    /// which does not depend on the catalog, see `Cursor`.
+    pub fn is_paged(&self) -> bool {
+        match self {
+            Bag::Limit(_, _, _) | Bag::Offset(_, _, _) => true,
+            _ => self.operands().iter().any(|bag| bag.is_paged()),
+        }
+    }
+}
+
+impl Projection {
+    /// Projection of at most `size` objects of the bag, starting at
+    /// `cursor`.
+    pub fn page(self, cursor: &Cursor, size: usize) -> Self {
+        match self {
+            Projection::Nifti(space_id, selector, bag, span) => {
+                Projection::Nifti(space_id, selector, bag.page(cursor, size), span)
+            }
+            Projection::Json(space_id, format, bag, span) => {
+                Projection::Json(space_id, format, bag.page(cursor, size), span)
+            }
+        }
+    }
+}
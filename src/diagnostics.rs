@@ -0,0 +1,185 @@
+use std::fmt::Write;
+
+use super::error::Error;
+
+// Keywords of the grammar, grouped by the kind of expression they start.
+const BAGS: &[&str] = &[
+    "distinct",
+    "filter",
+    "complement",
+    "intersection",
+    "union",
+    "bag",
+    "limit",
+    "offset",
+    "inside",
+    "outside",
+    "shape",
+];
+const SHAPES: &[&str] = &["point", "hyperrectangle", "hypersphere", "label", "nifti"];
+const PREDICATES: &[&str] = &["<", ">", "=", "!", "&", "|"];
+const PROJECTIONS: &[&str] = &["nifti", "json"];
+const AGGREGATIONS: &[&str] = &["count", "sum", "min", "max"];
+const OTHERS: &[&str] = &[
+    "str_cmp",
+    "str_cmp_ignore_case",
+    "uri",
+    "bytes",
+    "true",
+    "false",
+    "null",
+];
+
+// Join the words as "a, b or c".
+fn enumerate(words: &[String]) -> String {
+    match words.split_last() {
+        None => String::new(),
+        Some((last, [])) => last.clone(),
+        Some((last, rest)) => format!("{} or {}", rest.join(", "), last),
+    }
+}
+
+// Describe a token expected by the parser, in plain words.
+fn describe(token: &str) -> String {
+    if let Some(regex) = token.strip_prefix("r#\"") {
+        if regex.starts_with("[.]") {
+            "a selector".to_string()
+        } else if regex.starts_with("[\"]") {
+            "a string".to_string()
+        } else {
+            "a number".to_string()
+        }
+    } else {
+        format!("'{}'", token.trim_matches('"'))
+    }
+}
+
+// Describe the set of tokens expected by the parser. When all the
+// keywords starting a kind of expression are expected, they are named
+// after that kind.
+fn expected(tokens: &[String]) -> String {
+    let keywords = tokens
+        .iter()
+        .map(|t| t.trim_matches('"'))
+        .collect::<Vec<_>>();
+    let groups = [
+        ("a bag expression", BAGS),
+        ("a shape", SHAPES),
+        ("a predicate", PREDICATES),
+    ];
+
+    let mut described = vec![];
+    let mut remaining = tokens.iter().collect::<Vec<_>>();
+    for (name, group) in groups.iter() {
+        if group.iter().all(|k| keywords.contains(k)) {
+            let words = group.iter().map(|k| k.to_string()).collect::<Vec<_>>();
+            described.push(format!("{}: {}", name, enumerate(&words)));
+            remaining.retain(|t| !group.contains(&t.trim_matches('"')));
+        }
+    }
+
+    let mut words = remaining.iter().map(|t| describe(t)).collect::<Vec<_>>();
+    words.dedup();
+    if !words.is_empty() {
+        described.push(enumerate(&words));
+    }
+
+    // Each group lists its keywords, so separate them clearly.
+    described.join("; or ")
+}
+
+// Number of single character edits to change `a` into `b`.
+fn distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+// Suggest the keyword closest to the word found at `offset`, if any is
+// close enough to be a likely misspelling.
+fn suggestion(query: &str, offset: usize) -> Option<&'static str> {
+    let rest = query.get(offset..)?;
+    let word = match rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')) {
+        Some(end) => &rest[..end],
+        None => rest,
+    };
+
+    if word.is_empty() {
+        return None;
+    }
+
+    [BAGS, SHAPES, PROJECTIONS, AGGREGATIONS, OTHERS]
+        .iter()
+        .flat_map(|group| group.iter())
+        .filter(|k| **k != word)
+        .map(|k| {
+            // Truncated keywords are likely to be meant as the complete
+            // keyword.
+            let d = if k.starts_with(word) && word.len() > 2 {
+                1
+            } else {
+                distance(word, k)
+            };
+            (d, *k)
+        })
+        .filter(|(d, k)| *d <= 2 && *d < k.len() / 2)
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, k)| k)
+}
+
+impl Error {
+    /// Render the error for the analysts: the line of the query with a
+    /// caret under the error, the tokens expected in plain words, and a
+    /// suggestion when a keyword seems to be misspelled.
+    pub fn render(&self, query: &str) -> String {
+        let span = self.span();
+        let (line, column) = span.line_column(query);
+        let text = query.lines().nth(line - 1).unwrap_or("");
+
+        // Underline the span, up to the end of its first line.
+        let start = span.start.min(query.len());
+        let end = span.end.min(query.len()).max(start);
+        let length = query[start..end]
+            .split('\n')
+            .next()
+            .map(|s| s.chars().count())
+            .unwrap_or(0)
+            .max(1);
+
+        let gutter = " ".repeat(line.to_string().len());
+        let mut out = String::new();
+        let _ = writeln!(out, "error: {}", self.message());
+        let _ = writeln!(out, "{}--> {}:{}", gutter, line, column);
+        let _ = writeln!(out, "{} |", gutter);
+        let _ = writeln!(out, "{} | {}", line, text);
+        let _ = write!(
+            out,
+            "{} | {}{}",
+            gutter,
+            " ".repeat(column - 1),
+            "^".repeat(length)
+        );
+
+        if let Error::Parse { expected: e, .. } = self {
+            if !e.is_empty() {
+                let _ = write!(out, "\n{} = expected {}", gutter, expected(e));
+            }
+
+            if let Some(keyword) = suggestion(query, span.start) {
+                let _ = write!(out, "\n{} = help: did you mean `{}`?", gutter, keyword);
+            }
+        }
+
+        out
+    }
+}
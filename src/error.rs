@@ -0,0 +1,147 @@
+use std::fmt;
+
+use lalrpop_util::ParseError;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Location of an expression in the query, as a range of byte offsets.
+///
+/// Expressions written at different places are compared with
+/// `Bag::same_as` and `Projection::same_as`, which ignore the spans.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start, end }
+    }
+
+    /// Line and column of the start of the span within `query`, both
+    /// starting at one. Columns are counted in characters.
+    pub fn line_column(&self, query: &str) -> (usize, usize) {
+        let before = &query[..self.start.min(query.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = match before.rfind('\n') {
+            Some(i) => before[i + 1..].chars().count() + 1,
+            None => before.chars().count() + 1,
+        };
+
+        (line, column)
+    }
+}
+
+/// Token of the query which cannot be parsed, although it is
+/// syntactically valid, such as a number out of range.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TokenError {
+    /// Location of the token in the query.
+    pub span: Span,
+    /// Reason why the token is invalid.
+    pub message: &'static str,
+}
+
+impl TokenError {
+    pub fn new(start: usize, end: usize, message: &'static str) -> Self {
+        TokenError {
+            span: Span::new(start, end),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Errors reported while parsing and validating queries.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    /// The query is not syntactically valid. `expected` lists the
+    /// tokens which would have been accepted at that location.
+    Parse {
+        span: Span,
+        message: String,
+        expected: Vec<String>,
+    },
+    /// The query is well-formed, but not valid.
+    Validation { span: Span, message: String },
+}
+
+impl Error {
+    pub fn validation<S: Into<String>>(span: Span, message: S) -> Self {
+        Error::Validation {
+            span,
+            message: message.into(),
+        }
+    }
+
+    /// Location of the error in the query.
+    pub fn span(&self) -> Span {
+        match self {
+            Error::Parse { span, .. } => *span,
+            Error::Validation { span, .. } => *span,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            Error::Parse { message, .. } => message,
+            Error::Validation { message, .. } => message,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let span = self.span();
+        write!(f, "{} (at {}..{})", self.message(), span.start, span.end)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl<T> From<ParseError<usize, T, TokenError>> for Error
+where
+    T: fmt::Display,
+{
+    fn from(error: ParseError<usize, T, TokenError>) -> Self {
+        match error {
+            ParseError::InvalidToken { location } => Error::Parse {
+                span: Span::new(location, location + 1),
+                message: "invalid token".to_string(),
+                expected: vec![],
+            },
+            ParseError::UnrecognizedEof { location, expected } => Error::Parse {
+                span: Span::new(location, location),
+                message: "unexpected end of query".to_string(),
+                expected,
+            },
+            ParseError::UnrecognizedToken {
+                token: (start, token, end),
+                expected,
+            } => Error::Parse {
+                span: Span::new(start, end),
+                message: format!("unexpected token '{}'", token),
+                expected,
+            },
+            ParseError::ExtraToken {
+                token: (start, token, end),
+            } => Error::Parse {
+                span: Span::new(start, end),
+                message: format!("extra token '{}'", token),
+                expected: vec![],
+            },
+            ParseError::User { error } => Error::Parse {
+                span: error.span,
+                message: error.message.to_string(),
+                expected: vec![],
+            },
+        }
+    }
+}
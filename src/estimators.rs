@@ -0,0 +1,207 @@
+use super::catalogs::Catalog;
+use super::expressions::*;
+use super::histograms::Histogram;
+use super::symbols::*;
+
+// Selectivities of the predicates, as commonly assumed when nothing is
+// known about the values of the properties.
+const EQUAL_SELECTIVITY: f64 = 0.1;
+const RANGE_SELECTIVITY: f64 = 1.0 / 3.0;
+
+// Fraction of the objects of a space expected to be within a label,
+// when its extent is not known by the catalog.
+const LABEL_SELECTIVITY: f64 = 0.01;
+
+// Histogram of the space `space_id`, which the estimates require.
+fn histogram<'c>(catalog: &'c Catalog, space_id: &str) -> Result<&'c Histogram, String> {
+    match catalog.histograms() {
+        Some(histograms) => histograms.get(space_id),
+        None => Err("No histograms in the catalog".to_string()),
+    }
+}
+
+impl Estimator for Projection {
+    fn estimate(&self, catalog: &Catalog) -> Result<f64, String> {
+        match self {
+            Projection::Nifti(_, _, bag, _) => bag.estimate(catalog),
+            Projection::Json(_, _, bag, _) => bag.estimate(catalog),
+        }
+    }
+}
+
+impl Estimator for Bag {
+    fn estimate(&self, catalog: &Catalog) -> Result<f64, String> {
+        let mut operands = vec![];
+        for bag in self.operands() {
+            operands.push(bag.estimate(catalog)?);
+        }
+
+        self.estimate_from(&operands, catalog)
+    }
+}
+
+impl Bag {
+    /// Number of objects expected in the results of the bag, from the
+    /// numbers expected for its operands, given in the order of
+    /// `Bag::operands`.
+    pub fn estimate_from(&self, operands: &[f64], catalog: &Catalog) -> Result<f64, String> {
+        if operands.len() != self.operands().len() {
+            return Err(format!(
+                "Expected {} estimates of operands, got {}",
+                self.operands().len(),
+                operands.len()
+            ));
+        }
+
+        match self {
+            Bag::Distinct(_, _) | Bag::Filter(None, _, _) => Ok(operands[0]),
+            Bag::Filter(Some(predicate), _, _) => Ok(operands[0] * predicate.selectivity()),
+            Bag::Complement(bag, _) => {
+                let total = histogram(catalog, bag.space())?.total() as f64;
+                Ok((total - operands[0]).max(0.0))
+            }
+            Bag::Intersection(_, _, _) => Ok(operands[0].min(operands[1])),
+            Bag::Union(_, _, _) | Bag::Bag(_, _) => Ok(operands.iter().sum()),
+            Bag::Inside(shape, _) => shape.estimate(catalog),
+            Bag::Outside(shape, _) => {
+                let total = histogram(catalog, shape.space())?.total() as f64;
+                Ok((total - shape.estimate(catalog)?).max(0.0))
+            }
+            // Positions generated from the shape, not objects.
+            Bag::Shape(shape, _) => shape.positions(histogram(catalog, shape.space())?.voxel()),
+            Bag::Limit(n, _, _) => Ok(operands[0].min(*n as f64)),
+            Bag::Offset(n, _, _) => Ok((operands[0] - *n as f64).max(0.0)),
+        }
+    }
+}
+
+// Fraction of the bounding box, given as (low, high), filled by a shape
+// of volume `volume`.
+fn fill(bounding_box: &(Vec<f64>, Vec<f64>), volume: f64) -> f64 {
+    let (low, high) = bounding_box;
+    let box_volume = low
+        .iter()
+        .zip(high)
+        .map(|(l, h)| h - l)
+        .filter(|length| *length > 0.0)
+        .product::<f64>();
+
+    (volume / box_volume).min(1.0)
+}
+
+// Objects within the bounding box of a shape, scaled by the fraction of
+// the box filled by the shape.
+fn within(histogram: &Histogram, bounding_box: (Vec<f64>, Vec<f64>), volume: f64) -> f64 {
+    let fraction = fill(&bounding_box, volume);
+    let (low, high) = bounding_box;
+
+    histogram.count(&low, &high) * fraction
+}
+
+impl Estimator for Shape {
+    fn estimate(&self, catalog: &Catalog) -> Result<f64, String> {
+        let histogram = histogram(catalog, self.space())?;
+
+        Ok(match self {
+            // All the objects of the bin may be at the position.
+            Shape::Point(_, position) => {
+                let position: Vec<f64> = position.into();
+                histogram.bin(&position) as f64
+            }
+            Shape::HyperRectangle(_, vertices) if vertices.len() == 2 => {
+                let ((low, high), _) = self.extent()?;
+                histogram.count(&low, &high)
+            }
+            Shape::Label(_, id) => match catalog.label(id) {
+                Some(extent) => extent.objects as f64,
+                None => histogram.total() as f64 * LABEL_SELECTIVITY,
+            },
+            _ => {
+                let (bounding_box, volume) = self.extent()?;
+                within(histogram, bounding_box, volume)
+            }
+        })
+    }
+}
+
+impl Shape {
+    // Bounding box of the shape, as (low, high), and its volume. Labels
+    // are only known from the database.
+    fn extent(&self) -> Result<((Vec<f64>, Vec<f64>), f64), String> {
+        let bounding_box = match self {
+            Shape::Point(_, position) => {
+                let position: Vec<f64> = position.into();
+                (position.clone(), position)
+            }
+            Shape::HyperRectangle(_, vertices) if vertices.len() != 2 => {
+                let shape = OrientedBox::new(vertices)?;
+                return Ok((shape.bounding_box(), shape.volume()));
+            }
+            Shape::HyperRectangle(_, vertices) => {
+                let a: Vec<f64> = (&vertices[0]).into();
+                let b: Vec<f64> = (&vertices[1]).into();
+                let low = a.iter().zip(&b).map(|(a, b)| a.min(*b)).collect();
+                let high = a.iter().zip(&b).map(|(a, b)| a.max(*b)).collect();
+
+                (low, high)
+            }
+            Shape::HyperSphere(_, center, radius) => {
+                let center: Vec<f64> = center.into();
+                let radius: f64 = radius.into();
+                let low = center.iter().map(|c| c - radius).collect();
+                let high = center.iter().map(|c| c + radius).collect();
+
+                (low, high)
+            }
+            Shape::Label(_, id) => return Err(format!("The extent of label '{}' is unknown", id)),
+            Shape::Nifti(_, _) => {
+                let mask = self.mask()?;
+                return Ok((mask.bounding_box(), mask.volume()));
+            }
+        };
+
+        Ok((bounding_box, self.volume()?))
+    }
+
+    // Number of positions generated from the shape, on the grid of the
+    // voxels at the finest resolution, of size `voxel`.
+    fn positions(&self, voxel: &[f64]) -> Result<f64, String> {
+        if let Shape::Point(_, _) = self {
+            return Ok(1.0);
+        }
+
+        let (bounds, volume) = self.extent()?;
+        let grid = bounds
+            .0
+            .iter()
+            .zip(&bounds.1)
+            .enumerate()
+            .map(|(i, (l, h))| {
+                let v = voxel.get(i).copied().unwrap_or(1.0);
+                ((h / v).floor() - (l / v).ceil() + 1.0).max(0.0)
+            })
+            .product::<f64>();
+
+        match self {
+            // The grid covers exactly the box.
+            Shape::HyperRectangle(_, vertices) if vertices.len() == 2 => Ok(grid),
+            _ => Ok(grid * fill(&bounds, volume)),
+        }
+    }
+}
+
+impl Predicate {
+    // Expected fraction of the objects for which the predicate is true.
+    fn selectivity(&self) -> f64 {
+        match self {
+            Predicate::Equal(_, _) => EQUAL_SELECTIVITY,
+            Predicate::Less(_, _) | Predicate::Greater(_, _) => RANGE_SELECTIVITY,
+            Predicate::Not(predicate) => 1.0 - predicate.selectivity(),
+            Predicate::And(lh, rh) => lh.selectivity() * rh.selectivity(),
+            Predicate::Or(lh, rh) => {
+                let (l, r) = (lh.selectivity(), rh.selectivity());
+                l + r - l * r
+            }
+        }
+    }
+}
@@ -7,14 +7,35 @@ use super::symbols::*;
 impl<'e> Evaluator<'e> for Predicate {
     type Object = (&'e String, &'e space::Position, &'e Properties);
 
-    fn eval(&self, object: Self::Object) -> bool {
+    fn eval(&self, object: Self::Object) -> Result<bool, String> {
+        Ok(match self {
+            Predicate::Not(predicate) => !predicate.eval(object)?,
+            Predicate::And(lh, rh) => lh.eval(object)? && rh.eval(object)?,
+            Predicate::Or(lh, rh) => lh.eval(object)? || rh.eval(object)?,
+            Predicate::Less(selector, literal) => &selector.value(object)? < literal,
+            Predicate::Greater(selector, literal) => &selector.value(object)? > literal,
+            Predicate::Equal(selector, literal) => &selector.value(object)? == literal,
+        })
+    }
+}
+
+impl Predicate {
+    /// Evaluate every comparison of the predicate on `object`, without
+    /// skipping the operands which do not change the result, to report
+    /// the ones which cannot be evaluated.
+    pub fn check<'e>(
+        &self,
+        object: (&'e String, &'e space::Position, &'e Properties),
+    ) -> Result<(), String> {
         match self {
-            Predicate::Not(predicate) => !predicate.eval(object),
-            Predicate::And(lh, rh) => lh.eval(object) && rh.eval(object),
-            Predicate::Or(lh, rh) => lh.eval(object) || rh.eval(object),
-            Predicate::Less(selector, literal) => &selector.value(object) < literal,
-            Predicate::Greater(selector, literal) => &selector.value(object) > literal,
-            Predicate::Equal(selector, literal) => &selector.value(object) == literal,
+            Predicate::Not(predicate) => predicate.check(object),
+            Predicate::And(lh, rh) | Predicate::Or(lh, rh) => {
+                lh.check(object)?;
+                rh.check(object)
+            }
+            Predicate::Less(selector, _)
+            | Predicate::Greater(selector, _)
+            | Predicate::Equal(selector, _) => selector.value(object).map(|_| ()),
         }
     }
 }
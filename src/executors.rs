@@ -1,26 +1,48 @@
-use std::collections::{HashMap, HashSet};
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::rc::Rc;
+use std::sync::OnceLock;
+use std::time::Duration;
+use std::time::Instant;
 
 use mercator_db::space;
 use mercator_db::Core;
 use mercator_db::CoreQueryParameters;
 use mercator_db::IterObjects;
 use mercator_db::IterObjectsBySpaces;
+use mercator_db::Properties;
 
+use super::catalogs::Catalog;
 use super::expressions::*;
+use super::nifti::Mask;
+use super::nifti::Volume;
+use super::predictors::right_smaller;
 use super::symbols::*;
 
+// Upper bound on the number of positions generated from a shape, and on
+// the number of voxels of the volumes built from positions.
+const MAX_RASTER_POSITIONS: usize = 1 << 24;
+
+// Test of the positions against the geometry of a shape.
+type Contains<'c> = Box<dyn Fn(&[f64]) -> bool + 'c>;
+
 fn group_by_space<'s>(
     list: IterObjectsBySpaces<'s>,
 ) -> Box<dyn Iterator<Item = (&'s String, IterObjects<'s>)> + 's> {
-    // Filter per Properties, in order to regroup by it, then build
-    // a single SpatialObject per Properties.
-    let mut hashmap = HashMap::new();
+    // Regroup the iterators per space, in the order in which the spaces
+    // first appear. The objects themselves are only read when the
+    // results are consumed.
+    let mut groups: Vec<(&String, Vec<IterObjects>)> = vec![];
     for (space, objects) in list {
-        hashmap.entry(space).or_insert_with(Vec::new).push(objects);
+        match groups.iter_mut().find(|(s, _)| *s == space) {
+            Some((_, iterators)) => iterators.push(objects),
+            None => groups.push((space, vec![objects])),
+        }
     }
 
-    Box::new(hashmap.into_iter().map(|(space, objects)| {
+    Box::new(groups.into_iter().map(|(space, objects)| {
         let objects: IterObjects = Box::new(objects.into_iter().flatten());
         (space, objects)
     }))
@@ -30,11 +52,12 @@ fn distinct_helper(list: IterObjectsBySpaces) -> IterObjectsBySpaces {
     // Make sure to collect all objects iterators per space, so that
     // each space appears only once.
     group_by_space(list)
-        // We would lose some objects otherwise when creating the
-        // HashMaps. Also this makes sure to keep the values are unique.
+        // Objects are produced as soon as they are first seen, only the
+        // ones already produced are kept to skip their duplicates.
         .map(|(space, iter)| {
-            let uniques: HashSet<_> = iter.collect();
-            let uniques: IterObjects = Box::new(uniques.into_iter());
+            let mut seen = HashSet::new();
+            let uniques: IterObjects =
+                Box::new(iter.filter(move |object| seen.insert(object.clone())));
             (space, uniques)
         })
         .collect()
@@ -54,6 +77,16 @@ fn into_positions_hashset(
         .collect::<HashMap<_, _>>()
 }
 
+// Number of lookups in the hash sets of positions, see `Statistics`.
+// Only counted when the statistics of the execution are collected.
+type Lookups = Option<Rc<Cell<usize>>>;
+
+fn count_lookup(lookups: &Lookups) {
+    if let Some(lookups) = lookups {
+        lookups.set(lookups.get() + 1);
+    }
+}
+
 // Strictly not inside nor on the surface.
 // TODO: inside must contains the valid positions in all expected spaces
 fn complement_helper<'h>(
@@ -61,6 +94,7 @@ fn complement_helper<'h>(
     parameters: &'h CoreQueryParameters<'h>,
     space_id: &'h str,
     inside: IterObjectsBySpaces<'h>,
+    lookups: Lookups,
 ) -> mercator_db::ResultSet<'h> {
     let (low, high) = parameters.db.space(space_id)?.bounding_box();
     let inside = into_positions_hashset(inside);
@@ -72,7 +106,11 @@ fn complement_helper<'h>(
             None => None, // Space not found, so no point might exist!
             Some(volume) => {
                 let volume = volume.clone();
-                let iter: IterObjects = Box::new(v.filter(move |a| !volume.contains(&a.0)));
+                let lookups = lookups.clone();
+                let iter: IterObjects = Box::new(v.filter(move |a| {
+                    count_lookup(&lookups);
+                    !volume.contains(&a.0)
+                }));
 
                 Some((space, iter))
             }
@@ -85,6 +123,7 @@ fn complement_helper<'h>(
 fn intersect_helper<'h>(
     smaller: IterObjectsBySpaces<'h>,
     bigger: IterObjectsBySpaces<'h>,
+    lookups: Lookups,
 ) -> IterObjectsBySpaces<'h> {
     let smaller = into_positions_hashset(smaller);
 
@@ -95,8 +134,11 @@ fn intersect_helper<'h>(
                 None => None,
                 Some(volume) => {
                     let volume = volume.clone();
-                    let filtered: IterObjects =
-                        Box::new(bigger_object_iter.filter(move |a| volume.contains(&a.0)));
+                    let lookups = lookups.clone();
+                    let filtered: IterObjects = Box::new(bigger_object_iter.filter(move |a| {
+                        count_lookup(&lookups);
+                        volume.contains(&a.0)
+                    }));
 
                     Some((space, filtered))
                 }
@@ -105,96 +147,231 @@ fn intersect_helper<'h>(
         .collect()
 }
 
-impl Bag {
-    fn distinct<'b>(
-        &'b self,
-        core_id: &'b str,
-        parameters: &'b CoreQueryParameters<'b>,
-    ) -> mercator_db::ResultSet<'b> {
-        let results = self.execute(core_id, parameters)?;
+// Test of the positions of the results against the geometry of a shape
+// defined in `space_id`. The positions are read in the output space,
+// when one is requested, so they are first expressed in `space_id`.
+// Positions which cannot be expressed there are not within the shape.
+fn in_shape_space<'h>(
+    parameters: &'h CoreQueryParameters<'h>,
+    space_id: &str,
+    contains: Contains<'h>,
+) -> Result<Rc<dyn Fn(&space::Position) -> bool + 'h>, String> {
+    match parameters.output_space {
+        Some(output) if output != space_id => {
+            let from = parameters.db.space(output)?;
+            let to = parameters.db.space(space_id)?;
 
-        Ok(distinct_helper(results))
+            Ok(Rc::new(move |position: &space::Position| {
+                let position = space::Space::change_base(position, from, to);
+                position.map_or(false, |position| contains(&Vec::<f64>::from(&position)))
+            }))
+        }
+        _ => Ok(Rc::new(move |position: &space::Position| {
+            contains(&Vec::<f64>::from(position))
+        })),
     }
+}
 
-    fn complement<'b>(
-        &'b self,
-        core_id: &'b str,
-        parameters: &'b CoreQueryParameters<'b>,
-        core: &'b Core,
-    ) -> mercator_db::ResultSet<'b> {
-        let inside = self.execute(core_id, parameters)?;
+// Objects of the space `space_id` for which `contains` is false, among
+// the ones of `query_space`. Positions are tested as they are read,
+// instead of hashing all the positions to exclude.
+fn exclude_helper<'h>(
+    core: &'h Core,
+    parameters: &'h CoreQueryParameters<'h>,
+    query_space: &'h str,
+    space_id: &'h str,
+    contains: Contains<'h>,
+) -> mercator_db::ResultSet<'h> {
+    let (low, high) = parameters.db.space(query_space)?.bounding_box();
+    let points = core.get_by_shape(
+        parameters,
+        space::Shape::BoundingBox(low, high),
+        query_space,
+    )?;
+    let contains = in_shape_space(parameters, space_id, contains)?;
 
-        // FIXME: The complement of a set should be computed within its
-        //        definition space. We don't know here so we use universe
-        complement_helper(
-            core,
-            parameters,
-            mercator_db::space::Space::universe().name(),
-            inside,
-        )
-    }
+    Ok(points
+        .into_iter()
+        .filter(|(space, _)| *space == space_id)
+        .map(move |(space, objects)| {
+            let contains = contains.clone();
+            let objects: IterObjects =
+                Box::new(objects.filter(move |(position, _)| !contains(position)));
 
-    fn intersection<'b>(
-        &'b self,
-        core_id: &'b str,
-        parameters: &'b CoreQueryParameters<'b>,
-        rh: &'b Bag,
-    ) -> mercator_db::ResultSet<'b> {
-        let left = self.execute(core_id, parameters)?;
-        let right = rh.execute(core_id, parameters)?;
+            (space, objects)
+        })
+        .collect())
+}
 
-        let v = if rh.predict(parameters.db) < self.predict(parameters.db) {
-            intersect_helper(right, left)
-        } else {
-            intersect_helper(left, right)
-        };
+// Select the objects within the bounding box, given as (low, high),
+// and keep only the ones for which `keep` returns true. Both are in the
+// coordinates of `space_id`.
+fn filter_bounding_box<'h>(
+    core: &'h Core,
+    parameters: &'h CoreQueryParameters<'h>,
+    space_id: &'h str,
+    bounding_box: (Vec<f64>, Vec<f64>),
+    keep: Contains<'h>,
+) -> mercator_db::ResultSet<'h> {
+    let space = parameters.db.space(space_id)?;
+    let (low, high) = bounding_box;
+    let low = space.encode(&low)?;
+    let high = space.encode(&high)?;
 
-        Ok(v)
-    }
+    let candidates =
+        core.get_by_shape(parameters, space::Shape::BoundingBox(low, high), space_id)?;
+    let keep = in_shape_space(parameters, space_id, keep)?;
 
-    fn union<'b>(
-        &'b self,
-        core_id: &'b str,
-        parameters: &'b CoreQueryParameters<'b>,
-        rh: &'b Bag,
-    ) -> mercator_db::ResultSet<'b> {
-        let mut left = self.execute(core_id, parameters)?;
-        let mut right = rh.execute(core_id, parameters)?;
+    Ok(candidates
+        .into_iter()
+        .map(move |(space, objects)| {
+            let keep = keep.clone();
+            let objects: IterObjects =
+                Box::new(objects.filter(move |(position, _)| keep(position)));
 
-        let union = if rh.predict(parameters.db) < self.predict(parameters.db) {
-            left.append(&mut right);
-            left
-        } else {
-            right.append(&mut left);
-            right
-        };
+            (space, objects)
+        })
+        .collect())
+}
 
-        Ok(union)
-    }
+// Bounding box of the mask, restricted to the dimensions of the space
+// `space_id`, which may have fewer than the three of the volume.
+fn mask_bounding_box(
+    mask: &Mask,
+    parameters: &CoreQueryParameters,
+    space_id: &str,
+) -> Result<(Vec<f64>, Vec<f64>), String> {
+    let dimensions = parameters.db.space(space_id)?.bounding_box().0.dimensions();
+    let (mut low, mut high) = mask.bounding_box();
+    low.truncate(dimensions);
+    high.truncate(dimensions);
 
-    fn filter<'b>(
-        &'b self,
-        predicate: &'b Predicate,
-        core_id: &'b str,
-        parameters: &'b CoreQueryParameters<'b>,
-    ) -> mercator_db::ResultSet<'b> {
-        let results = self.execute(core_id, parameters)?;
+    Ok((low, high))
+}
 
-        Ok(results
-            .into_iter()
-            .map(move |(space, positions)| {
-                let positions = positions.collect::<Vec<_>>();
-                (
-                    space,
-                    Box::new(positions.into_iter().filter(move |(position, properties)| {
-                        predicate.eval((space, position, properties))
-                    })) as IterObjects,
-                )
-            })
-            .collect())
+// The results of the left operand come first, whatever their sizes, so
+// that the order of the results does not depend on the predictions.
+fn union_helper<'h>(
+    mut left: IterObjectsBySpaces<'h>,
+    mut right: IterObjectsBySpaces<'h>,
+) -> IterObjectsBySpaces<'h> {
+    left.append(&mut right);
+    left
+}
+
+// Regroup the results per space, in the order of the names of the
+// spaces, so that paging counts the objects in the same order whatever
+// the order in which the spaces are returned by the operand.
+fn sorted_by_space(results: IterObjectsBySpaces) -> IterObjectsBySpaces {
+    let mut results = group_by_space(results).collect::<Vec<_>>();
+    results.sort_by(|(a, _), (b, _)| a.cmp(b));
+    results
+}
+
+// Objects of the spaces of a limited bag, read as the results are
+// consumed. The objects of a space are counted after those of the
+// previous spaces, which are read first, and kept, when the spaces are
+// not consumed in order.
+struct Limited<'h> {
+    remaining: usize,
+    // Number of spaces whose objects have all been counted.
+    counted: usize,
+    spaces: Vec<(IterObjects<'h>, VecDeque<(space::Position, &'h Properties)>)>,
+}
+
+impl<'h> Limited<'h> {
+    fn next(&mut self, space: usize) -> Option<(space::Position, &'h Properties)> {
+        if let Some(object) = self.spaces[space].1.pop_front() {
+            return Some(object);
+        }
+
+        while self.counted < space {
+            let remaining = self.remaining;
+            let (objects, kept) = &mut self.spaces[self.counted];
+            let before = kept.len();
+            kept.extend(objects.by_ref().take(remaining));
+            self.remaining -= kept.len() - before;
+            self.counted += 1;
+        }
+
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let object = self.spaces[space].0.next()?;
+        self.remaining -= 1;
+        Some(object)
     }
 }
 
+// At most `n` objects are read from the operand, as the results are
+// consumed, in the order of `sorted_by_space`.
+fn limit_helper<'h>(n: usize, results: IterObjectsBySpaces<'h>) -> IterObjectsBySpaces<'h> {
+    let (spaces, objects): (Vec<_>, Vec<_>) = sorted_by_space(results).into_iter().unzip();
+    let limited = Rc::new(RefCell::new(Limited {
+        remaining: n,
+        counted: 0,
+        spaces: objects
+            .into_iter()
+            .map(|objects| (objects, VecDeque::new()))
+            .collect(),
+    }));
+
+    spaces
+        .into_iter()
+        .enumerate()
+        .map(|(i, space)| {
+            let limited = limited.clone();
+            let objects: IterObjects =
+                Box::new(std::iter::from_fn(move || limited.borrow_mut().next(i)));
+            (space, objects)
+        })
+        .collect()
+}
+
+// The first `n` objects are read, and dropped, before any result is
+// returned. See `limit_helper` for the order in which they are counted.
+fn offset_helper(n: usize, results: IterObjectsBySpaces) -> IterObjectsBySpaces {
+    let mut remaining = n;
+    sorted_by_space(results)
+        .into_iter()
+        .filter_map(|(space, mut objects)| {
+            while remaining > 0 {
+                objects.next()?;
+                remaining -= 1;
+            }
+
+            Some((space, objects))
+        })
+        .collect()
+}
+
+// The predicate is evaluated as the results are read, which cannot
+// report errors. Whether a selector resolves only depends on the space of
+// the object, see `LiteralSelector::resolve`, so every comparison of the
+// predicate is first checked on the first object of each space, to report
+// the predicates which cannot be evaluated before any result is produced.
+fn filter_helper<'h>(
+    predicate: &'h Predicate,
+    results: IterObjectsBySpaces<'h>,
+) -> mercator_db::ResultSet<'h> {
+    results
+        .into_iter()
+        .map(move |(space, objects)| {
+            let mut objects = objects.peekable();
+            if let Some((position, properties)) = objects.peek() {
+                predicate.check((space, position, *properties))?;
+            }
+
+            let filtered: IterObjects = Box::new(objects.filter(move |(position, properties)| {
+                matches!(predicate.eval((space, position, *properties)), Ok(true))
+            }));
+
+            Ok((space, filtered))
+        })
+        .collect()
+}
+
 impl Shape {
     fn inside<'s>(
         &'s self,
@@ -209,22 +386,27 @@ impl Shape {
                 let position = space.encode(&position)?;
                 Ok((space_id, space::Shape::Point(position)))
             }
+            Shape::HyperRectangle(space_id, vertices) if vertices.len() != 2 => {
+                // Select the objects within the enclosing box aligned
+                // with the axes, then keep those inside the actual box.
+                let shape = OrientedBox::new(vertices)?;
+
+                return filter_bounding_box(
+                    core,
+                    parameters,
+                    space_id,
+                    shape.bounding_box(),
+                    Box::new(move |position: &[f64]| shape.contains(position)),
+                );
+            }
             Shape::HyperRectangle(space_id, bounding_box) => {
-                if bounding_box.len() != 2 {
-                    //FIXME: Support arbitrary HyperRectangles
-                    Err(
-                        "The number of position is different from 2, which is unsupported."
-                            .to_string(),
-                    )
-                } else {
-                    let space = db.space(space_id)?;
-                    let low: Vec<f64> = (&bounding_box[0]).into();
-                    let high: Vec<f64> = (&bounding_box[1]).into();
-                    let low = space.encode(&low)?;
-                    let high = space.encode(&high)?;
+                let space = db.space(space_id)?;
+                let low: Vec<f64> = (&bounding_box[0]).into();
+                let high: Vec<f64> = (&bounding_box[1]).into();
+                let low = space.encode(&low)?;
+                let high = space.encode(&high)?;
 
-                    Ok((space_id, space::Shape::BoundingBox(low, high)))
-                }
+                Ok((space_id, space::Shape::BoundingBox(low, high)))
             }
             Shape::HyperSphere(space_id, position, radius) => {
                 let space = db.space(space_id)?;
@@ -243,7 +425,20 @@ impl Shape {
                 // Not a real shape, so short circuit and return.
                 return core.get_by_label(parameters, id);
             }
-            Shape::Nifti(_space_id) => Err("Inside-Nifti: not yet implemented".to_string()),
+            Shape::Nifti(transform, _) => {
+                // Select the objects within the bounding box of the
+                // volume, then keep those lying in non-zero voxels.
+                let mask = self.mask()?;
+                let bounding_box = mask_bounding_box(&mask, parameters, &transform.reference)?;
+
+                return filter_bounding_box(
+                    core,
+                    parameters,
+                    &transform.reference,
+                    bounding_box,
+                    Box::new(move |position: &[f64]| mask.contains(position)),
+                );
+            }
         };
 
         match param {
@@ -256,106 +451,903 @@ impl Shape {
         &'s self,
         parameters: &'s CoreQueryParameters<'s>,
         core: &'s Core,
+        lookups: Lookups,
     ) -> mercator_db::ResultSet<'s> {
-        let (space_id, inside) = match self {
-            Shape::Point(space_id, position) => {
-                let position: Vec<f64> = position.into();
-                let positions = vec![position.into()];
-                let inside = core.get_by_positions(parameters, positions, space_id)?;
+        let space_id = self.space();
 
-                Ok((space_id, inside))
+        // The surface is not part of the outside, so exclude the
+        // positions on it as well.
+        match self.contains(true)? {
+            Some(contains) => exclude_helper(core, parameters, space_id, space_id, contains),
+            None => {
+                let inside = self.inside(parameters, core)?;
+                complement_helper(core, parameters, space_id, inside, lookups)
             }
-            Shape::HyperRectangle(space_id, bounding_box) => {
-                // We need to adapt the bounding_box to ensure the
-                // surface will not hit as part of the inside set, so we
-                // compute the biggest bounding box contained within the
-                // given box.
-
-                // Smallest increment possible
-                let mut increment = Vec::with_capacity(bounding_box[0].dimensions());
-                for _ in 0..bounding_box[0].dimensions() {
-                    increment.push(f64::EPSILON);
+        }
+    }
+
+    // Test of the positions inside the shape, or strictly inside it, for
+    // the shapes whose geometry is known without querying the database.
+    fn contains(&self, strictly: bool) -> Result<Option<Contains>, String> {
+        let contains: Contains = match self {
+            Shape::Point(_, position) => {
+                let position: Vec<f64> = position.into();
+                Box::new(move |x: &[f64]| x == position.as_slice())
+            }
+            Shape::HyperRectangle(_, vertices) if vertices.len() != 2 => {
+                let shape = OrientedBox::new(vertices)?;
+                if strictly {
+                    Box::new(move |x: &[f64]| shape.contains_strictly(x))
+                } else {
+                    Box::new(move |x: &[f64]| shape.contains(x))
                 }
+            }
+            Shape::HyperRectangle(_, bounding_box) => {
+                let a: Vec<f64> = (&bounding_box[0]).into();
+                let b: Vec<f64> = (&bounding_box[1]).into();
+                let low = a.iter().zip(&b).map(|(a, b)| a.min(*b)).collect::<Vec<_>>();
+                let high = a.iter().zip(&b).map(|(a, b)| a.max(*b)).collect::<Vec<_>>();
 
-                // Add it to the lower bound
-                let mut low: space::Position = (&bounding_box[0]).into();
-                low += increment.clone().into();
+                Box::new(move |x: &[f64]| {
+                    x.len() == low.len()
+                        && x.iter().zip(&low).zip(&high).all(|((x, l), h)| {
+                            if strictly {
+                                l < x && x < h
+                            } else {
+                                l <= x && x <= h
+                            }
+                        })
+                })
+            }
+            Shape::HyperSphere(_, center, radius) => {
+                let center: Vec<f64> = center.into();
+                let radius: f64 = radius.into();
 
-                // Substract it from the upper bound
-                let mut high: space::Position = (&bounding_box[1]).into();
-                high -= increment.into();
+                Box::new(move |x: &[f64]| {
+                    let distance = x
+                        .iter()
+                        .zip(&center)
+                        .map(|(x, c)| (x - c) * (x - c))
+                        .sum::<f64>();
 
-                let inside =
-                    core.get_by_shape(parameters, space::Shape::BoundingBox(low, high), space_id)?;
+                    x.len() == center.len()
+                        && if strictly {
+                            distance < radius * radius
+                        } else {
+                            distance <= radius * radius
+                        }
+                })
+            }
+            // Only known from the database.
+            Shape::Label(_, _) => return Ok(None),
+            Shape::Nifti(_, _) => {
+                let mask = self.mask()?;
+                Box::new(move |x: &[f64]| mask.contains(x))
+            }
+        };
+
+        Ok(Some(contains))
+    }
+}
 
-                Ok((space_id, inside))
+// Properties shared by all the positions generated from shapes.
+fn shape_properties() -> &'static Properties {
+    static PROPERTIES: OnceLock<Properties> = OnceLock::new();
+
+    PROPERTIES.get_or_init(|| Properties::Unknown(String::new(), "Shape".to_string()))
+}
+
+impl Shape {
+    // Generate the positions of the shape, on a grid of the size of the
+    // voxels at the requested resolution.
+    fn rasterize<'s>(
+        &'s self,
+        parameters: &'s CoreQueryParameters<'s>,
+    ) -> mercator_db::ResultSet<'s> {
+        let (bounding_box, contains): ((Vec<f64>, Vec<f64>), Contains) = match self {
+            Shape::Point(space_id, position) => {
+                // A point is its own, and only, position.
+                let objects: IterObjects =
+                    Box::new(std::iter::once((position.into(), shape_properties())));
+                let objects = in_output_space(parameters, space_id, objects)?;
+                return Ok(vec![(space_id, objects)]);
+            }
+            Shape::HyperRectangle(_, vertices) if vertices.len() != 2 => {
+                let shape = OrientedBox::new(vertices)?;
+                (
+                    shape.bounding_box(),
+                    Box::new(move |position: &[f64]| shape.contains(position)),
+                )
             }
-            Shape::HyperSphere(space_id, center, radius) => {
-                // Smallest decrement possible, to exclude the surface
-                let mut radius: f64 = radius.into();
-                radius -= f64::EPSILON;
-                let center: space::Position = center.into();
+            Shape::HyperRectangle(_, bounding_box) => {
+                let a: Vec<f64> = (&bounding_box[0]).into();
+                let b: Vec<f64> = (&bounding_box[1]).into();
+                let low = a.iter().zip(&b).map(|(a, b)| a.min(*b)).collect();
+                let high = a.iter().zip(&b).map(|(a, b)| a.max(*b)).collect();
 
-                let inside = core.get_by_shape(
-                    parameters,
-                    space::Shape::HyperSphere(center, radius.into()),
-                    space_id,
-                )?;
+                // The grid covers exactly the box.
+                ((low, high), Box::new(|_: &[f64]| true))
+            }
+            Shape::HyperSphere(_, center, radius) => {
+                let center: Vec<f64> = center.into();
+                let radius: f64 = radius.into();
+                let low = center.iter().map(|c| c - radius).collect();
+                let high = center.iter().map(|c| c + radius).collect();
+
+                (
+                    (low, high),
+                    Box::new(move |position: &[f64]| {
+                        position.len() == center.len()
+                            && position
+                                .iter()
+                                .zip(&center)
+                                .map(|(x, c)| (x - c) * (x - c))
+                                .sum::<f64>()
+                                <= radius * radius
+                    }),
+                )
+            }
+            Shape::Label(_, id) => {
+                return Err(format!(
+                    "Rasterize: the positions of label '{}' cannot be computed.",
+                    id
+                ))
+            }
+            Shape::Nifti(_, _) => {
+                let mask = self.mask()?;
+                (
+                    mask_bounding_box(&mask, parameters, self.space())?,
+                    Box::new(move |position: &[f64]| mask.contains(position)),
+                )
+            }
+        };
+
+        // Align the grid on multiples of the voxel size.
+        let (low, high) = bounding_box;
+        let step = resolution(parameters, self.space(), low.len())?;
+        let first = low
+            .iter()
+            .zip(&step)
+            .map(|(l, s)| (l / s).ceil() * s)
+            .collect::<Vec<_>>();
+        let counts = first
+            .iter()
+            .zip(&high)
+            .zip(&step)
+            .map(|((f, h), s)| {
+                if h < f {
+                    0
+                } else {
+                    ((h - f) / s).floor() as usize + 1
+                }
+            })
+            .collect::<Vec<_>>();
 
-                Ok((space_id, inside))
+        let total = counts
+            .iter()
+            .try_fold(1usize, |acc, c| acc.checked_mul(*c))
+            .filter(|total| *total <= MAX_RASTER_POSITIONS)
+            .ok_or_else(|| {
+                format!(
+                    "Rasterize: too many positions at this resolution, more than {}.",
+                    MAX_RASTER_POSITIONS
+                )
+            })?;
+
+        let positions = (0..total).filter_map(move |mut i| {
+            let mut position = Vec::with_capacity(counts.len());
+            for ((f, s), c) in first.iter().zip(&step).zip(&counts) {
+                position.push(f + (i % c) as f64 * s);
+                i /= c;
             }
-            Shape::Label(space_id, id) => {
-                let inside = core.get_by_label(parameters, id)?;
 
-                Ok((space_id, inside))
+            if contains(position.as_slice()) {
+                Some((LiteralPosition::from(position).into(), shape_properties()))
+            } else {
+                None
             }
-            Shape::Nifti(_space_id) => Err("Outside-nifti: not yet implemented".to_string()),
-        }?;
+        });
 
-        complement_helper(core, parameters, space_id, inside)
+        let objects = in_output_space(parameters, self.space(), Box::new(positions))?;
+        Ok(vec![(self.space(), objects)])
     }
 }
 
-fn filter<'c>(
-    core_id: &'c str,
-    parameters: &'c CoreQueryParameters<'c>,
-    predicate: &'c Option<Predicate>,
-    bag: &'c Bag,
-) -> mercator_db::ResultSet<'c> {
-    match predicate {
-        None => bag.execute(core_id, parameters),
-        Some(predicate) => bag.filter(predicate, core_id, parameters),
+// Express the positions generated in the space `space_id` in the output
+// space, when one is requested, as the database does for the positions
+// of the objects. Positions which cannot be expressed there are dropped.
+fn in_output_space<'h>(
+    parameters: &'h CoreQueryParameters<'h>,
+    space_id: &str,
+    objects: IterObjects<'h>,
+) -> Result<IterObjects<'h>, String> {
+    match parameters.output_space {
+        Some(output) if output != space_id => {
+            let from = parameters.db.space(space_id)?;
+            let to = parameters.db.space(output)?;
+
+            let objects = objects.filter_map(move |(position, properties)| {
+                let position = space::Space::change_base(&position, from, to).ok()?;
+                Some((position, properties))
+            });
+
+            Ok(Box::new(objects))
+        }
+        _ => Ok(objects),
     }
 }
 
-fn bag<'c>(
-    core_id: &'c str,
-    parameters: &'c CoreQueryParameters<'c>,
-    bags: &'c [Bag],
-) -> mercator_db::ResultSet<'c> {
-    let mut results = Vec::new();
-    for bag in bags {
-        let mut result = bag.execute(core_id, parameters)?;
-        results.append(&mut result);
+fn json_number(number: &LiteralNumber) -> serde_json::Value {
+    match number {
+        LiteralNumber::Int(x) => (*x).into(),
+        LiteralNumber::Float(x) => (*x).into(),
     }
+}
 
-    Ok(results)
+// Object of the results, as passed to the selectors.
+type Object<'o> = (&'o String, space::Position, &'o Properties);
+
+// Resolve the selector on the object, and keep only the numerical
+// values. Objects for which the selector resolves to `null` are ignored.
+fn number(selector: &LiteralSelector, object: &Object) -> Result<Option<LiteralNumber>, String> {
+    let (space_id, position, properties) = object;
+    let value = selector.json((*space_id, position, *properties))?;
+    if value.is_null() {
+        return Ok(None);
+    }
+
+    if let Some(x) = value.as_i64() {
+        Ok(Some(LiteralNumber::Int(x)))
+    } else if let Some(x) = value.as_f64() {
+        Ok(Some(LiteralNumber::Float(x)))
+    } else {
+        Err(format!(
+            "Aggregation: '{}' is not a number, for selector {:?}",
+            value, selector
+        ))
+    }
+}
+
+// JSON value, to de-duplicate the values of the selectors. Numbers are
+// equal when they have the same value, whatever their representation,
+// so that 1 and 1.0 are the same value.
+#[derive(PartialEq, Eq, Hash)]
+enum Key {
+    Null,
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    // Bits of the number, which is neither an integer nor -0.0.
+    Float(u64),
+    String(String),
+    Array(Vec<Key>),
+    Object(Vec<(String, Key)>),
+}
+
+impl From<&serde_json::Value> for Key {
+    fn from(value: &serde_json::Value) -> Self {
+        match value {
+            serde_json::Value::Null => Key::Null,
+            serde_json::Value::Bool(b) => Key::Bool(*b),
+            serde_json::Value::Number(n) => match (n.as_i64(), n.as_u64(), n.as_f64()) {
+                (Some(x), _, _) => Key::Int(x),
+                (_, Some(x), _) => Key::UInt(x),
+                (_, _, Some(x))
+                    if x.fract() == 0.0 && x >= i64::MIN as f64 && x < i64::MAX as f64 =>
+                {
+                    Key::Int(x as i64)
+                }
+                (_, _, Some(x)) if x.fract() == 0.0 && x >= 0.0 && x < u64::MAX as f64 => {
+                    Key::UInt(x as u64)
+                }
+                (_, _, x) => Key::Float(x.unwrap_or(f64::NAN).to_bits()),
+            },
+            serde_json::Value::String(s) => Key::String(s.clone()),
+            serde_json::Value::Array(values) => Key::Array(values.iter().map(Key::from).collect()),
+            serde_json::Value::Object(map) => {
+                let mut pairs = map
+                    .iter()
+                    .map(|(k, v)| (k.clone(), Key::from(v)))
+                    .collect::<Vec<_>>();
+                pairs.sort_by(|(a, _), (b, _)| a.cmp(b));
+                Key::Object(pairs)
+            }
+        }
+    }
+}
+
+/// Whether `count(distinct ...)` counts both values once. Numbers are
+/// compared by value, so 1 and 1.0 are the same, as are objects with
+/// the same fields in a different order.
+pub fn same_value(a: &serde_json::Value, b: &serde_json::Value) -> bool {
+    Key::from(a) == Key::from(b)
+}
+
+// State of an aggregation, updated with the objects as they are read.
+enum Accumulator {
+    // Number of values, and the values already counted when only the
+    // distinct ones are.
+    Count(u64, Option<HashSet<Key>>),
+    Sum(LiteralNumber),
+    // Lowest or highest value so far.
+    Extremum(Option<LiteralNumber>),
+}
+
+impl Aggregation {
+    fn accumulator(&self) -> Accumulator {
+        match self {
+            Aggregation::Count(distinct, _) => Accumulator::Count(
+                0,
+                if *distinct {
+                    Some(HashSet::new())
+                } else {
+                    None
+                },
+            ),
+            Aggregation::Sum(_) => Accumulator::Sum(LiteralNumber::Int(0)),
+            Aggregation::Min(_) | Aggregation::Max(_) => Accumulator::Extremum(None),
+        }
+    }
+
+    // Update `accumulator` with `object`.
+    fn add(&self, accumulator: &mut Accumulator, object: &Object) -> Result<(), String> {
+        match (self, accumulator) {
+            (Aggregation::Count(_, selector), Accumulator::Count(count, seen)) => {
+                let (space_id, position, properties) = object;
+                let value = selector.json((*space_id, position, *properties))?;
+                if value.is_null() {
+                    return Ok(());
+                }
+
+                if let Some(seen) = seen {
+                    if !seen.insert(Key::from(&value)) {
+                        return Ok(());
+                    }
+                }
+
+                *count += 1;
+            }
+            (Aggregation::Sum(selector), Accumulator::Sum(sum)) => {
+                if let Some(n) = number(selector, object)? {
+                    *sum = match (&*sum, n) {
+                        (LiteralNumber::Int(s), LiteralNumber::Int(x)) => match s.checked_add(x) {
+                            Some(s) => LiteralNumber::Int(s),
+                            None => LiteralNumber::Float(*s as f64 + x as f64),
+                        },
+                        (s, x) => LiteralNumber::Float(f64::from(s) + f64::from(x)),
+                    };
+                }
+            }
+            (Aggregation::Min(selector), Accumulator::Extremum(extremum))
+            | (Aggregation::Max(selector), Accumulator::Extremum(extremum)) => {
+                if let Some(n) = number(selector, object)? {
+                    let is_min = matches!(self, Aggregation::Min(_));
+                    let replace = match extremum {
+                        None => true,
+                        Some(e) => {
+                            let (x, y) = (f64::from(&n), f64::from(&*e));
+                            (is_min && x < y) || (!is_min && x > y)
+                        }
+                    };
+                    if replace {
+                        *extremum = Some(n);
+                    }
+                }
+            }
+            _ => return Err(format!("Aggregation: invalid state for {:?}", self)),
+        }
+
+        Ok(())
+    }
+}
+
+impl Accumulator {
+    fn value(self) -> serde_json::Value {
+        match self {
+            Accumulator::Count(count, _) => count.into(),
+            Accumulator::Sum(sum) => json_number(&sum),
+            // The extremum of an empty set is undefined.
+            Accumulator::Extremum(None) => serde_json::Value::Null,
+            Accumulator::Extremum(Some(e)) => json_number(&e),
+        }
+    }
+}
+
+impl JsonValue {
+    pub fn has_aggregation(&self) -> bool {
+        match self {
+            JsonValue::Aggregation(_) => true,
+            JsonValue::Object(pairs) => pairs.iter().any(|(_, value)| value.has_aggregation()),
+            JsonValue::Array(values) => values.iter().any(|value| value.has_aggregation()),
+            _ => false,
+        }
+    }
+
+    // Instantiate the JSON template, using the provided functions to
+    // compute the value of selectors and aggregations.
+    fn instantiate<S, A>(&self, selector: &S, aggregation: &A) -> Result<serde_json::Value, String>
+    where
+        S: Fn(&LiteralSelector) -> Result<serde_json::Value, String>,
+        A: Fn(&Aggregation) -> Result<serde_json::Value, String>,
+    {
+        let value = match self {
+            JsonValue::String(s) => serde_json::Value::String(s.clone()),
+            JsonValue::JsonNumber(n) => json_number(n),
+            JsonValue::Bool(b) => serde_json::Value::Bool(*b),
+            JsonValue::Null => serde_json::Value::Null,
+            JsonValue::Object(pairs) => {
+                let mut map = serde_json::Map::with_capacity(pairs.len());
+                for (key, value) in pairs {
+                    map.insert(key.clone(), value.instantiate(selector, aggregation)?);
+                }
+                serde_json::Value::Object(map)
+            }
+            JsonValue::Array(values) => {
+                let mut array = Vec::with_capacity(values.len());
+                for value in values {
+                    array.push(value.instantiate(selector, aggregation)?);
+                }
+                serde_json::Value::Array(array)
+            }
+            JsonValue::Selector(s) => selector(s)?,
+            JsonValue::Aggregation(a) => aggregation(a)?,
+        };
+
+        Ok(value)
+    }
+
+    // Instantiate the JSON template for a single object, resolving the
+    // selectors against it.
+    fn project<'o>(
+        &self,
+        object: (&'o String, &'o space::Position, &'o Properties),
+    ) -> Result<serde_json::Value, String> {
+        self.instantiate(
+            &|selector: &LiteralSelector| selector.json(object),
+            &|_: &Aggregation| {
+                Err("Proj-Json: aggregations require to be computed over the whole set".to_string())
+            },
+        )
+    }
+
+    // Aggregations of the template, in the order of the template.
+    fn aggregations<'a>(&'a self, aggregations: &mut Vec<&'a Aggregation>) {
+        match self {
+            JsonValue::Aggregation(a) => aggregations.push(a),
+            JsonValue::Object(pairs) => pairs
+                .iter()
+                .for_each(|(_, value)| value.aggregations(aggregations)),
+            JsonValue::Array(values) => values
+                .iter()
+                .for_each(|value| value.aggregations(aggregations)),
+            _ => (),
+        }
+    }
+
+    // Instantiate the JSON template once for the whole set of objects,
+    // computing the aggregations as the objects are read.
+    fn aggregate<'o, I>(&self, objects: I) -> Result<serde_json::Value, String>
+    where
+        I: Iterator<Item = Object<'o>>,
+    {
+        let mut aggregations = vec![];
+        self.aggregations(&mut aggregations);
+
+        let mut accumulators = aggregations
+            .iter()
+            .map(|a| a.accumulator())
+            .collect::<Vec<_>>();
+        for object in objects {
+            for (aggregation, accumulator) in aggregations.iter().zip(accumulators.iter_mut()) {
+                aggregation.add(accumulator, &object)?;
+            }
+        }
+
+        let values = aggregations
+            .into_iter()
+            .zip(accumulators.into_iter().map(Accumulator::value))
+            .collect::<Vec<_>>();
+
+        self.instantiate(
+            &|selector: &LiteralSelector| {
+                Err(format!(
+                    "Proj-Json: selector {:?} cannot be used outside of an aggregation when aggregations are used",
+                    selector
+                ))
+            },
+            &|aggregation: &Aggregation| {
+                let value = values.iter().find(|(a, _)| std::ptr::eq(*a, aggregation));
+                Ok(value.map_or(serde_json::Value::Null, |(_, v)| v.clone()))
+            },
+        )
+    }
+}
+
+/// Size of the voxels along each dimension of `space`, at the resolution
+/// `levels`: the precision of the graduation of each axis, at the first
+/// level, each following level halving it. Dimensions without axis, as
+/// for the universe, have a precision of one.
+pub fn voxel_size(space: &space::Space, dimensions: usize, levels: &[u32]) -> Vec<f64> {
+    let axes = space.axes();
+
+    (0..dimensions)
+        .map(|i| {
+            let precision = axes.get(i).map_or(1.0, |axis| axis.graduation().epsilon);
+            let level = levels.get(i).or_else(|| levels.last()).copied();
+            precision * 2f64.powi(level.unwrap_or(0) as i32)
+        })
+        .collect()
+}
+
+// Size of the voxels of the space `space_id`, at the requested resolution.
+fn resolution(
+    parameters: &CoreQueryParameters,
+    space_id: &str,
+    dimensions: usize,
+) -> Result<Vec<f64>, String> {
+    let space = parameters.db.space(space_id)?;
+    let levels = parameters.resolution.as_deref().unwrap_or(&[]);
+
+    Ok(voxel_size(space, dimensions, levels))
+}
+
+// Build a volume containing all the given positions, expressed in the
+// space `space_id`, using the value associated to each position. When
+// multiple positions fall within the same voxel, the maximum value is
+// kept.
+fn rasterize_positions(
+    positions: &[(Vec<f64>, f64)],
+    parameters: &CoreQueryParameters,
+    space_id: &str,
+) -> Result<Volume, String> {
+    let dimensions = match positions.first() {
+        None => return Volume::new(vec![1, 1, 1], &[0.0, 0.0, 0.0], vec![1.0, 1.0, 1.0]),
+        Some((position, _)) => position.len(),
+    };
+
+    let mut low = vec![f64::INFINITY; dimensions];
+    let mut high = vec![f64::NEG_INFINITY; dimensions];
+    for (position, _) in positions {
+        if position.len() != dimensions {
+            return Err("Rasterize: positions have different number of dimensions.".to_string());
+        }
+
+        for ((l, h), x) in low.iter_mut().zip(high.iter_mut()).zip(position) {
+            *l = l.min(*x);
+            *h = h.max(*x);
+        }
+    }
+
+    // Align the grid on multiples of the voxel size.
+    let size = resolution(parameters, space_id, dimensions)?;
+    let origin = low
+        .iter()
+        .zip(&size)
+        .map(|(l, s)| (l / s).floor() * s)
+        .collect::<Vec<_>>();
+
+    let index = |position: &[f64]| {
+        position
+            .iter()
+            .zip(&origin)
+            .zip(&size)
+            .map(|((x, o), s)| ((x - o) / s).floor() as usize)
+            .collect::<Vec<_>>()
+    };
+
+    // The volume covers the whole extent of the positions, so check its
+    // size before allocating it.
+    let shape = index(high.as_slice())
+        .iter()
+        .map(|i| i.saturating_add(1))
+        .collect::<Vec<_>>();
+    shape
+        .iter()
+        .try_fold(1usize, |acc, d| acc.checked_mul(*d))
+        .filter(|total| *total <= MAX_RASTER_POSITIONS)
+        .ok_or_else(|| {
+            format!(
+                "Rasterize: too many voxels at this resolution, more than {}.",
+                MAX_RASTER_POSITIONS
+            )
+        })?;
+
+    let centre = origin
+        .iter()
+        .zip(&size)
+        .map(|(o, s)| o + s / 2.0)
+        .collect::<Vec<_>>();
+    let mut volume = Volume::new(shape, &centre, size.clone())?;
+
+    for (position, value) in positions {
+        let voxel = index(position.as_slice());
+        let value = *value as f32;
+        let current = volume.get(&voxel).unwrap_or(0.0);
+        if current == 0.0 || value > current {
+            volume.set(&voxel, value)?;
+        }
+    }
+
+    Ok(volume)
+}
+
+/// Result of a projection.
+#[derive(Clone, Debug)]
+pub enum ProjectionResult {
+    /// One document per object, or a single document when the template
+    /// contains aggregations.
+    Json(Vec<serde_json::Value>),
+    /// NIfTI-1 single file (".nii") content.
+    Nifti(Vec<u8>),
+}
+
+/// Statistics collected while executing an expression, per node.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Statistics {
+    /// Operation executed by this node.
+    pub operation: String,
+    /// Time spent executing the node, operands included.
+    pub time: Duration,
+    /// Number of objects produced, per space, in the order of the
+    /// results.
+    pub objects: Vec<(String, usize)>,
+    /// Number of lookups in the hash sets of positions, for
+    /// intersections and complements.
+    pub lookups: usize,
+    /// Statistics of the operands, in the order of the expression.
+    pub operands: Vec<Statistics>,
+}
+
+impl Statistics {
+    /// Total number of objects produced by the node.
+    pub fn count(&self) -> usize {
+        self.objects.iter().map(|(_, count)| count).sum()
+    }
 }
 
 impl<'e> Executor<'e> for Projection {
-    type ResultSet = mercator_db::ResultSet<'e>;
+    type ResultSet = Result<ProjectionResult, String>;
 
-    fn execute(
+    fn execute_with(
         &'e self,
         core_id: &'e str,
         parameters: &'e CoreQueryParameters<'e>,
+        catalog: &Catalog,
     ) -> Self::ResultSet {
+        // Positions are returned in the reference space requested by the
+        // projection.
+        let parameters = CoreQueryParameters {
+            output_space: Some(self.space().as_str()),
+            ..*parameters
+        };
+
         match self {
-            Projection::Nifti(_, _, _bag) => Err("Proj-Nifti: not yet implemented".to_string()),
-            Projection::Json(_, _format, bag) => {
-                bag.execute(core_id, parameters)
-                // FIXME: Add projections here
+            Projection::Nifti(_, selector, bag, _) => {
+                let results = bag.execute_with(core_id, &parameters, catalog)?;
+                let LiteralSelector(fields) = selector;
+
+                let mut positions = vec![];
+                for (space_id, objects) in results {
+                    for (position, properties) in objects {
+                        // Without selector, each position has the value one.
+                        let value = if fields.is_empty() {
+                            1.0
+                        } else {
+                            let value = selector.json((space_id, &position, properties))?;
+                            if value.is_null() {
+                                continue;
+                            }
+
+                            match value.as_f64() {
+                                Some(v) => v,
+                                None => {
+                                    return Err(format!(
+                                        "Proj-Nifti: '{}' is not a number, for selector {:?}",
+                                        value, selector
+                                    ))
+                                }
+                            }
+                        };
+
+                        let position: Vec<f64> = (&position).into();
+                        positions.push((position, value));
+                    }
+                }
+
+                let volume = rasterize_positions(&positions, &parameters, self.space())?;
+
+                Ok(ProjectionResult::Nifti(volume.to_bytes()))
             }
+            Projection::Json(_, format, bag, _) => {
+                let results = bag.execute_with(core_id, &parameters, catalog)?;
+
+                if format.has_aggregation() {
+                    // Aggregations produce a single document computed
+                    // over the whole result set.
+                    let objects = results.into_iter().flat_map(|(space_id, objects)| {
+                        objects.map(move |(position, properties)| (space_id, position, properties))
+                    });
+
+                    Ok(ProjectionResult::Json(vec![format.aggregate(objects)?]))
+                } else {
+                    let mut documents = vec![];
+                    for (space_id, objects) in results {
+                        for (position, properties) in objects {
+                            documents.push(format.project((space_id, &position, properties))?);
+                        }
+                    }
+
+                    Ok(ProjectionResult::Json(documents))
+                }
+            }
+        }
+    }
+}
+
+// Statistics of a node, updated as its results are read.
+struct Probe {
+    operation: String,
+    time: Cell<Duration>,
+    objects: RefCell<Vec<(String, usize)>>,
+    lookups: Rc<Cell<usize>>,
+    operands: RefCell<Vec<Rc<Probe>>>,
+}
+
+impl Probe {
+    fn new(operation: String) -> Self {
+        Probe {
+            operation,
+            time: Cell::default(),
+            objects: RefCell::default(),
+            lookups: Rc::default(),
+            operands: RefCell::default(),
+        }
+    }
+
+    fn spend(&self, time: Duration) {
+        self.time.set(self.time.get() + time);
+    }
+
+    fn statistics(&self) -> Statistics {
+        Statistics {
+            operation: self.operation.clone(),
+            time: self.time.get(),
+            objects: self.objects.borrow().clone(),
+            lookups: self.lookups.get(),
+            operands: self
+                .operands
+                .borrow()
+                .iter()
+                .map(|p| p.statistics())
+                .collect(),
+        }
+    }
+}
+
+// Count the objects of the results in the statistics of `probe`, along
+// with the time spent to produce them, as they are read.
+fn observe<'r>(probe: &Rc<Probe>, results: IterObjectsBySpaces<'r>) -> IterObjectsBySpaces<'r> {
+    results
+        .into_iter()
+        .map(|(space, mut objects)| {
+            let index = {
+                let mut counts = probe.objects.borrow_mut();
+                match counts.iter().position(|(s, _)| s == space) {
+                    Some(index) => index,
+                    None => {
+                        counts.push((space.clone(), 0));
+                        counts.len() - 1
+                    }
+                }
+            };
+
+            let probe = probe.clone();
+            let objects: IterObjects = Box::new(std::iter::from_fn(move || {
+                let start = Instant::now();
+                let object = objects.next();
+                probe.spend(start.elapsed());
+                if object.is_some() {
+                    probe.objects.borrow_mut()[index].1 += 1;
+                }
+                object
+            }));
+
+            (space, objects)
+        })
+        .collect()
+}
+
+// Execution of the nodes of an expression, whose statistics are
+// collected by `probe`, the one of the node being executed, if any.
+// The operands are combined in a fixed order when the expression is
+// `paged`, see `Bag::is_paged`.
+#[derive(Clone, Copy)]
+struct Execution<'e, 'c> {
+    core_id: &'e str,
+    parameters: &'e CoreQueryParameters<'e>,
+    catalog: &'c Catalog,
+    paged: bool,
+    probe: Option<&'c Rc<Probe>>,
+}
+
+impl<'e> Execution<'e, '_> {
+    // Execute `bag`, as an operand of the node being executed.
+    fn run(&self, bag: &'e Bag) -> mercator_db::ResultSet<'e> {
+        let parent = match self.probe {
+            None => return self.dispatch(bag),
+            Some(parent) => parent,
+        };
+
+        let probe = Rc::new(Probe::new(bag.operation()));
+        parent.operands.borrow_mut().push(probe.clone());
+
+        let start = Instant::now();
+        let execution = Execution {
+            probe: Some(&probe),
+            ..*self
+        };
+        let results = execution.dispatch(bag);
+        probe.spend(start.elapsed());
+
+        Ok(observe(&probe, results?))
+    }
+
+    fn dispatch(&self, bag: &'e Bag) -> mercator_db::ResultSet<'e> {
+        let parameters = self.parameters;
+        let core = parameters.db.core(self.core_id)?;
+        let lookups = self.probe.map(|probe| probe.lookups.clone());
+
+        match bag {
+            Bag::Distinct(bag, _) => Ok(distinct_helper(self.run(bag)?)),
+            Bag::Filter(None, bag, _) => self.run(bag),
+            Bag::Filter(Some(predicate), bag, _) => filter_helper(predicate, self.run(bag)?),
+            Bag::Complement(bag, _) => {
+                // FIXME: The complement of a set should be computed within its
+                //        definition space. We don't know here so we use universe
+                let universe = mercator_db::space::Space::universe().name();
+
+                // The operand is not executed when its shape can be
+                // tested instead.
+                if let Bag::Inside(shape, _) = bag.as_ref() {
+                    if let Some(contains) = shape.contains(false)? {
+                        return exclude_helper(core, parameters, universe, shape.space(), contains);
+                    }
+                }
+
+                let inside = self.run(bag)?;
+                complement_helper(core, parameters, universe, inside, lookups)
+            }
+            Bag::Intersection(lh, rh, _) => {
+                let left = self.run(lh)?;
+                let right = self.run(rh)?;
+
+                // The results are in the order of the operand probing
+                // the hashed positions.
+                if self.paged || right_smaller(lh, rh, self.core_id, parameters, self.catalog) {
+                    Ok(intersect_helper(right, left, lookups))
+                } else {
+                    Ok(intersect_helper(left, right, lookups))
+                }
+            }
+            Bag::Union(lh, rh, _) => Ok(union_helper(self.run(lh)?, self.run(rh)?)),
+            Bag::Bag(bags, _) => {
+                let mut results = Vec::new();
+                for bag in bags {
+                    results.append(&mut self.run(bag)?);
+                }
+
+                Ok(results)
+            }
+            Bag::Inside(shape, _) => shape.inside(parameters, core),
+            Bag::Outside(shape, _) => {
+                //FIXME: This is currently computed as the complement of the values within the shape, except its surface.
+                //       Should this be instead a list of positions within the shape?
+                //FIXME: Should we use the Shape's Space to get the maximum bounds or the output Space requested?
+                shape.outside(parameters, core, lookups)
+            }
+            Bag::Shape(shape, _) => shape.rasterize(parameters),
+            Bag::Limit(n, bag, _) => Ok(limit_helper(*n, self.run(bag)?)),
+            Bag::Offset(n, bag, _) => Ok(offset_helper(*n, self.run(bag)?)),
         }
     }
 }
@@ -363,27 +1355,81 @@ impl<'e> Executor<'e> for Projection {
 impl<'e> Executor<'e> for Bag {
     type ResultSet = mercator_db::ResultSet<'e>;
 
-    fn execute(
+    fn execute_with(
         &'e self,
         core_id: &'e str,
         parameters: &'e CoreQueryParameters<'e>,
+        catalog: &Catalog,
     ) -> Self::ResultSet {
-        let core = parameters.db.core(core_id)?;
+        let catalog = with_labels(self, core_id, parameters, catalog);
+        let execution = Execution {
+            core_id,
+            parameters,
+            catalog: &catalog,
+            paged: self.is_paged(),
+            probe: None,
+        };
 
-        match self {
-            Bag::Distinct(bag) => bag.distinct(core_id, parameters),
-            Bag::Filter(predicate, bag) => filter(core_id, parameters, predicate, bag),
-            Bag::Complement(bag) => bag.complement(core_id, parameters, core),
-            Bag::Intersection(lh, rh) => lh.intersection(core_id, parameters, rh),
-            Bag::Union(lh, rh) => lh.union(core_id, parameters, rh),
-            Bag::Bag(list) => bag(core_id, parameters, list),
-            Bag::Inside(shape) => shape.inside(parameters, core),
-            Bag::Outside(shape) => {
-                //FIXME: This is currently computed as the complement of the values within the shape, except its surface.
-                //       Should this be instead a list of positions within the shape?
-                //FIXME: Should we use the Shape's Space to get the maximum bounds or the output Space requested?
-                shape.outside(parameters, core)
-            }
-        }
+        execution.run(self)
+    }
+}
+
+// The extents of the labels are used to choose which operand of the
+// intersections to hash, so look up the ones `catalog` does not know,
+// unless the operands are combined in a fixed order.
+fn with_labels<'c>(
+    bag: &Bag,
+    core_id: &str,
+    parameters: &CoreQueryParameters,
+    catalog: &'c Catalog,
+) -> Cow<'c, Catalog> {
+    fn intersects(bag: &Bag) -> bool {
+        matches!(bag, Bag::Intersection(_, _, _)) || bag.operands().into_iter().any(intersects)
+    }
+
+    if bag.is_paged() || !intersects(bag) {
+        Cow::Borrowed(catalog)
+    } else {
+        catalog.with_labels_of(core_id, parameters, bag)
+    }
+}
+
+impl<'e> Analyzer<'e> for Bag {
+    type ResultSet = IterObjectsBySpaces<'e>;
+
+    fn analyze_with(
+        &'e self,
+        core_id: &'e str,
+        parameters: &'e CoreQueryParameters<'e>,
+        catalog: &Catalog,
+    ) -> Result<(Self::ResultSet, Statistics), String> {
+        // The expression is executed as the only operand of a node which
+        // does nothing, to collect its statistics as the ones of the
+        // operands.
+        let catalog = with_labels(self, core_id, parameters, catalog);
+        let root = Rc::new(Probe::new(String::new()));
+        let execution = Execution {
+            core_id,
+            parameters,
+            catalog: &catalog,
+            paged: self.is_paged(),
+            probe: Some(&root),
+        };
+        let results = execution.run(self)?;
+
+        // Results are lazily computed, so read them to measure the
+        // actual cost of the nodes.
+        let results = results
+            .into_iter()
+            .map(|(space, objects)| (space, objects.collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+        let statistics = root.operands.borrow()[0].statistics();
+
+        let results = results
+            .into_iter()
+            .map(|(space, objects)| (space, Box::new(objects.into_iter()) as IterObjects))
+            .collect();
+
+        Ok((results, statistics))
     }
 }
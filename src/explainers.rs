@@ -0,0 +1,223 @@
+use mercator_db::space::Space;
+use mercator_db::CoreQueryParameters;
+
+use super::catalogs::Catalog;
+use super::expressions::*;
+use super::predictors::right_smaller_from;
+use super::predictors::Prediction;
+use super::symbols::*;
+
+/// Execution plan of an expression, as a tree of the operations which
+/// will be executed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Plan {
+    /// Operation executed by this node.
+    pub operation: String,
+    /// Cost of the node, as predicted by the `Predictor`.
+    pub prediction: Prediction,
+    /// Number of objects produced by the node, as estimated by the
+    /// `Estimator`, when the catalog has histograms.
+    pub estimate: Option<f64>,
+    /// How the results of the operands are combined, when it depends on
+    /// the predictions.
+    pub strategy: Option<String>,
+    /// Calls to the database issued by this node itself.
+    pub calls: Vec<String>,
+    /// Plans of the operands, in the order of the expression.
+    pub operands: Vec<Plan>,
+}
+
+impl Plan {
+    fn new(operation: String, prediction: Prediction, estimate: Option<f64>) -> Self {
+        Plan {
+            operation,
+            prediction,
+            estimate,
+            strategy: None,
+            calls: vec![],
+            operands: vec![],
+        }
+    }
+}
+
+impl Explainer for Projection {
+    fn explain_with(
+        &self,
+        core_id: &str,
+        parameters: &CoreQueryParameters,
+        catalog: &Catalog,
+    ) -> Result<Plan, String> {
+        let (operation, bag) = match self {
+            Projection::Nifti(_, LiteralSelector(fields), bag, _) if fields.is_empty() => {
+                ("nifti".to_string(), bag)
+            }
+            Projection::Nifti(_, selector, bag, _) => (format!("nifti({})", selector), bag),
+            Projection::Json(_, format, bag, _) => (format!("json({})", format), bag),
+        };
+
+        // Same volume and number of objects as the bag.
+        let operand = bag.explain_with(core_id, parameters, catalog)?;
+        let mut plan = Plan::new(operation, operand.prediction, operand.estimate);
+        plan.operands.push(operand);
+
+        Ok(plan)
+    }
+}
+
+impl Explainer for Bag {
+    fn explain_with(
+        &self,
+        core_id: &str,
+        parameters: &CoreQueryParameters,
+        catalog: &Catalog,
+    ) -> Result<Plan, String> {
+        // The labels unknown to the catalog are looked up, to predict
+        // their actual volumes.
+        let catalog = catalog.with_labels_of(core_id, parameters, self);
+        self.plan(self.is_paged(), core_id, parameters, &catalog)
+    }
+}
+
+impl Bag {
+    // Plan of the node, whose operands are combined in a fixed order
+    // when the whole expression is `paged`, as by the executor.
+    fn plan(
+        &self,
+        paged: bool,
+        core_id: &str,
+        parameters: &CoreQueryParameters,
+        catalog: &Catalog,
+    ) -> Result<Plan, String> {
+        // The operands are explained first, and the costs of the node
+        // computed from theirs, so that each node is predicted once.
+        let mut operands = vec![];
+        for bag in self.operands() {
+            operands.push(bag.plan(paged, core_id, parameters, catalog)?);
+        }
+        let predictions = operands.iter().map(|p| p.prediction).collect::<Vec<_>>();
+        let estimates = operands
+            .iter()
+            .map(|p| p.estimate)
+            .collect::<Option<Vec<_>>>();
+
+        let mut plan = Plan::new(
+            self.operation(),
+            self.predict_from(&predictions, core_id, parameters, catalog)?,
+            estimates.and_then(|estimates| self.estimate_from(&estimates, catalog).ok()),
+        );
+        match self {
+            Bag::Complement(bag, _) => {
+                // Computed within the universe, see the executor.
+                let universe = Space::universe().name();
+                plan.calls.push(bounding_box(universe));
+                match bag.as_ref() {
+                    // The operand is not executed.
+                    Bag::Inside(shape, _) if !shape.is_label() => {
+                        plan.strategy = Some(GEOMETRY.to_string());
+                        operands.clear();
+                    }
+                    _ => {
+                        plan.strategy = Some("exclude the positions of the operand".to_string());
+                    }
+                }
+            }
+            Bag::Intersection(_, _, _) => {
+                let (l, r) = (&operands[0], &operands[1]);
+                let right = paged
+                    || right_smaller_from(
+                        (Some(l.prediction), l.estimate),
+                        (Some(r.prediction), r.estimate),
+                    );
+                plan.strategy = Some(if right {
+                    "hash the positions of the right operand, probe with the left one".to_string()
+                } else {
+                    "hash the positions of the left operand, probe with the right one".to_string()
+                });
+            }
+            Bag::Union(_, _, _) => {
+                plan.strategy = Some("left operand first, then the right one".to_string());
+            }
+            Bag::Inside(shape, _) => plan.calls = shape.inside_calls(),
+            Bag::Outside(shape, _) if shape.is_label() => {
+                plan.calls = shape.inside_calls();
+                plan.calls.push(bounding_box(shape.space()));
+                plan.strategy = Some("exclude the positions of the label".to_string());
+            }
+            Bag::Outside(shape, _) => {
+                plan.calls.push(bounding_box(shape.space()));
+                plan.strategy = Some(GEOMETRY.to_string());
+            }
+            Bag::Shape(_, _) => {
+                plan.strategy = Some("rasterize at the requested resolution".to_string());
+            }
+            Bag::Limit(n, _, _) => {
+                plan.strategy = Some(format!("stop reading the operand after {} objects", n));
+            }
+            Bag::Offset(n, _, _) => {
+                plan.strategy = Some(format!("skip the first {} objects of the operand", n));
+            }
+            Bag::Distinct(_, _) | Bag::Filter(_, _, _) | Bag::Bag(_, _) => (),
+        }
+        plan.operands = operands;
+
+        Ok(plan)
+    }
+
+    /// Operation executed by the node, as shown in plans and statistics.
+    pub fn operation(&self) -> String {
+        match self {
+            Bag::Distinct(_, _) => "distinct".to_string(),
+            Bag::Filter(None, _, _) => "filter".to_string(),
+            Bag::Filter(Some(predicate), _, _) => format!("filter({})", predicate),
+            Bag::Complement(_, _) => "complement".to_string(),
+            Bag::Intersection(_, _, _) => "intersection".to_string(),
+            Bag::Union(_, _, _) => "union".to_string(),
+            Bag::Bag(_, _) => "bag".to_string(),
+            Bag::Inside(shape, _) => format!("inside({})", shape),
+            Bag::Outside(shape, _) => format!("outside({})", shape),
+            Bag::Shape(shape, _) => format!("shape({})", shape),
+            Bag::Limit(n, _, _) => format!("limit({})", n),
+            Bag::Offset(n, _, _) => format!("offset({})", n),
+        }
+    }
+}
+
+// Exclusion of the positions inside a shape, without querying them.
+const GEOMETRY: &str = "keep the positions outside of the shape, tested as they are read";
+
+// Selection of all the objects of the space.
+fn bounding_box(space_id: &str) -> String {
+    format!(
+        "get_by_shape(BoundingBox, {:?}), bounds of the space",
+        space_id
+    )
+}
+
+impl Shape {
+    // Calls to the database selecting the objects inside the shape.
+    fn inside_calls(&self) -> Vec<String> {
+        let space_id = self.space();
+        let call = match self {
+            Shape::Point(_, _) => format!("get_by_shape(Point, {:?})", space_id),
+            Shape::HyperRectangle(_, vertices) if vertices.len() != 2 => format!(
+                "get_by_shape(BoundingBox, {:?}), then keep the positions inside the box",
+                space_id
+            ),
+            Shape::HyperRectangle(_, _) => format!("get_by_shape(BoundingBox, {:?})", space_id),
+            Shape::HyperSphere(_, _, _) => format!("get_by_shape(HyperSphere, {:?})", space_id),
+            Shape::Label(_, id) => format!("get_by_label({:?})", id),
+            Shape::Nifti(_, _) => format!(
+                "get_by_shape(BoundingBox, {:?}), then keep the positions in non-zero voxels",
+                space_id
+            ),
+        };
+
+        vec![call]
+    }
+
+    // Labels are only known from the database, the other shapes are
+    // tested against their geometry.
+    fn is_label(&self) -> bool {
+        matches!(self, Shape::Label(_, _))
+    }
+}
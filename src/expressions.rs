@@ -1,28 +1,124 @@
 use mercator_db::CoreQueryParameters;
-use mercator_db::DataBase;
+
+use super::catalogs::Catalog;
+use super::executors::Statistics;
+use super::explainers::Plan;
+use super::predictors::Prediction;
+use super::types::Schema;
 
 pub trait Validator {
     type ValidationResult;
 
-    fn validate(&self) -> Self::ValidationResult;
+    /// Check the expression against the default data model.
+    fn validate(&self) -> Self::ValidationResult {
+        self.validate_with(&Schema::default())
+    }
+
+    /// Check the expression against the data model described by `schema`.
+    fn validate_with(&self, schema: &Schema) -> Self::ValidationResult;
 }
 
 pub trait Predictor {
-    fn predict(&self, db: &DataBase) -> Result<f64, String>;
+    /// Range of the volume selected by the expression on the core
+    /// `core_id`.
+    fn predict(
+        &self,
+        core_id: &str,
+        parameters: &CoreQueryParameters,
+    ) -> Result<Prediction, String> {
+        self.predict_with(core_id, parameters, &Catalog::default())
+    }
+
+    /// Range of the volume selected by the expression on the core
+    /// `core_id`, using the extents of the labels known by `catalog`.
+    fn predict_with(
+        &self,
+        core_id: &str,
+        parameters: &CoreQueryParameters,
+        catalog: &Catalog,
+    ) -> Result<Prediction, String>;
+}
+
+pub trait Estimator {
+    /// Number of objects expected in the results of the expression,
+    /// according to the histograms and label extents of `catalog`.
+    fn estimate(&self, catalog: &Catalog) -> Result<f64, String>;
+}
+
+pub trait Explainer {
+    /// Execution plan of the expression on the core `core_id`, with the
+    /// costs predicted from the volumes of the shapes, and of the labels
+    /// looked up in the core.
+    fn explain(&self, core_id: &str, parameters: &CoreQueryParameters) -> Result<Plan, String> {
+        self.explain_with(core_id, parameters, &Catalog::default())
+    }
+
+    /// Execution plan of the expression on the core `core_id`, with the
+    /// costs predicted from what `catalog` knows of its data.
+    fn explain_with(
+        &self,
+        core_id: &str,
+        parameters: &CoreQueryParameters,
+        catalog: &Catalog,
+    ) -> Result<Plan, String>;
 }
 
 pub trait Executor<'e> {
     type ResultSet;
 
+    /// Execute the expression on the core `core_id`, choosing how from
+    /// the volumes of the shapes, and of the labels looked up in the
+    /// core.
     fn execute(
         &'e self,
         core_id: &'e str,
         parameters: &'e CoreQueryParameters<'e>,
+    ) -> Self::ResultSet {
+        self.execute_with(core_id, parameters, &Catalog::default())
+    }
+
+    /// Execute the expression on the core `core_id`, choosing how from
+    /// what `catalog` knows of its data. The labels it does not know are
+    /// looked up in the core.
+    fn execute_with(
+        &'e self,
+        core_id: &'e str,
+        parameters: &'e CoreQueryParameters<'e>,
+        catalog: &Catalog,
     ) -> Self::ResultSet;
 }
 
+pub trait Optimizer {
+    /// Rewrite the expression into an equivalent one, cheaper to
+    /// execute.
+    fn optimize(self) -> Self;
+}
+
+pub trait Analyzer<'e> {
+    type ResultSet;
+
+    /// Execute the expression, and collect statistics on the execution
+    /// of each of its nodes.
+    fn analyze(
+        &'e self,
+        core_id: &'e str,
+        parameters: &'e CoreQueryParameters<'e>,
+    ) -> Result<(Self::ResultSet, Statistics), String> {
+        self.analyze_with(core_id, parameters, &Catalog::default())
+    }
+
+    /// Execute the expression as `Executor::execute_with` does, and
+    /// collect statistics on the execution of each of its nodes.
+    fn analyze_with(
+        &'e self,
+        core_id: &'e str,
+        parameters: &'e CoreQueryParameters<'e>,
+        catalog: &Catalog,
+    ) -> Result<(Self::ResultSet, Statistics), String>;
+}
+
 pub trait Evaluator<'e> {
     type Object;
 
-    fn eval(&self, object: Self::Object) -> bool;
+    fn eval(&self, object: Self::Object) -> Result<bool, String>;
 }
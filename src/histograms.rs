@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+
+use mercator_db::space;
+use mercator_db::CoreQueryParameters;
+
+use super::executors::voxel_size;
+
+// Upper bound on the number of bins of a histogram, all dimensions
+// included.
+const MAX_BINS: usize = 1 << 12;
+
+/// Number of objects of a reference space, per bin of a regular grid
+/// covering the space.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Histogram {
+    low: Vec<f64>,
+    high: Vec<f64>,
+    // Size of the voxels of the space, at the finest resolution.
+    voxel: Vec<f64>,
+    // Number of bins per dimension.
+    bins: usize,
+    counts: Vec<usize>,
+    total: usize,
+}
+
+impl Histogram {
+    /// Empty histogram of the space bounded by `low` and `high`.
+    pub fn new(low: Vec<f64>, high: Vec<f64>) -> Self {
+        let dimensions = low.len().max(1);
+        let mut bins = (MAX_BINS as f64).powf(1.0 / dimensions as f64).floor() as usize;
+        // Guard against rounding errors of the root.
+        while bins.pow(dimensions as u32) > MAX_BINS {
+            bins -= 1;
+        }
+        let bins = bins.max(1);
+
+        Histogram {
+            counts: vec![0; bins.pow(low.len() as u32)],
+            voxel: vec![1.0; low.len()],
+            low,
+            high,
+            bins,
+            total: 0,
+        }
+    }
+
+    /// Use `voxel` as the size of the voxels of the space, at the finest
+    /// resolution, instead of one along each dimension.
+    pub fn with_voxel(mut self, voxel: Vec<f64>) -> Self {
+        self.voxel = voxel;
+        self
+    }
+
+    /// Size of the voxels of the space, at the finest resolution.
+    pub fn voxel(&self) -> &[f64] {
+        &self.voxel
+    }
+
+    // Width of the bins along dimension `i`.
+    fn width(&self, i: usize) -> f64 {
+        (self.high[i] - self.low[i]) / self.bins as f64
+    }
+
+    // Index of the bin of `position`, or of the closest bin for the
+    // positions outside of the space.
+    fn index(&self, position: &[f64]) -> usize {
+        let mut index = 0;
+        for (i, x) in position.iter().enumerate().take(self.low.len()) {
+            let width = self.width(i);
+            let bin = if width > 0.0 {
+                (((x - self.low[i]) / width).floor().max(0.0) as usize).min(self.bins - 1)
+            } else {
+                0
+            };
+            index = index * self.bins + bin;
+        }
+
+        index
+    }
+
+    /// Count an object at `position`. Positions outside of the space are
+    /// counted in the closest bin.
+    pub fn add(&mut self, position: &[f64]) {
+        let index = self.index(position);
+        self.counts[index] += 1;
+        self.total += 1;
+    }
+
+    /// Number of objects in the bin of `position`.
+    pub fn bin(&self, position: &[f64]) -> usize {
+        if position.len() != self.low.len() {
+            return 0;
+        }
+
+        self.counts[self.index(position)]
+    }
+
+    /// Number of objects in the space.
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    // Fraction of the bin `bin` of dimension `i` within [low, high].
+    fn overlap(&self, i: usize, bin: usize, low: f64, high: f64) -> f64 {
+        let width = self.width(i);
+        let start = self.low[i] + bin as f64 * width;
+        let end = start + width;
+
+        if width > 0.0 {
+            ((high.min(end) - low.max(start)) / width).clamp(0.0, 1.0)
+        } else if low <= start && start <= high {
+            1.0
+        } else {
+            0.0
+        }
+    }
+
+    /// Expected number of objects within the box bounded by `low` and
+    /// `high`, assuming the objects are uniformly distributed within
+    /// each bin.
+    pub fn count(&self, low: &[f64], high: &[f64]) -> f64 {
+        let dimensions = self.low.len();
+        if low.len() != dimensions || high.len() != dimensions {
+            return 0.0;
+        }
+
+        let mut count = 0.0;
+        for (index, objects) in self.counts.iter().enumerate() {
+            if *objects == 0 {
+                continue;
+            }
+
+            // Coordinates of the bin, last dimension first.
+            let mut rest = index;
+            let mut fraction = 1.0;
+            for i in (0..dimensions).rev() {
+                fraction *= self.overlap(i, rest % self.bins, low[i], high[i]);
+                rest /= self.bins;
+            }
+
+            count += *objects as f64 * fraction;
+        }
+
+        count
+    }
+
+    /// Expected number of objects per unit of volume around `position`.
+    pub fn density(&self, position: &[f64]) -> f64 {
+        let dimensions = self.low.len();
+        let mut low = Vec::with_capacity(dimensions);
+        let mut high = Vec::with_capacity(dimensions);
+        let mut volume = 1.0;
+
+        // Box of one bin centered on the position.
+        for (i, x) in position.iter().enumerate().take(dimensions) {
+            let width = self.width(i);
+            low.push(x - width / 2.0);
+            high.push(x + width / 2.0);
+            if width > 0.0 {
+                volume *= width;
+            }
+        }
+
+        self.count(&low, &high) / volume
+    }
+}
+
+/// Histograms of the reference spaces of a core.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Histograms {
+    histograms: HashMap<String, Histogram>,
+}
+
+impl Histograms {
+    /// Build the histograms of `spaces`, from the objects of the core
+    /// `core_id`.
+    pub fn build<S: AsRef<str>>(
+        core_id: &str,
+        parameters: &CoreQueryParameters,
+        spaces: &[S],
+    ) -> Result<Self, String> {
+        let core = parameters.db.core(core_id)?;
+        let mut histograms = Histograms::default();
+
+        for space_id in spaces {
+            let space_id = space_id.as_ref();
+            let space = parameters.db.space(space_id)?;
+            let (low, high) = space.bounding_box();
+            let voxel = voxel_size(space, low.dimensions(), &[]);
+            let mut histogram = Histogram::new((&low).into(), (&high).into()).with_voxel(voxel);
+
+            let objects =
+                core.get_by_shape(parameters, space::Shape::BoundingBox(low, high), space_id)?;
+            for (_, objects) in objects {
+                for (position, _) in objects {
+                    let position: Vec<f64> = (&position).into();
+                    histogram.add(&position);
+                }
+            }
+
+            histograms.insert(space_id, histogram);
+        }
+
+        Ok(histograms)
+    }
+
+    pub fn insert(&mut self, space_id: &str, histogram: Histogram) {
+        self.histograms.insert(space_id.to_string(), histogram);
+    }
+
+    pub fn get(&self, space_id: &str) -> Result<&Histogram, String> {
+        self.histograms
+            .get(space_id)
+            .ok_or_else(|| format!("No histogram for space '{}'", space_id))
+    }
+}
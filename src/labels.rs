@@ -0,0 +1,72 @@
+use std::collections::HashSet;
+
+use mercator_db::CoreQueryParameters;
+
+/// Extent of a label, as found in the database.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LabelExtent {
+    /// Lowest coordinates of the positions of the label.
+    pub low: Vec<f64>,
+    /// Highest coordinates of the positions of the label.
+    pub high: Vec<f64>,
+    /// Number of distinct positions of the label.
+    pub positions: usize,
+    /// Number of objects within the label.
+    pub objects: usize,
+}
+
+impl LabelExtent {
+    /// Extent of the label `id` of the core `core_id`, from the
+    /// positions of its objects. See `Catalog::insert_label` to keep it.
+    pub fn lookup(
+        core_id: &str,
+        parameters: &CoreQueryParameters,
+        id: &str,
+    ) -> Result<LabelExtent, String> {
+        let core = parameters.db.core(core_id)?;
+        let mut extent = LabelExtent::default();
+        let mut positions = HashSet::new();
+
+        for (_, objects) in core.get_by_label(parameters, id)? {
+            for (position, _) in objects {
+                let coordinates: Vec<f64> = (&position).into();
+                extent.include(&coordinates);
+                positions.insert(position);
+            }
+        }
+        extent.positions = positions.len();
+
+        Ok(extent)
+    }
+
+    // Extend the extent to an object at `position`.
+    fn include(&mut self, position: &[f64]) {
+        if self.objects == 0 {
+            self.low = position.to_vec();
+            self.high = position.to_vec();
+        } else {
+            for ((l, h), x) in self.low.iter_mut().zip(&mut self.high).zip(position) {
+                *l = l.min(*x);
+                *h = h.max(*x);
+            }
+        }
+        self.objects += 1;
+    }
+
+    /// Volume of the bounding box of the label, or the smallest non-zero
+    /// volume for labels without extent.
+    pub fn volume(&self) -> f64 {
+        let volume = self
+            .low
+            .iter()
+            .zip(&self.high)
+            .map(|(l, h)| h - l)
+            .product::<f64>();
+
+        if self.objects == 0 || volume <= 0.0 {
+            f64::EPSILON
+        } else {
+            volume
+        }
+    }
+}
@@ -38,29 +38,86 @@ lalrpop_mod!(#[allow(clippy::all,unused_parens)] pub queries); // synthesized by
 //       as well.
 //       Instead we enable it per modules below, except for the tests.
 
+//#[warn(missing_docs)]
+mod builders;
+//#[warn(missing_docs)]
+mod catalogs;
+//#[warn(missing_docs)]
+mod cursors;
+//#[warn(missing_docs)]
+mod diagnostics;
+//#[warn(missing_docs)]
+mod error;
+//#[warn(missing_docs)]
+mod estimators;
 //#[warn(missing_docs)]
 mod evaluators;
 //#[warn(missing_docs)]
 mod executors;
 //#[warn(missing_docs)]
+mod explainers;
+//#[warn(missing_docs)]
 mod expressions;
 //#[warn(missing_docs)]
+mod histograms;
+//#[warn(missing_docs)]
+mod labels;
+//#[warn(missing_docs)]
+mod nifti;
+//#[warn(missing_docs)]
+mod optimizers;
+//#[warn(missing_docs)]
 mod predictors;
 //#[warn(missing_docs)]
+mod printers;
+//#[warn(missing_docs)]
 mod validators;
 
+/// Versioned encodings of the parsed queries, to ship them without
+/// parsing them again.
+#[cfg(feature = "serde")]
+pub mod serialization;
+
 //#[warn(missing_docs)]
 mod symbols;
 //#[warn(missing_docs)]
 mod types;
 
+pub use catalogs::Catalog;
+pub use cursors::Cursor;
+pub use error::Error;
+pub use error::Span;
+pub use error::TokenError;
+pub use executors::ProjectionResult;
+pub use executors::Statistics;
+pub use explainers::Plan;
+pub use expressions::Analyzer;
+pub use expressions::Estimator;
 pub use expressions::Executor;
+pub use expressions::Explainer;
+pub use expressions::Optimizer;
 pub use expressions::Predictor;
 pub use expressions::Validator;
+pub use histograms::Histogram;
+pub use histograms::Histograms;
+pub use labels::LabelExtent;
+pub use predictors::Prediction;
 pub use queries::FiltersParser;
 pub use queries::QueryParser;
+pub use symbols::Aggregation;
 pub use symbols::Bag;
+pub use symbols::ByteProvider;
+pub use symbols::Field;
+pub use symbols::JsonValue;
+pub use symbols::LiteralNumber;
+pub use symbols::LiteralPosition;
+pub use symbols::LiteralSelector;
+pub use symbols::Position;
+pub use symbols::Predicate;
 pub use symbols::Projection;
+pub use symbols::Shape;
+pub use symbols::Transform;
+pub use types::Schema;
 pub use validators::ValidationResult;
 
 #[cfg(test)]
@@ -7,10 +7,17 @@ use std::io;
 
 use mercator_db::CoreQueryParameters;
 use mercator_db::DataBase;
+use mercator_parser::Analyzer;
+use mercator_parser::Catalog;
+use mercator_parser::Error;
 use mercator_parser::Executor;
+use mercator_parser::Explainer;
 use mercator_parser::FiltersParser;
+use mercator_parser::Histograms;
+use mercator_parser::Optimizer;
 use mercator_parser::Predictor;
 use mercator_parser::QueryParser;
+use mercator_parser::Schema;
 use mercator_parser::Validator;
 
 fn main() {
@@ -37,6 +44,18 @@ fn main() {
         view_port: &None,
         resolution: &Some(vec![0]),
     };
+    let schema = Schema::default().with_database(&db);
+
+    // Estimate the costs of the queries from the data itself.
+    let mut catalog = Catalog::default();
+    {
+        info_time!("Building histograms");
+        match Histograms::build(core, &parameters, &db.space_ids()) {
+            Ok(histograms) => catalog = catalog.with_histograms(histograms),
+            Err(e) => warn!("Unable to build the histograms: {}", e),
+        }
+    }
+
     let parser = QueryParser::new();
     let parser = FiltersParser::new();
 
@@ -54,15 +73,29 @@ fn main() {
                     break;
                 }
 
+                // Queries prefixed by `explain` are not executed, their
+                // execution plan is printed instead. With `explain analyze`
+                // they are executed as well, and the statistics of the
+                // execution are printed along the plan.
+                let query = input.trim_start();
+                let (explain, analyze, input) =
+                    if let Some(query) = query.strip_prefix("explain analyze ") {
+                        (true, true, query.to_string())
+                    } else if let Some(query) = query.strip_prefix("explain ") {
+                        (true, false, query.to_string())
+                    } else {
+                        (false, false, input.clone())
+                    };
+
                 info_time!("Interpretation");
                 let parse;
                 {
                     info_time!("Parsing");
-                    parse = parser.parse(&input);
+                    parse = parser.parse(&input).map_err(Error::from);
                 }
 
                 if let Err(e) = &parse {
-                    warn!("Parsing failed: \n{:?}", e);
+                    warn!("Parsing failed: \n{}", e.render(&input));
                 } else {
                     trace!("Tree: \n{:?}", parse);
                 }
@@ -75,22 +108,55 @@ fn main() {
                     let validate;
                     {
                         info_time!("Type check");
-                        validate = t.validate();
+                        validate = t.validate_with(&schema);
+                    }
+                    match &validate {
+                        Ok(t) => info!("Type: \n{:?}", t),
+                        Err(e) => warn!("Type check failed: \n{}", e.render(&input)),
                     }
-                    info!("Type: \n{:?}", validate);
 
                     if validate.is_ok() {
+                        let t = {
+                            info_time!("Optimization");
+                            t.optimize()
+                        };
+                        trace!("Optimized: \n{}", t);
+
+                        // The extents of the labels are kept for the
+                        // following queries.
+                        {
+                            info_time!("Looking up labels");
+                            if let Err(e) = catalog.lookup_labels(core, &parameters, &t) {
+                                warn!("Unable to look up the labels: {}", e);
+                            }
+                        }
+
+                        if explain {
+                            match t.explain_with(core, &parameters, &catalog) {
+                                Ok(plan) => info!("Plan: \n{}", plan),
+                                Err(e) => warn!("Explain failed: {}", e),
+                            }
+
+                            if analyze {
+                                match t.analyze_with(core, &parameters, &catalog) {
+                                    Ok((_, statistics)) => info!("Analysis: \n{}", statistics),
+                                    Err(e) => warn!("Analysis failed: {}", e),
+                                }
+                            }
+                            continue;
+                        }
+
                         let predict;
                         {
                             info_time!("Prediction");
-                            predict = t.predict(&db);
+                            predict = t.predict_with(core, &parameters, &catalog);
                         }
                         info!("Predict: \n{:?}", predict);
 
                         let execute;
                         {
                             info_time!("Execution");
-                            execute = t.execute(core, &parameters);
+                            execute = t.execute_with(core, &parameters, &catalog);
                         }
 
                         match execute {
@@ -0,0 +1,664 @@
+// Minimal support for NIfTI-1 single file volumes (".nii").
+//
+// Specification: https://nifti.nimh.nih.gov/nifti-1/
+
+const HEADER_SIZE: usize = 348;
+const VOX_OFFSET: usize = 352; // Header + 4 bytes of extension flags.
+
+// Data types
+const DT_UINT8: i16 = 2;
+const DT_INT16: i16 = 4;
+const DT_INT32: i16 = 8;
+const DT_FLOAT32: i16 = 16;
+const DT_FLOAT64: i16 = 64;
+const DT_INT8: i16 = 256;
+const DT_UINT16: i16 = 512;
+const DT_UINT32: i16 = 768;
+const DT_INT64: i16 = 1024;
+const DT_UINT64: i16 = 1280;
+
+// Transform codes
+const NIFTI_XFORM_ALIGNED_ANAT: i16 = 2;
+
+/// Affine transformation in three dimensions, stored as the first three
+/// rows of the homogeneous 4x4 matrix.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Affine([[f64; 4]; 3]);
+
+impl Affine {
+    pub fn new(matrix: [[f64; 4]; 3]) -> Self {
+        Affine(matrix)
+    }
+
+    pub fn identity() -> Self {
+        Affine([
+            [1.0, 0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+        ])
+    }
+
+    pub fn apply(&self, p: &[f64; 3]) -> [f64; 3] {
+        let Affine(m) = self;
+        let mut r = [0.0; 3];
+        for (r, row) in r.iter_mut().zip(m) {
+            *r = row[0] * p[0] + row[1] * p[1] + row[2] * p[2] + row[3];
+        }
+
+        r
+    }
+
+    /// Compose the transformations, `self` being applied first.
+    pub fn then(&self, next: &Affine) -> Affine {
+        let Affine(a) = self;
+        let Affine(b) = next;
+        let mut m = [[0.0; 4]; 3];
+
+        for (i, row) in m.iter_mut().enumerate() {
+            for (j, v) in row.iter_mut().enumerate() {
+                *v = b[i][0] * a[0][j] + b[i][1] * a[1][j] + b[i][2] * a[2][j];
+            }
+            row[3] += b[i][3];
+        }
+
+        Affine(m)
+    }
+
+    /// Determinant of the linear part of the transformation.
+    pub fn determinant(&self) -> f64 {
+        let Affine(m) = self;
+
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+
+    // Quaternion (b, c, d) and qfac of the transformation, as stored in
+    // the qform of the headers, when its linear part is a rotation,
+    // possibly with a reflection along the third axis, scaled by `pixdim`
+    // along each axis.
+    fn quaternion(&self, pixdim: &[f64; 3]) -> Option<([f64; 3], f64)> {
+        if pixdim.iter().any(|p| *p <= 0.0) {
+            return None;
+        }
+
+        let Affine(m) = self;
+        let mut r = [[0.0; 3]; 3];
+        for (row, m) in r.iter_mut().zip(m) {
+            for ((v, x), p) in row.iter_mut().zip(m).zip(pixdim) {
+                *v = x / p;
+            }
+        }
+
+        // The columns have to be orthonormal.
+        for j in 0..3 {
+            for k in j..3 {
+                let dot = r.iter().map(|row| row[j] * row[k]).sum::<f64>();
+                let expected = if j == k { 1.0 } else { 0.0 };
+                if (dot - expected).abs() > 1e-6 {
+                    return None;
+                }
+            }
+        }
+
+        let rotation = Affine([
+            [r[0][0], r[0][1], r[0][2], 0.0],
+            [r[1][0], r[1][1], r[1][2], 0.0],
+            [r[2][0], r[2][1], r[2][2], 0.0],
+        ]);
+        let qfac = if rotation.determinant() < 0.0 {
+            for row in r.iter_mut() {
+                row[2] = -row[2];
+            }
+            -1.0
+        } else {
+            1.0
+        };
+
+        // Conversion of the rotation matrix, as in the reference
+        // implementation of the NIfTI-1 library.
+        let trace = r[0][0] + r[1][1] + r[2][2] + 1.0;
+        let (a, b, c, d) = if trace > 0.5 {
+            let a = 0.5 * trace.sqrt();
+            (
+                a,
+                0.25 * (r[2][1] - r[1][2]) / a,
+                0.25 * (r[0][2] - r[2][0]) / a,
+                0.25 * (r[1][0] - r[0][1]) / a,
+            )
+        } else {
+            let xd = 1.0 + r[0][0] - (r[1][1] + r[2][2]);
+            let yd = 1.0 + r[1][1] - (r[0][0] + r[2][2]);
+            let zd = 1.0 + r[2][2] - (r[0][0] + r[1][1]);
+            if xd > 1.0 {
+                let b = 0.5 * xd.sqrt();
+                (
+                    0.25 * (r[2][1] - r[1][2]) / b,
+                    b,
+                    0.25 * (r[0][1] + r[1][0]) / b,
+                    0.25 * (r[0][2] + r[2][0]) / b,
+                )
+            } else if yd > 1.0 {
+                let c = 0.5 * yd.sqrt();
+                (
+                    0.25 * (r[0][2] - r[2][0]) / c,
+                    0.25 * (r[0][1] + r[1][0]) / c,
+                    c,
+                    0.25 * (r[1][2] + r[2][1]) / c,
+                )
+            } else {
+                let d = 0.5 * zd.sqrt();
+                (
+                    0.25 * (r[1][0] - r[0][1]) / d,
+                    0.25 * (r[0][2] + r[2][0]) / d,
+                    0.25 * (r[1][2] + r[2][1]) / d,
+                    d,
+                )
+            }
+        };
+
+        // The first parameter is not stored, and assumed positive.
+        if a < 0.0 {
+            Some(([-b, -c, -d], qfac))
+        } else {
+            Some(([b, c, d], qfac))
+        }
+    }
+
+    pub fn inverse(&self) -> Result<Affine, String> {
+        let det = self.determinant();
+        if det.abs() < f64::EPSILON {
+            return Err("The transformation is not invertible.".to_string());
+        }
+
+        let Affine(m) = self;
+        let mut inv = [[0.0; 4]; 3];
+
+        // Inverse of the linear part, using the cofactors.
+        for (i, row) in inv.iter_mut().enumerate() {
+            for (j, v) in row.iter_mut().take(3).enumerate() {
+                let (r0, r1) = ((j + 1) % 3, (j + 2) % 3);
+                let (c0, c1) = ((i + 1) % 3, (i + 2) % 3);
+                *v = (m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]) / det;
+            }
+        }
+
+        // Translation part: -inv * t
+        for row in inv.iter_mut() {
+            row[3] = -(row[0] * m[0][3] + row[1] * m[1][3] + row[2] * m[2][3]);
+        }
+
+        Ok(Affine(inv))
+    }
+}
+
+/// A volume of voxels, with at most three spatial dimensions.
+#[derive(Clone, Debug)]
+pub struct Volume {
+    // Number of voxels along each dimension.
+    dimensions: Vec<usize>,
+    // Size of a voxel along each dimension.
+    voxel_size: Vec<f64>,
+    // Affine transformation from voxel indices to world coordinates.
+    affine: Affine,
+    // Values, in x-fastest order.
+    data: Vec<f32>,
+}
+
+impl Volume {
+    /// Create an empty volume, whose voxel `[0, .., 0]` is centered on
+    /// `origin`.
+    pub fn new(
+        dimensions: Vec<usize>,
+        origin: &[f64],
+        voxel_size: Vec<f64>,
+    ) -> Result<Self, String> {
+        let n = dimensions.len();
+        if origin.len() != n || voxel_size.len() != n {
+            return Err("NIfTI: incoherent number of dimensions.".to_string());
+        }
+
+        let mut affine = Affine::identity();
+        for (i, row) in affine.0.iter_mut().enumerate().take(n) {
+            row[i] = voxel_size[i];
+            row[3] = origin[i];
+        }
+
+        Self::with_affine(dimensions, voxel_size, affine)
+    }
+
+    fn with_affine(
+        dimensions: Vec<usize>,
+        voxel_size: Vec<f64>,
+        affine: Affine,
+    ) -> Result<Self, String> {
+        let n = dimensions.len();
+        if n == 0 || n > 3 {
+            return Err(format!(
+                "NIfTI: unsupported number of dimensions: {}, only 1 to 3 are supported.",
+                n
+            ));
+        }
+
+        if dimensions.iter().any(|d| *d == 0 || *d > i16::MAX as usize) {
+            return Err(format!(
+                "NIfTI: invalid volume dimensions: {:?}",
+                dimensions
+            ));
+        }
+
+        let len = dimensions
+            .iter()
+            .try_fold(1usize, |acc, d| acc.checked_mul(*d))
+            .ok_or_else(|| "NIfTI: volume too large.".to_string())?;
+
+        Ok(Volume {
+            dimensions,
+            voxel_size,
+            affine,
+            data: vec![0.0; len],
+        })
+    }
+
+    pub fn affine(&self) -> &Affine {
+        &self.affine
+    }
+
+    fn offset(&self, index: &[usize]) -> Option<usize> {
+        if index.len() != self.dimensions.len() {
+            return None;
+        }
+
+        let mut offset = 0;
+        let mut stride = 1;
+        for (i, d) in index.iter().zip(&self.dimensions) {
+            if i >= d {
+                return None;
+            }
+            offset += i * stride;
+            stride *= d;
+        }
+
+        Some(offset)
+    }
+
+    pub fn get(&self, index: &[usize]) -> Option<f32> {
+        self.offset(index).map(|offset| self.data[offset])
+    }
+
+    pub fn set(&mut self, index: &[usize], value: f32) -> Result<(), String> {
+        match self.offset(index) {
+            None => Err(format!("NIfTI: index out of bounds: {:?}", index)),
+            Some(offset) => {
+                self.data[offset] = value;
+                Ok(())
+            }
+        }
+    }
+
+    /// Indices of the voxels with a non-zero value, padded to three
+    /// dimensions.
+    pub fn non_zero(&self) -> impl Iterator<Item = [usize; 3]> + '_ {
+        let mut dimensions = [1; 3];
+        dimensions[..self.dimensions.len()].copy_from_slice(&self.dimensions);
+
+        self.data
+            .iter()
+            .enumerate()
+            .filter(|(_, v)| **v != 0.0)
+            .map(move |(offset, _)| {
+                [
+                    offset % dimensions[0],
+                    (offset / dimensions[0]) % dimensions[1],
+                    offset / (dimensions[0] * dimensions[1]),
+                ]
+            })
+    }
+
+    /// Parse a NIfTI-1 single file (".nii").
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        fn ordered<const N: usize>(bytes: &[u8], offset: usize, little_endian: bool) -> [u8; N] {
+            let mut a = [0u8; N];
+            a.copy_from_slice(&bytes[offset..offset + N]);
+            if !little_endian {
+                a.reverse();
+            }
+            a
+        }
+
+        if bytes.len() < VOX_OFFSET {
+            return Err("NIfTI: truncated header.".to_string());
+        }
+
+        let little_endian = if i32::from_le_bytes(ordered(bytes, 0, true)) == HEADER_SIZE as i32 {
+            true
+        } else if i32::from_le_bytes(ordered(bytes, 0, false)) == HEADER_SIZE as i32 {
+            false
+        } else {
+            return Err("NIfTI: invalid header size.".to_string());
+        };
+
+        let i16_at = |offset| i16::from_le_bytes(ordered(bytes, offset, little_endian));
+        let f32_at = |offset| f32::from_le_bytes(ordered(bytes, offset, little_endian)) as f64;
+
+        match &bytes[344..348] {
+            b"n+1\0" => (),
+            b"ni1\0" => {
+                return Err("NIfTI: separate header and image files are not supported.".to_string())
+            }
+            _ => return Err("NIfTI: invalid magic string.".to_string()),
+        }
+
+        // Dimensions after the third one are accepted only if they are
+        // of size 1.
+        let n = i16_at(40);
+        if !(1..=7).contains(&n) {
+            return Err(format!("NIfTI: invalid number of dimensions: {}", n));
+        }
+        let mut dimensions = vec![];
+        for i in 0..n as usize {
+            let d = i16_at(42 + i * 2);
+            if d < 1 || (i >= 3 && d != 1) {
+                return Err(format!("NIfTI: unsupported dimension {}: {}", i + 1, d));
+            }
+            if i < 3 {
+                dimensions.push(d as usize);
+            }
+        }
+
+        let voxel_size = (0..dimensions.len())
+            .map(|i| f32_at(80 + i * 4))
+            .collect::<Vec<_>>();
+
+        let affine = if i16_at(254) > 0 {
+            // sform
+            let mut m = [[0.0; 4]; 3];
+            for (r, row) in m.iter_mut().enumerate() {
+                for (c, v) in row.iter_mut().enumerate() {
+                    *v = f32_at(280 + r * 16 + c * 4);
+                }
+            }
+            Affine::new(m)
+        } else if i16_at(252) > 0 {
+            // qform
+            let (b, c, d) = (f32_at(256), f32_at(260), f32_at(264));
+            let a = (1.0 - (b * b + c * c + d * d)).max(0.0).sqrt();
+            let qfac = if f32_at(76) < 0.0 { -1.0 } else { 1.0 };
+            let mut pixdim = [1.0; 3];
+            for (i, p) in pixdim.iter_mut().enumerate() {
+                let v = f32_at(80 + i * 4);
+                if v > 0.0 {
+                    *p = v;
+                }
+            }
+            pixdim[2] *= qfac;
+
+            let r = [
+                [
+                    a * a + b * b - c * c - d * d,
+                    2.0 * (b * c - a * d),
+                    2.0 * (b * d + a * c),
+                ],
+                [
+                    2.0 * (b * c + a * d),
+                    a * a + c * c - b * b - d * d,
+                    2.0 * (c * d - a * b),
+                ],
+                [
+                    2.0 * (b * d - a * c),
+                    2.0 * (c * d + a * b),
+                    a * a + d * d - b * b - c * c,
+                ],
+            ];
+            let offset = [f32_at(268), f32_at(272), f32_at(276)];
+
+            let mut m = [[0.0; 4]; 3];
+            for (i, row) in m.iter_mut().enumerate() {
+                for (j, v) in row.iter_mut().take(3).enumerate() {
+                    *v = r[i][j] * pixdim[j];
+                }
+                row[3] = offset[i];
+            }
+            Affine::new(m)
+        } else {
+            // Scaling only
+            let mut affine = Affine::identity();
+            for (i, row) in affine.0.iter_mut().enumerate().take(dimensions.len()) {
+                row[i] = voxel_size[i];
+            }
+            affine
+        };
+
+        let datatype = i16_at(70);
+        let size = match datatype {
+            DT_UINT8 | DT_INT8 => 1,
+            DT_INT16 | DT_UINT16 => 2,
+            DT_INT32 | DT_UINT32 | DT_FLOAT32 => 4,
+            DT_FLOAT64 | DT_INT64 | DT_UINT64 => 8,
+            _ => return Err(format!("NIfTI: unsupported data type: {}", datatype)),
+        };
+
+        let (slope, intercept) = match f32_at(112) {
+            s if s == 0.0 => (1.0, 0.0),
+            s => (s, f32_at(116)),
+        };
+
+        // Check the data is actually there before allocating the volume,
+        // as the header alone can claim billions of voxels.
+        let start = f32_at(108) as usize;
+        let end = dimensions
+            .iter()
+            .try_fold(size, |acc, d| acc.checked_mul(*d))
+            .and_then(|len| len.checked_add(start));
+        match end {
+            Some(end) if start >= VOX_OFFSET && end <= bytes.len() => (),
+            _ => return Err("NIfTI: truncated data.".to_string()),
+        }
+
+        let mut volume = Volume::with_affine(dimensions, voxel_size, affine)?;
+
+        for (i, v) in volume.data.iter_mut().enumerate() {
+            let offset = start + i * size;
+            let raw = match datatype {
+                DT_UINT8 => bytes[offset] as f64,
+                DT_INT8 => bytes[offset] as i8 as f64,
+                DT_INT16 => i16::from_le_bytes(ordered(bytes, offset, little_endian)) as f64,
+                DT_UINT16 => u16::from_le_bytes(ordered(bytes, offset, little_endian)) as f64,
+                DT_INT32 => i32::from_le_bytes(ordered(bytes, offset, little_endian)) as f64,
+                DT_UINT32 => u32::from_le_bytes(ordered(bytes, offset, little_endian)) as f64,
+                DT_FLOAT32 => f32::from_le_bytes(ordered(bytes, offset, little_endian)) as f64,
+                DT_FLOAT64 => f64::from_le_bytes(ordered(bytes, offset, little_endian)),
+                DT_INT64 => i64::from_le_bytes(ordered(bytes, offset, little_endian)) as f64,
+                _ => u64::from_le_bytes(ordered(bytes, offset, little_endian)) as f64,
+            };
+            *v = (raw * slope + intercept) as f32;
+        }
+
+        Ok(volume)
+    }
+
+    /// Serialize the volume as a NIfTI-1 single file.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        fn put_i16(buffer: &mut [u8], offset: usize, value: i16) {
+            buffer[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+        }
+
+        fn put_i32(buffer: &mut [u8], offset: usize, value: i32) {
+            buffer[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+
+        fn put_f32(buffer: &mut [u8], offset: usize, value: f32) {
+            buffer[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+
+        let mut buffer = vec![0u8; VOX_OFFSET + self.data.len() * 4];
+
+        put_i32(&mut buffer, 0, HEADER_SIZE as i32); // sizeof_hdr
+        buffer[38] = b'r'; // regular
+
+        // dim[8]
+        put_i16(&mut buffer, 40, self.dimensions.len() as i16);
+        for i in 0..7 {
+            let d = self.dimensions.get(i).copied().unwrap_or(1);
+            put_i16(&mut buffer, 42 + i * 2, d as i16);
+        }
+
+        put_i16(&mut buffer, 70, DT_FLOAT32); // datatype
+        put_i16(&mut buffer, 72, 32); // bitpix
+
+        // pixdim[8], pixdim[0] is qfac.
+        put_f32(&mut buffer, 76, 1.0);
+        for i in 0..7 {
+            let d = self.voxel_size.get(i).copied().unwrap_or(1.0);
+            put_f32(&mut buffer, 80 + i * 4, d as f32);
+        }
+
+        put_f32(&mut buffer, 108, VOX_OFFSET as f32); // vox_offset
+        put_f32(&mut buffer, 112, 1.0); // scl_slope
+
+        let (min, max) = self
+            .data
+            .iter()
+            .fold((0f32, 0f32), |(min, max), v| (min.min(*v), max.max(*v)));
+        put_f32(&mut buffer, 124, max); // cal_max
+        put_f32(&mut buffer, 128, min); // cal_min
+
+        let description = b"Mercator";
+        buffer[148..148 + description.len()].copy_from_slice(description);
+
+        put_i16(&mut buffer, 254, NIFTI_XFORM_ALIGNED_ANAT); // sform_code
+
+        // srow_x, srow_y, srow_z
+        for (r, row) in self.affine.0.iter().enumerate() {
+            for (c, v) in row.iter().enumerate() {
+                put_f32(&mut buffer, 280 + r * 16 + c * 4, *v as f32);
+            }
+        }
+
+        // The same transformation as a quaternion, for the readers which
+        // only use the qform, when it can be expressed that way.
+        let mut pixdim = [1.0; 3];
+        for (p, v) in pixdim.iter_mut().zip(&self.voxel_size) {
+            *p = *v;
+        }
+        if let Some((quaternion, qfac)) = self.affine.quaternion(&pixdim) {
+            put_i16(&mut buffer, 252, NIFTI_XFORM_ALIGNED_ANAT); // qform_code
+            put_f32(&mut buffer, 76, qfac as f32);
+
+            // quatern_b, quatern_c, quatern_d, then qoffset_x, _y, _z
+            for (i, q) in quaternion.iter().enumerate() {
+                put_f32(&mut buffer, 256 + i * 4, *q as f32);
+            }
+            for (i, row) in self.affine.0.iter().enumerate() {
+                put_f32(&mut buffer, 268 + i * 4, row[3] as f32);
+            }
+        }
+
+        buffer[344..348].copy_from_slice(b"n+1\0"); // magic
+
+        for (i, v) in self.data.iter().enumerate() {
+            put_f32(&mut buffer, VOX_OFFSET + i * 4, *v);
+        }
+
+        buffer
+    }
+}
+
+/// The non-zero voxels of a volume, placed in a reference space.
+#[derive(Clone, Debug)]
+pub struct Mask {
+    volume: Volume,
+    to_world: Affine,
+    to_voxel: Affine,
+}
+
+impl Mask {
+    /// Place the volume in space, by applying `transform` after the
+    /// transformation of the volume itself.
+    pub fn new(volume: Volume, transform: &Affine) -> Result<Self, String> {
+        let to_world = volume.affine().then(transform);
+        let to_voxel = to_world.inverse()?;
+
+        Ok(Mask {
+            volume,
+            to_world,
+            to_voxel,
+        })
+    }
+
+    // Positions of spaces with fewer than three dimensions lie on the
+    // first axes of the world, their other coordinates being zero.
+    fn world(position: &[f64]) -> Option<[f64; 3]> {
+        if position.len() > 3 {
+            None
+        } else {
+            let mut world = [0.0; 3];
+            world[..position.len()].copy_from_slice(position);
+            Some(world)
+        }
+    }
+
+    /// Whether the position, of at most three dimensions, falls within
+    /// a non-zero voxel.
+    pub fn contains(&self, position: &[f64]) -> bool {
+        let position = match Self::world(position) {
+            None => return false,
+            Some(p) => p,
+        };
+
+        let mut index = vec![];
+        for (i, v) in self.to_voxel.apply(&position).iter().enumerate() {
+            let v = v.round();
+            if v < 0.0 {
+                return false;
+            }
+            if i < self.volume.dimensions.len() {
+                index.push(v as usize);
+            } else if v != 0.0 {
+                return false;
+            }
+        }
+
+        match self.volume.get(&index) {
+            None => false,
+            Some(v) => v != 0.0,
+        }
+    }
+
+    /// Axis-aligned bounding box of the whole volume, as (low, high).
+    pub fn bounding_box(&self) -> (Vec<f64>, Vec<f64>) {
+        let mut dimensions = [1; 3];
+        dimensions[..self.volume.dimensions.len()].copy_from_slice(&self.volume.dimensions);
+
+        let mut low = vec![f64::INFINITY; 3];
+        let mut high = vec![f64::NEG_INFINITY; 3];
+
+        // Voxel indices denote the center of the voxels.
+        for corner in 0..8 {
+            let mut p = [0.0; 3];
+            for (i, v) in p.iter_mut().enumerate() {
+                *v = if corner & (1 << i) == 0 {
+                    -0.5
+                } else {
+                    dimensions[i] as f64 - 0.5
+                };
+            }
+
+            for ((l, h), x) in low
+                .iter_mut()
+                .zip(high.iter_mut())
+                .zip(&self.to_world.apply(&p))
+            {
+                *l = l.min(*x);
+                *h = h.max(*x);
+            }
+        }
+
+        (low, high)
+    }
+
+    /// Volume covered by the non-zero voxels.
+    pub fn volume(&self) -> f64 {
+        self.volume.non_zero().count() as f64 * self.to_world.determinant().abs()
+    }
+}
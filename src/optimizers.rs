@@ -0,0 +1,364 @@
+use super::error::Span;
+use super::expressions::*;
+use super::symbols::*;
+
+// Rewrite rules applied to the expressions, bottom-up. Each rule
+// produces an equivalent expression, which is cheaper to execute:
+//  * distinct(distinct(x)) is reduced to a single operator,
+//  * intersection(x, x) is x, distinct(union(x, x)) is distinct(x),
+//  * nested bag{} are flattened,
+//  * filter(p, filter(q, x)) is filter(&(q, p), x), filters without
+//    predicate or with an always true predicate are removed,
+//  * filters are pushed down through union, bag{} and distinct, closer
+//    to the spatial operators,
+//  * intersections of axis-aligned hyperrectangles are merged, as well
+//    as the distinct union of nested ones,
+//  * double negations are removed, and De Morgan's laws are applied when
+//    they reduce the number of negations,
+//  * nested limit() keep the smallest one, nested offset() are added.
+//
+// complement(complement(x)) is kept: the complement selects all the
+// objects at positions not selected by its operand, so twice is every
+// object sharing a position with x, in any space, not x itself.
+//
+// Predicates are not pushed down into inside(): they compare positions
+// strictly, or for equality, which shapes, closed, cannot express.
+//
+// Rewritten expressions keep the span of the expression they replace.
+
+impl Optimizer for Projection {
+    fn optimize(self) -> Self {
+        match self {
+            Projection::Nifti(space_id, selector, bag, span) => {
+                Projection::Nifti(space_id, selector, bag.optimize(), span)
+            }
+            Projection::Json(space_id, format, bag, span) => {
+                Projection::Json(space_id, format, bag.optimize(), span)
+            }
+        }
+    }
+}
+
+impl Optimizer for Bag {
+    fn optimize(self) -> Self {
+        match self {
+            Bag::Distinct(bag, span) => distinct(bag.optimize(), span),
+            Bag::Filter(predicate, bag, span) => {
+                filter(predicate.map(Optimizer::optimize), bag.optimize(), span)
+            }
+            Bag::Complement(bag, span) => Bag::Complement(bag.optimize(), span),
+            Bag::Intersection(lh, rh, span) => intersection(lh.optimize(), rh.optimize(), span),
+            Bag::Union(lh, rh, span) => Bag::Union(lh.optimize(), rh.optimize(), span),
+            Bag::Bag(bags, span) => bag(bags.into_iter().map(Optimizer::optimize).collect(), span),
+            Bag::Limit(n, bag, span) => limit(n, bag.optimize(), span),
+            Bag::Offset(n, bag, span) => offset(n, bag.optimize(), span),
+            Bag::Inside(_, _) | Bag::Outside(_, _) | Bag::Shape(_, _) => self,
+        }
+    }
+}
+
+impl Optimizer for Box<Bag> {
+    fn optimize(self) -> Self {
+        Box::new((*self).optimize())
+    }
+}
+
+impl Optimizer for Predicate {
+    fn optimize(self) -> Self {
+        match self {
+            Predicate::Not(predicate) => not(predicate.optimize()),
+            Predicate::And(lh, rh) => and(lh.optimize(), rh.optimize()),
+            Predicate::Or(lh, rh) => or(lh.optimize(), rh.optimize()),
+            Predicate::Less(_, _) | Predicate::Greater(_, _) | Predicate::Equal(_, _) => self,
+        }
+    }
+}
+
+impl Optimizer for Box<Predicate> {
+    fn optimize(self) -> Self {
+        Box::new((*self).optimize())
+    }
+}
+
+/**********************************************************************/
+/* BAG OPERATORS                                                      */
+/**********************************************************************/
+// The following functions expect their arguments to be optimized
+// already.
+
+fn distinct(bag: Box<Bag>, span: Span) -> Bag {
+    match *bag {
+        bag @ Bag::Distinct(_, _) => bag,
+        Bag::Union(lh, rh, union_span) => {
+            // Duplicates are removed, so the union with a subset of a bag
+            // is the bag itself.
+            if lh.same_as(&rh) {
+                distinct(lh, span)
+            } else if let Some(shape) = covering(&lh, &rh) {
+                Bag::Distinct(Box::new(Bag::Inside(shape, union_span)), span)
+            } else {
+                Bag::Distinct(Box::new(Bag::Union(lh, rh, union_span)), span)
+            }
+        }
+        bag => Bag::Distinct(Box::new(bag), span),
+    }
+}
+
+fn filter(predicate: Option<Predicate>, bag: Box<Bag>, span: Span) -> Bag {
+    let predicate = match predicate {
+        None => return *bag,
+        Some(predicate) if predicate.constant() == Some(true) => return *bag,
+        Some(predicate) => predicate,
+    };
+
+    match *bag {
+        Bag::Filter(None, bag, _) => filter(Some(predicate), bag, span),
+        // Keep the order of evaluation of the predicates.
+        Bag::Filter(Some(inner), bag, _) => {
+            filter(Some(and(Box::new(inner), Box::new(predicate))), bag, span)
+        }
+        Bag::Distinct(bag, distinct_span) => {
+            let filtered = filter(Some(predicate), bag, span);
+            Bag::Distinct(Box::new(filtered), distinct_span)
+        }
+        Bag::Union(lh, rh, union_span) => Bag::Union(
+            Box::new(filter(Some(predicate.clone()), lh, span)),
+            Box::new(filter(Some(predicate), rh, span)),
+            union_span,
+        ),
+        Bag::Bag(bags, bag_span) => Bag::Bag(
+            bags.into_iter()
+                .map(|b| filter(Some(predicate.clone()), Box::new(b), span))
+                .collect(),
+            bag_span,
+        ),
+        bag => Bag::Filter(Some(predicate), Box::new(bag), span),
+    }
+}
+
+fn limit(n: usize, bag: Box<Bag>, span: Span) -> Bag {
+    match *bag {
+        Bag::Limit(m, bag, _) => Bag::Limit(n.min(m), bag, span),
+        bag => Bag::Limit(n, Box::new(bag), span),
+    }
+}
+
+fn offset(n: usize, bag: Box<Bag>, span: Span) -> Bag {
+    match *bag {
+        Bag::Offset(m, bag, _) => Bag::Offset(n.saturating_add(m), bag, span),
+        bag => Bag::Offset(n, Box::new(bag), span),
+    }
+}
+
+fn intersection(lh: Box<Bag>, rh: Box<Bag>, span: Span) -> Bag {
+    if lh.same_as(&rh) {
+        return *lh;
+    }
+
+    if let (Bag::Inside(a, _), Bag::Inside(b, _)) = (&*lh, &*rh) {
+        if let Some(shape) = overlap(a, b) {
+            return Bag::Inside(shape, span);
+        }
+    }
+
+    Bag::Intersection(lh, rh, span)
+}
+
+fn bag(bags: Vec<Bag>, span: Span) -> Bag {
+    let mut flattened = vec![];
+    for bag in bags {
+        match bag {
+            Bag::Bag(mut inner, _) => flattened.append(&mut inner),
+            bag => flattened.push(bag),
+        }
+    }
+
+    Bag::Bag(flattened, span)
+}
+
+/**********************************************************************/
+/* SHAPES                                                             */
+/**********************************************************************/
+// Lowest and highest coordinates, per dimension, of an axis-aligned
+// hyperrectangle.
+type Extent<'s> = Vec<(&'s LiteralNumber, &'s LiteralNumber)>;
+
+fn extent(shape: &Shape) -> Option<(&String, Extent)> {
+    match shape {
+        Shape::HyperRectangle(space_id, vertices) if vertices.len() == 2 => {
+            let LiteralPosition(a) = &vertices[0];
+            let LiteralPosition(b) = &vertices[1];
+            if a.len() != b.len() {
+                return None;
+            }
+
+            let extent = a
+                .iter()
+                .zip(b)
+                .map(|(a, b)| (lowest(a, b), highest(a, b)))
+                .collect();
+
+            Some((space_id, extent))
+        }
+        _ => None,
+    }
+}
+
+fn lowest<'n>(a: &'n LiteralNumber, b: &'n LiteralNumber) -> &'n LiteralNumber {
+    if f64::from(b) < f64::from(a) {
+        b
+    } else {
+        a
+    }
+}
+
+fn highest<'n>(a: &'n LiteralNumber, b: &'n LiteralNumber) -> &'n LiteralNumber {
+    if f64::from(b) > f64::from(a) {
+        b
+    } else {
+        a
+    }
+}
+
+fn hyperrectangle(space_id: &str, extent: &Extent) -> Shape {
+    let low = extent.iter().map(|(l, _)| (*l).clone()).collect();
+    let high = extent.iter().map(|(_, h)| (*h).clone()).collect();
+
+    Shape::HyperRectangle(
+        space_id.to_string(),
+        vec![LiteralPosition(low), LiteralPosition(high)],
+    )
+}
+
+// Extents of `a` and `b`, when both are axis-aligned hyperrectangles of
+// the same space.
+fn extents<'s>(a: &'s Shape, b: &'s Shape) -> Option<(&'s String, Extent<'s>, Extent<'s>)> {
+    let (space_a, a) = extent(a)?;
+    let (space_b, b) = extent(b)?;
+
+    if space_a == space_b && a.len() == b.len() {
+        Some((space_a, a, b))
+    } else {
+        None
+    }
+}
+
+// Hyperrectangle common to `a` and `b`. There is none when they do not
+// overlap, as empty shapes cannot be expressed.
+fn overlap(a: &Shape, b: &Shape) -> Option<Shape> {
+    let (space_id, a, b) = extents(a, b)?;
+
+    let mut common = vec![];
+    for ((al, ah), (bl, bh)) in a.into_iter().zip(b) {
+        let low = highest(al, bl);
+        let high = lowest(ah, bh);
+        if f64::from(low) > f64::from(high) {
+            return None;
+        }
+        common.push((low, high));
+    }
+
+    Some(hyperrectangle(space_id, &common))
+}
+
+// Shape of the bag among `a` and `b` which contains the other one, when
+// both select the objects inside axis-aligned hyperrectangles.
+fn covering(a: &Bag, b: &Bag) -> Option<Shape> {
+    let (a, b) = match (a, b) {
+        (Bag::Inside(a, _), Bag::Inside(b, _)) => (a, b),
+        _ => return None,
+    };
+    let (_, extent_a, extent_b) = extents(a, b)?;
+
+    let contains = |outer: &Extent, inner: &Extent| {
+        outer.iter().zip(inner).all(|((ol, oh), (il, ih))| {
+            f64::from(*ol) <= f64::from(*il) && f64::from(*ih) <= f64::from(*oh)
+        })
+    };
+
+    if contains(&extent_a, &extent_b) {
+        Some(a.clone())
+    } else if contains(&extent_b, &extent_a) {
+        Some(b.clone())
+    } else {
+        None
+    }
+}
+
+/**********************************************************************/
+/* PREDICATES                                                         */
+/**********************************************************************/
+impl Predicate {
+    // Value of the predicate, when it does not depend on the objects.
+    fn constant(&self) -> Option<bool> {
+        match self {
+            Predicate::Less(Position::Literal(position), literal) => Some(position < literal),
+            Predicate::Greater(Position::Literal(position), literal) => Some(position > literal),
+            Predicate::Equal(Position::Literal(position), literal) => Some(position == literal),
+            Predicate::Less(_, _) | Predicate::Greater(_, _) | Predicate::Equal(_, _) => None,
+            Predicate::Not(predicate) => predicate.constant().map(|c| !c),
+            Predicate::And(lh, rh) => match (lh.constant(), rh.constant()) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            },
+            Predicate::Or(lh, rh) => match (lh.constant(), rh.constant()) {
+                (Some(true), _) | (_, Some(true)) => Some(true),
+                (Some(false), Some(false)) => Some(false),
+                _ => None,
+            },
+        }
+    }
+}
+
+fn not(predicate: Box<Predicate>) -> Predicate {
+    match *predicate {
+        Predicate::Not(predicate) => *predicate,
+        // De Morgan: !(&(!a, !b)) is |(a, b), and !(|(!a, !b)) is &(a, b).
+        Predicate::And(lh, rh) => match (*lh, *rh) {
+            (Predicate::Not(a), Predicate::Not(b)) => Predicate::Or(a, b),
+            (lh, rh) => Predicate::Not(Box::new(Predicate::And(Box::new(lh), Box::new(rh)))),
+        },
+        Predicate::Or(lh, rh) => match (*lh, *rh) {
+            (Predicate::Not(a), Predicate::Not(b)) => Predicate::And(a, b),
+            (lh, rh) => Predicate::Not(Box::new(Predicate::Or(Box::new(lh), Box::new(rh)))),
+        },
+        predicate => Predicate::Not(Box::new(predicate)),
+    }
+}
+
+fn and(lh: Box<Predicate>, rh: Box<Predicate>) -> Predicate {
+    match (lh.constant(), rh.constant()) {
+        (Some(true), _) | (_, Some(false)) => return *rh,
+        (_, Some(true)) | (Some(false), _) => return *lh,
+        _ => (),
+    }
+
+    if lh == rh {
+        return *lh;
+    }
+
+    match (*lh, *rh) {
+        // De Morgan: &(!a, !b) is !(|(a, b)).
+        (Predicate::Not(a), Predicate::Not(b)) => Predicate::Not(Box::new(Predicate::Or(a, b))),
+        (lh, rh) => Predicate::And(Box::new(lh), Box::new(rh)),
+    }
+}
+
+fn or(lh: Box<Predicate>, rh: Box<Predicate>) -> Predicate {
+    match (lh.constant(), rh.constant()) {
+        (Some(false), _) | (_, Some(true)) => return *rh,
+        (_, Some(false)) | (Some(true), _) => return *lh,
+        _ => (),
+    }
+
+    if lh == rh {
+        return *lh;
+    }
+
+    match (*lh, *rh) {
+        // De Morgan: |(!a, !b) is !(&(a, b)).
+        (Predicate::Not(a), Predicate::Not(b)) => Predicate::Not(Box::new(Predicate::And(a, b))),
+        (lh, rh) => Predicate::Or(Box::new(lh), Box::new(rh)),
+    }
+}
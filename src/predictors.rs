@@ -1,48 +1,339 @@
-use mercator_db::DataBase;
+use mercator_db::CoreQueryParameters;
 
+use super::catalogs::Catalog;
+use super::expressions::Estimator;
 use super::expressions::Predictor;
 use super::symbols::*;
 
+/// Predicted volume of an expression, as the range of its possible
+/// values.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Prediction {
+    /// Lowest possible volume.
+    pub min: f64,
+    /// Highest possible volume.
+    pub max: f64,
+}
+
+impl Prediction {
+    /// Volume between `min` and `max`, which are swapped if needed.
+    pub fn new(min: f64, max: f64) -> Self {
+        Prediction {
+            min: min.min(max),
+            max: max.max(min),
+        }
+    }
+
+    /// Volume known to be exactly `value`.
+    pub fn exact(value: f64) -> Self {
+        Prediction {
+            min: value,
+            max: value,
+        }
+    }
+
+    /// Middle of the range, used to compare predictions.
+    pub fn value(&self) -> f64 {
+        (self.min + self.max) / 2.0
+    }
+
+    // Restrict the range to volumes between 0 and `limit`.
+    fn clamp(self, limit: f64) -> Self {
+        Prediction {
+            min: self.min.max(0.0).min(limit),
+            max: self.max.max(0.0).min(limit),
+        }
+    }
+}
+
 impl Predictor for Projection {
-    fn predict(&self, db: &DataBase) -> Result<f64, String> {
+    fn predict_with(
+        &self,
+        core_id: &str,
+        parameters: &CoreQueryParameters,
+        catalog: &Catalog,
+    ) -> Result<Prediction, String> {
         match self {
-            Projection::Nifti(_, _, bag) => bag.predict(db),
-            Projection::Json(_, _, bag) => bag.predict(db),
+            Projection::Nifti(_, _, bag, _) => bag.predict_with(core_id, parameters, catalog),
+            Projection::Json(_, _, bag, _) => bag.predict_with(core_id, parameters, catalog),
         }
     }
 }
 
 impl Predictor for Bag {
-    fn predict(&self, db: &DataBase) -> Result<f64, String> {
+    fn predict_with(
+        &self,
+        core_id: &str,
+        parameters: &CoreQueryParameters,
+        catalog: &Catalog,
+    ) -> Result<Prediction, String> {
+        let mut operands = vec![];
+        for bag in self.operands() {
+            operands.push(bag.predict_with(core_id, parameters, catalog)?);
+        }
+
+        self.predict_from(&operands, core_id, parameters, catalog)
+    }
+}
+
+impl Bag {
+    /// Range of the volume selected by the bag, from the ranges of the
+    /// volumes of its operands, given in the order of `Bag::operands`.
+    pub fn predict_from(
+        &self,
+        operands: &[Prediction],
+        core_id: &str,
+        parameters: &CoreQueryParameters,
+        catalog: &Catalog,
+    ) -> Result<Prediction, String> {
+        let db = parameters.db;
+        if operands.len() != self.operands().len() {
+            return Err(format!(
+                "Expected {} predictions of operands, got {}",
+                self.operands().len(),
+                operands.len()
+            ));
+        }
+
         match self {
-            Bag::Distinct(bag) => bag.predict(db),
-            Bag::Filter(_, bag) => bag.predict(db),
-            Bag::Complement(bag) => Ok(db.space(bag.space())?.volume() - bag.predict(db)?),
-            Bag::Intersection(lh, rh) => {
-                let l = lh.predict(db)?;
-                let r = rh.predict(db)?;
-                if l < r {
-                    Ok(l)
-                } else {
-                    Ok(r)
-                }
+            Bag::Distinct(_, _) | Bag::Filter(None, _, _) => Ok(operands[0]),
+            // The predicate, or the paging, may exclude any of the
+            // objects.
+            Bag::Filter(Some(_), _, _) | Bag::Limit(_, _, _) | Bag::Offset(_, _, _) => {
+                Ok(Prediction::new(0.0, operands[0].max))
+            }
+            Bag::Complement(bag, _) => {
+                let volume = db.space(bag.space())?.volume();
+                let p = operands[0];
+                Ok(Prediction::new(volume - p.max, volume - p.min).clamp(volume))
+            }
+            Bag::Intersection(lh, rh, _) => {
+                let limit = space_volume(self.space(), parameters);
+                Ok(overlap(lh, rh, operands[0], operands[1], limit))
+            }
+            Bag::Union(_, _, _) | Bag::Bag(_, _) => Ok(union(
+                &self.operands(),
+                operands,
+                space_volume(self.space(), parameters),
+            )),
+            Bag::Inside(shape, _) | Bag::Shape(shape, _) => Ok(shape
+                .predict_with(core_id, parameters, catalog)?
+                .clamp(space_volume(shape.space(), parameters))),
+            Bag::Outside(shape, _) => {
+                let volume = db.space(shape.space())?.volume();
+                let p = shape.predict_with(core_id, parameters, catalog)?;
+                Ok(Prediction::new(volume - p.max, volume - p.min).clamp(volume))
+            }
+        }
+    }
+}
+
+// Volume of the space, or no limit when the space is unknown, as for
+// the universe.
+fn space_volume(space_id: &str, parameters: &CoreQueryParameters) -> f64 {
+    match parameters.db.space(space_id) {
+        Ok(space) => space.volume(),
+        Err(_) => f64::INFINITY,
+    }
+}
+
+// Volume of the union of `bags`, whose volumes are predicted to be
+// `predictions`, within a space of volume `limit`, from the volumes of
+// the bags and of their pairwise overlaps:
+//  * at least the sum of the volumes, minus all the pairwise overlaps,
+//  * at most the sum of the volumes, minus the overlap of each bag with
+//    one of the previous ones.
+fn union(bags: &[&Bag], predictions: &[Prediction], limit: f64) -> Prediction {
+    let mut largest = 0.0_f64;
+    let mut min = 0.0;
+    let mut max = 0.0;
+    for (i, (bag, p)) in bags.iter().zip(predictions).enumerate() {
+        largest = largest.max(p.min);
+        min += p.min;
+        max += p.max;
+
+        let mut shared = 0.0_f64;
+        for (other, q) in bags.iter().zip(predictions).take(i) {
+            let o = overlap(bag, other, *p, *q, limit);
+            min -= o.max;
+            shared = shared.max(o.min);
+        }
+        max -= shared;
+    }
+
+    // The union is at least as large as any of the bags.
+    Prediction::new(min.max(largest), max).clamp(limit)
+}
+
+// Volume common to `lh` and `rh`, whose volumes are predicted to be `l`
+// and `r`, within a space of volume `limit`.
+fn overlap(lh: &Bag, rh: &Bag, l: Prediction, r: Prediction, limit: f64) -> Prediction {
+    if lh.same_as(rh) {
+        return l;
+    }
+    if let (Some(a), Some(b)) = (region(lh), region(rh)) {
+        if let Some(p) = a.overlap(b) {
+            return p.clamp(l.max.min(r.max));
+        }
+    }
+
+    // Without knowing the shapes, both bags can be nested, or as far
+    // apart as the space allows.
+    Prediction::new(l.min + r.min - limit, l.max.min(r.max)).clamp(limit)
+}
+
+// Shape selected by a bag, when it is known.
+fn region(bag: &Bag) -> Option<&Shape> {
+    match bag {
+        Bag::Inside(shape, _) | Bag::Shape(shape, _) => Some(shape),
+        _ => None,
+    }
+}
+
+// Volume of the box bounded by `low` and `high`, or 0 when empty.
+fn box_volume(low: &[f64], high: &[f64]) -> f64 {
+    low.iter()
+        .zip(high)
+        .map(|(l, h)| (h - l).max(0.0))
+        .product()
+}
+
+// Box common to two boxes.
+fn box_overlap(a: &(Vec<f64>, Vec<f64>), b: &(Vec<f64>, Vec<f64>)) -> (Vec<f64>, Vec<f64>) {
+    let low = a.0.iter().zip(&b.0).map(|(x, y)| x.max(*y)).collect();
+    let high = a.1.iter().zip(&b.1).map(|(x, y)| x.min(*y)).collect();
+
+    (low, high)
+}
+
+impl Shape {
+    // Lowest and highest coordinates of the shape, when it is an
+    // axis-aligned box.
+    fn aligned_box(&self) -> Option<(Vec<f64>, Vec<f64>)> {
+        match self {
+            Shape::HyperRectangle(_, vertices) if vertices.len() == 2 => {
+                let a: Vec<f64> = (&vertices[0]).into();
+                let b: Vec<f64> = (&vertices[1]).into();
+                let low = a.iter().zip(&b).map(|(a, b)| a.min(*b)).collect();
+                let high = a.iter().zip(&b).map(|(a, b)| a.max(*b)).collect();
+
+                Some((low, high))
             }
-            Bag::Union(lh, rh) => Ok(lh.predict(db)? + rh.predict(db)?),
-            Bag::Bag(bags) => {
-                let mut s = 0.0;
-                for bag in bags {
-                    s += bag.predict(db)?;
+            _ => None,
+        }
+    }
+
+    // Volume common to both shapes, for the pairs of shapes for which it
+    // can be computed or bounded.
+    fn overlap(&self, other: &Shape) -> Option<Prediction> {
+        if self.space() != other.space() {
+            return None;
+        }
+        match (self, other) {
+            (Shape::HyperRectangle(_, _), Shape::HyperRectangle(_, _)) => {
+                let a = self.aligned_box()?;
+                let b = other.aligned_box()?;
+                if a.0.len() != b.0.len() {
+                    return None;
                 }
-                Ok(s)
+
+                let (low, high) = box_overlap(&a, &b);
+                Some(Prediction::exact(box_volume(&low, &high)))
+            }
+            (Shape::HyperSphere(_, center, radius), Shape::HyperRectangle(_, _)) => {
+                sphere_box(self.volume().ok()?, center, radius, &other.aligned_box()?)
+            }
+            (Shape::HyperRectangle(_, _), Shape::HyperSphere(_, center, radius)) => {
+                sphere_box(other.volume().ok()?, center, radius, &self.aligned_box()?)
             }
-            Bag::Inside(shape) => shape.predict(db),
-            Bag::Outside(shape) => Ok(db.space(shape.space())?.volume() - shape.predict(db)?),
+            _ => None,
         }
     }
 }
 
+// Volume common to a sphere and an axis-aligned box, bounded by the part
+// of the bounding box of the sphere within the box. Exact when the box
+// contains the sphere, or does not reach it.
+fn sphere_box(
+    volume: f64,
+    center: &LiteralPosition,
+    radius: &LiteralNumber,
+    aligned: &(Vec<f64>, Vec<f64>),
+) -> Option<Prediction> {
+    let center: Vec<f64> = center.into();
+    let radius: f64 = radius.into();
+    if center.len() != aligned.0.len() {
+        return None;
+    }
+
+    let bounds = (
+        center.iter().map(|c| c - radius).collect::<Vec<_>>(),
+        center.iter().map(|c| c + radius).collect::<Vec<_>>(),
+    );
+    let (low, high) = box_overlap(&bounds, aligned);
+    let within = box_volume(&low, &high);
+    let outside = box_volume(&bounds.0, &bounds.1) - within;
+
+    // The sphere fills its bounding box, except at most `outside`.
+    Some(Prediction::new(volume - outside, volume.min(within)).clamp(volume))
+}
+
+// Intersections hash their right operand, when it is predicted to be
+// smaller than the left one.
+pub fn right_smaller(
+    lh: &Bag,
+    rh: &Bag,
+    core_id: &str,
+    parameters: &CoreQueryParameters,
+    catalog: &Catalog,
+) -> bool {
+    right_smaller_from(
+        (
+            lh.predict_with(core_id, parameters, catalog).ok(),
+            lh.estimate(catalog).ok(),
+        ),
+        (
+            rh.predict_with(core_id, parameters, catalog).ok(),
+            rh.estimate(catalog).ok(),
+        ),
+    )
+}
+
+// Whether the right operand is the smaller one, from the predicted
+// volume and the estimated number of objects of both operands. The
+// numbers of objects are compared when the catalog has histograms,
+// otherwise the volumes are. Operands which cannot be predicted are the
+// largest.
+pub fn right_smaller_from(
+    (l, l_estimate): (Option<Prediction>, Option<f64>),
+    (r, r_estimate): (Option<Prediction>, Option<f64>),
+) -> bool {
+    if let (Some(l), Some(r)) = (l_estimate, r_estimate) {
+        return r < l;
+    }
+
+    match (l, r) {
+        (Some(l), Some(r)) => r.value() < l.value(),
+        (None, Some(_)) => true,
+        _ => false,
+    }
+}
+
 impl Predictor for Shape {
-    fn predict(&self, _db: &DataBase) -> Result<f64, String> {
-        Ok(self.volume())
+    fn predict_with(
+        &self,
+        _core_id: &str,
+        parameters: &CoreQueryParameters,
+        catalog: &Catalog,
+    ) -> Result<Prediction, String> {
+        match self {
+            // The extent of labels is only known from the database, so
+            // it may be anything when the catalog does not know it.
+            Shape::Label(space_id, id) => Ok(match catalog.label(id) {
+                Some(extent) => Prediction::exact(extent.volume()),
+                None => Prediction::new(0.0, space_volume(space_id, parameters)),
+            }),
+            _ => Ok(Prediction::exact(self.volume()?)),
+        }
     }
 }
@@ -0,0 +1,467 @@
+use std::fmt;
+use std::fmt::Display;
+use std::fmt::Formatter;
+use std::fmt::Write;
+
+use mercator_db::space::Space;
+
+use super::executors::Statistics;
+use super::explainers::Plan;
+use super::predictors::Prediction;
+use super::symbols::*;
+
+// Text of the expressions in canonical form:
+//  * Optional arguments are omitted when they have their default value,
+//    such as the universe for the reference spaces,
+//  * Elements of lists are separated by a comma and a space,
+//  * Floating point numbers are printed with the shortest representation
+//    which parses back to the same value. They are always finite, as the
+//    parser and the builders reject infinities and NaN, which the queries
+//    cannot express.
+//
+// The alternate form, `{:#}`, puts each sub-expression of bag operators
+// on its own, indented, line.
+
+const INDENT: &str = "    ";
+
+fn string(f: &mut Formatter, s: &str) -> fmt::Result {
+    f.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            '\u{8}' => f.write_str("\\b")?,
+            '\u{c}' => f.write_str("\\f")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => f.write_char(c)?,
+        }
+    }
+    f.write_char('"')
+}
+
+// Reference spaces are optional, and default to the universe.
+fn space(f: &mut Formatter, space_id: &str) -> fmt::Result {
+    if space_id != Space::universe().name() {
+        f.write_str(", ")?;
+        string(f, space_id)?;
+    }
+
+    Ok(())
+}
+
+fn list<T: Display>(f: &mut Formatter, items: &[T]) -> fmt::Result {
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            f.write_str(", ")?;
+        }
+        write!(f, "{}", item)?;
+    }
+
+    Ok(())
+}
+
+// Arguments of bag operators.
+enum Argument<'a> {
+    Bag(&'a Bag),
+    Predicate(&'a Predicate),
+    Natural(usize),
+}
+
+// Write `name(arguments)`, with `open` and `close` as delimiters.
+// Arguments are on their own lines in the alternate form, at `depth`.
+fn operator(
+    f: &mut Formatter,
+    depth: usize,
+    name: &str,
+    (open, close): (char, char),
+    arguments: &[Argument],
+) -> fmt::Result {
+    write!(f, "{}{}", name, open)?;
+
+    for (i, argument) in arguments.iter().enumerate() {
+        if f.alternate() {
+            if i > 0 {
+                f.write_char(',')?;
+            }
+            write!(f, "\n{}", INDENT.repeat(depth + 1))?;
+        } else if i > 0 {
+            f.write_str(", ")?;
+        }
+
+        match argument {
+            Argument::Bag(bag) => bag.write(f, depth + 1)?,
+            Argument::Predicate(predicate) => write!(f, "{}", predicate)?,
+            Argument::Natural(n) => write!(f, "{}", n)?,
+        }
+    }
+
+    if f.alternate() {
+        write!(f, "\n{}", INDENT.repeat(depth))?;
+    }
+
+    f.write_char(close)
+}
+
+impl Bag {
+    fn write(&self, f: &mut Formatter, depth: usize) -> fmt::Result {
+        let parentheses = ('(', ')');
+
+        match self {
+            Bag::Distinct(bag, _) => {
+                operator(f, depth, "distinct", parentheses, &[Argument::Bag(bag)])
+            }
+            Bag::Filter(None, bag, _) => {
+                operator(f, depth, "filter", parentheses, &[Argument::Bag(bag)])
+            }
+            Bag::Filter(Some(predicate), bag, _) => operator(
+                f,
+                depth,
+                "filter",
+                parentheses,
+                &[Argument::Predicate(predicate), Argument::Bag(bag)],
+            ),
+            Bag::Complement(bag, _) => {
+                operator(f, depth, "complement", parentheses, &[Argument::Bag(bag)])
+            }
+            Bag::Intersection(lh, rh, _) => operator(
+                f,
+                depth,
+                "intersection",
+                parentheses,
+                &[Argument::Bag(lh), Argument::Bag(rh)],
+            ),
+            Bag::Union(lh, rh, _) => operator(
+                f,
+                depth,
+                "union",
+                parentheses,
+                &[Argument::Bag(lh), Argument::Bag(rh)],
+            ),
+            Bag::Bag(bags, _) => {
+                let arguments = bags.iter().map(Argument::Bag).collect::<Vec<_>>();
+                operator(f, depth, "bag", ('{', '}'), &arguments)
+            }
+            Bag::Limit(n, bag, _) => operator(
+                f,
+                depth,
+                "limit",
+                parentheses,
+                &[Argument::Natural(*n), Argument::Bag(bag)],
+            ),
+            Bag::Offset(n, bag, _) => operator(
+                f,
+                depth,
+                "offset",
+                parentheses,
+                &[Argument::Natural(*n), Argument::Bag(bag)],
+            ),
+            // Shapes are always printed on one line.
+            Bag::Inside(shape, _) => write!(f, "inside({})", shape),
+            Bag::Outside(shape, _) => write!(f, "outside({})", shape),
+            Bag::Shape(shape, _) => write!(f, "shape({})", shape),
+        }
+    }
+}
+
+impl Display for Bag {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+impl Display for Projection {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let (name, argument, bag, space_id) = match self {
+            Projection::Nifti(space_id, selector, bag, _) => {
+                let LiteralSelector(fields) = selector;
+                let argument = if fields.is_empty() {
+                    None
+                } else {
+                    Some(selector.to_string())
+                };
+                ("nifti", argument, bag, space_id)
+            }
+            Projection::Json(space_id, format, bag, _) => {
+                ("json", Some(format.to_string()), bag, space_id)
+            }
+        };
+
+        write!(f, "{}(", name)?;
+        if let Some(argument) = argument {
+            write!(f, "{},", argument)?;
+            if !f.alternate() {
+                f.write_char(' ')?;
+            }
+        }
+
+        if f.alternate() {
+            write!(f, "\n{}", INDENT)?;
+            bag.write(f, 1)?;
+            space(f, space_id)?;
+            f.write_str("\n)")
+        } else {
+            bag.write(f, 0)?;
+            space(f, space_id)?;
+            f.write_char(')')
+        }
+    }
+}
+
+// A single volume when it is known exactly, otherwise its range.
+impl Display for Prediction {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.min == self.max {
+            write!(f, "{:.3e}", self.min)
+        } else {
+            write!(f, "{:.3e}..{:.3e}", self.min, self.max)
+        }
+    }
+}
+
+impl Plan {
+    fn write(&self, f: &mut Formatter, depth: usize) -> fmt::Result {
+        let indent = INDENT.repeat(depth);
+        write!(
+            f,
+            "{}{} [predicted: {}",
+            indent, self.operation, self.prediction
+        )?;
+        if let Some(estimate) = self.estimate {
+            write!(f, ", estimated: {:.0} objects", estimate)?;
+        }
+        f.write_char(']')?;
+        if let Some(strategy) = &self.strategy {
+            write!(f, " [{}]", strategy)?;
+        }
+
+        for call in &self.calls {
+            write!(f, "\n{}{}-> {}", indent, INDENT, call)?;
+        }
+
+        for operand in &self.operands {
+            f.write_char('\n')?;
+            operand.write(f, depth + 1)?;
+        }
+
+        Ok(())
+    }
+}
+
+// One operation per line, followed by the database calls it issues,
+// then its operands, indented.
+impl Display for Plan {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+impl Statistics {
+    fn write(&self, f: &mut Formatter, depth: usize) -> fmt::Result {
+        write!(
+            f,
+            "{}{} [time: {:?}, objects: {}",
+            INDENT.repeat(depth),
+            self.operation,
+            self.time,
+            self.count()
+        )?;
+        if !self.objects.is_empty() {
+            f.write_str(" (")?;
+            for (i, (space_id, count)) in self.objects.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(", ")?;
+                }
+                string(f, space_id)?;
+                write!(f, ": {}", count)?;
+            }
+            f.write_char(')')?;
+        }
+        if self.lookups > 0 {
+            write!(f, ", lookups: {}", self.lookups)?;
+        }
+        f.write_char(']')?;
+
+        for operand in &self.operands {
+            f.write_char('\n')?;
+            operand.write(f, depth + 1)?;
+        }
+
+        Ok(())
+    }
+}
+
+// One operation per line, with its statistics, then its operands,
+// indented.
+impl Display for Statistics {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+impl Display for JsonValue {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            JsonValue::String(s) => string(f, s),
+            JsonValue::JsonNumber(n) => write!(f, "{}", n),
+            JsonValue::Bool(b) => write!(f, "{}", b),
+            JsonValue::Null => f.write_str("null"),
+            JsonValue::Object(pairs) => {
+                f.write_char('{')?;
+                for (i, (key, value)) in pairs.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    string(f, key)?;
+                    write!(f, ": {}", value)?;
+                }
+                f.write_char('}')
+            }
+            JsonValue::Array(values) => {
+                f.write_char('[')?;
+                list(f, values)?;
+                f.write_char(']')
+            }
+            JsonValue::Selector(selector) => write!(f, "{}", selector),
+            JsonValue::Aggregation(aggregation) => write!(f, "{}", aggregation),
+        }
+    }
+}
+
+impl Display for Aggregation {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Aggregation::Count(true, selector) => write!(f, "count(distinct {})", selector),
+            Aggregation::Count(false, selector) => write!(f, "count({})", selector),
+            Aggregation::Sum(selector) => write!(f, "sum({})", selector),
+            Aggregation::Min(selector) => write!(f, "min({})", selector),
+            Aggregation::Max(selector) => write!(f, "max({})", selector),
+        }
+    }
+}
+
+impl Display for Predicate {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Predicate::Less(position, literal) => write!(f, "<({}, {})", position, literal),
+            Predicate::Greater(position, literal) => write!(f, ">({}, {})", position, literal),
+            Predicate::Equal(position, literal) => write!(f, "=({}, {})", position, literal),
+            Predicate::Not(predicate) => write!(f, "!({})", predicate),
+            Predicate::And(lh, rh) => write!(f, "&({}, {})", lh, rh),
+            Predicate::Or(lh, rh) => write!(f, "|({}, {})", lh, rh),
+        }
+    }
+}
+
+impl Display for Shape {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Shape::Point(space_id, position) => {
+                write!(f, "point{{{}", position)?;
+                space(f, space_id)?;
+            }
+            Shape::HyperRectangle(space_id, positions) => {
+                f.write_str("hyperrectangle{")?;
+                list(f, positions)?;
+                space(f, space_id)?;
+            }
+            Shape::HyperSphere(space_id, center, radius) => {
+                write!(f, "hypersphere{{{}, {}", center, radius)?;
+                space(f, space_id)?;
+            }
+            Shape::Label(space_id, id) => {
+                f.write_str("label{")?;
+                string(f, id)?;
+                space(f, space_id)?;
+            }
+            Shape::Nifti(transform, provider) => {
+                f.write_str("nifti{")?;
+                if !transform.offset.is_empty() {
+                    f.write_char('[')?;
+                    list(f, &transform.offset)?;
+                    f.write_str("], ")?;
+                }
+                if !transform.rotation.is_empty() {
+                    f.write_char('[')?;
+                    for (i, row) in transform.rotation.iter().enumerate() {
+                        if i > 0 {
+                            f.write_str(", ")?;
+                        }
+                        f.write_char('[')?;
+                        list(f, row)?;
+                        f.write_char(']')?;
+                    }
+                    f.write_str("], ")?;
+                }
+                write!(f, "{}", provider)?;
+                space(f, &transform.reference)?;
+            }
+        }
+
+        f.write_char('}')
+    }
+}
+
+impl Display for ByteProvider {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let (name, s) = match self {
+            ByteProvider::Uri(s) => ("uri", s),
+            ByteProvider::Bytes(s) => ("bytes", s),
+        };
+
+        write!(f, "{}(", name)?;
+        string(f, s)?;
+        f.write_char(')')
+    }
+}
+
+impl Display for Position {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let (name, selector, s) = match self {
+            Position::StrCmp(selector, s) => ("str_cmp", selector, s),
+            Position::StrCmpIgnoreCase(selector, s) => ("str_cmp_ignore_case", selector, s),
+            Position::Selector(selector) => return write!(f, "{}", selector),
+            Position::Literal(literal) => return write!(f, "{}", literal),
+        };
+
+        write!(f, "{}({}, ", name, selector)?;
+        string(f, s)?;
+        f.write_char(')')
+    }
+}
+
+impl Display for LiteralSelector {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let LiteralSelector(fields) = self;
+        for Field(name, index) in fields {
+            write!(f, ".{}", name)?;
+            if let Some(index) = index {
+                write!(f, "[{}]", index)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Display for LiteralPosition {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let LiteralPosition(coordinates) = self;
+        f.write_char('[')?;
+        list(f, coordinates)?;
+        f.write_char(']')
+    }
+}
+
+impl Display for LiteralNumber {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            LiteralNumber::Int(x) => write!(f, "{}", x),
+            // The debug format always keeps a fractional part or an
+            // exponent, which distinguishes floats from integers.
+            LiteralNumber::Float(x) => write!(f, "{:?}", x),
+        }
+    }
+}
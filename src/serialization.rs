@@ -0,0 +1,84 @@
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Version of the encoding of the queries produced by this crate.
+///
+/// Increment it whenever the representation of the expressions changes,
+/// and keep reading the previous versions. Expressions are encoded with
+/// the tags given by their `serde(rename)` attributes, which must not
+/// change, and without their spans, which only make sense along with the
+/// text of the query.
+//
+// Versions:
+//  1. initial encoding,
+//  2. limit() and offset() added to the bags.
+pub const VERSION: u32 = 2;
+
+// Oldest version of the encoding which can still be read.
+const OLDEST_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct Envelope<'q, T> {
+    version: u32,
+    query: &'q T,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    version: u32,
+}
+
+fn check_version(version: u32) -> Result<(), String> {
+    if (OLDEST_VERSION..=VERSION).contains(&version) {
+        Ok(())
+    } else {
+        Err(format!(
+            "Unsupported encoding version {}, expected {} to {}",
+            version, OLDEST_VERSION, VERSION
+        ))
+    }
+}
+
+/// Encode the query as JSON, along with the version of the encoding.
+pub fn to_json<T: Serialize>(query: &T) -> Result<String, String> {
+    let envelope = Envelope {
+        version: VERSION,
+        query,
+    };
+
+    serde_json::to_string(&envelope).map_err(|e| e.to_string())
+}
+
+/// Decode a query encoded by `to_json`.
+pub fn from_json<T: DeserializeOwned>(json: &str) -> Result<T, String> {
+    let mut value: serde_json::Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+
+    // Check the version first, as the layout of the query depends on it.
+    let Header { version } = Header::deserialize(&value).map_err(|e| e.to_string())?;
+    check_version(version)?;
+
+    match value.get_mut("query") {
+        Some(query) => serde_json::from_value(query.take()).map_err(|e| e.to_string()),
+        None => Err("Missing field 'query'".to_string()),
+    }
+}
+
+/// Encode the query in a compact binary form, prefixed by the version
+/// of the encoding.
+pub fn to_bytes<T: Serialize>(query: &T) -> Result<Vec<u8>, String> {
+    let mut bytes = bincode::serialize(&VERSION).map_err(|e| e.to_string())?;
+    bincode::serialize_into(&mut bytes, query).map_err(|e| e.to_string())?;
+
+    Ok(bytes)
+}
+
+/// Decode a query encoded by `to_bytes`.
+pub fn from_bytes<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, String> {
+    let mut reader = bytes;
+
+    let version: u32 = bincode::deserialize_from(&mut reader).map_err(|e| e.to_string())?;
+    check_version(version)?;
+
+    bincode::deserialize(reader).map_err(|e| e.to_string())
+}
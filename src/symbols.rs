@@ -1,111 +1,392 @@
 use std::cmp::Ordering;
+use std::io::Read;
+use std::str::FromStr;
 
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use flate2::read::GzDecoder;
 use mercator_db::space;
 use mercator_db::Properties;
-
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use unicase::UniCase;
+
+use super::error::Span;
+use super::nifti::Affine;
+use super::nifti::Mask;
+use super::nifti::Volume;
 pub use super::types::*;
 
 /**********************************************************************/
 /* FORMATTING DATA                                                    */
 /**********************************************************************/
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Projection {
-    Nifti(String, LiteralSelector, Bag),
-    Json(String, JsonValue, Bag),
+    #[cfg_attr(feature = "serde", serde(rename = "nifti"))]
+    Nifti(
+        String,
+        LiteralSelector,
+        Bag,
+        #[cfg_attr(feature = "serde", serde(skip))] Span,
+    ),
+    #[cfg_attr(feature = "serde", serde(rename = "json"))]
+    Json(
+        String,
+        JsonValue,
+        Bag,
+        #[cfg_attr(feature = "serde", serde(skip))] Span,
+    ),
 }
 
 impl Projection {
     pub fn space(&self) -> &String {
         match self {
-            Projection::Nifti(space, _, _) => space,
-            Projection::Json(space, _, _) => space,
+            Projection::Nifti(space, _, _, _) => space,
+            Projection::Json(space, _, _, _) => space,
+        }
+    }
+
+    /// Location of the projection in the query.
+    pub fn span(&self) -> Span {
+        match self {
+            Projection::Nifti(_, _, _, span) => *span,
+            Projection::Json(_, _, _, span) => *span,
+        }
+    }
+
+    /// Whether both projections are the same expression, wherever they
+    /// were written.
+    pub fn same_as(&self, other: &Projection) -> bool {
+        match (self, other) {
+            (Projection::Nifti(a, s, x, _), Projection::Nifti(b, t, y, _)) => {
+                a == b && s == t && x.same_as(y)
+            }
+            (Projection::Json(a, f, x, _), Projection::Json(b, g, y, _)) => {
+                a == b && f == g && x.same_as(y)
+            }
+            _ => false,
         }
     }
 }
 
 // JSON FORMAT
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum JsonValue {
+    #[cfg_attr(feature = "serde", serde(rename = "string"))]
     String(String),
+    #[cfg_attr(feature = "serde", serde(rename = "number"))]
     JsonNumber(LiteralNumber),
+    #[cfg_attr(feature = "serde", serde(rename = "bool"))]
     Bool(bool),
+    #[cfg_attr(feature = "serde", serde(rename = "null"))]
     Null,
+    #[cfg_attr(feature = "serde", serde(rename = "object"))]
     Object(Vec<(String, JsonValue)>),
+    #[cfg_attr(feature = "serde", serde(rename = "array"))]
     Array(Vec<JsonValue>),
+    #[cfg_attr(feature = "serde", serde(rename = "selector"))]
     Selector(LiteralSelector),
+    #[cfg_attr(feature = "serde", serde(rename = "aggregation"))]
     Aggregation(Aggregation),
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Aggregation {
+    #[cfg_attr(feature = "serde", serde(rename = "count"))]
     Count(bool, LiteralSelector),
+    #[cfg_attr(feature = "serde", serde(rename = "sum"))]
     Sum(LiteralSelector),
+    #[cfg_attr(feature = "serde", serde(rename = "min"))]
     Min(LiteralSelector),
+    #[cfg_attr(feature = "serde", serde(rename = "max"))]
     Max(LiteralSelector),
 }
 
 // NIFTI
-#[derive(Clone, Debug)]
-struct Transform {
-    reference: String,
-    offset: Vec<LiteralNumber>,
-    rotation: Vec<Vec<LiteralNumber>>,
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Transform {
+    pub reference: String,
+    pub offset: Vec<LiteralNumber>,
+    pub rotation: Vec<Vec<LiteralNumber>>,
+}
+
+impl Transform {
+    // An empty offset is the origin, and an empty rotation is the
+    // identity.
+    pub fn affine(&self) -> Result<Affine, String> {
+        let mut m = [
+            [1.0, 0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+        ];
+
+        if !self.offset.is_empty() {
+            if self.offset.len() != 3 {
+                return Err(format!(
+                    "Transform: the offset must have 3 coordinates, got {}",
+                    self.offset.len()
+                ));
+            }
+
+            for (row, v) in m.iter_mut().zip(&self.offset) {
+                row[3] = v.into();
+            }
+        }
+
+        if !self.rotation.is_empty() {
+            if self.rotation.len() != 3 || self.rotation.iter().any(|row| row.len() != 3) {
+                return Err("Transform: the rotation must be a 3x3 matrix".to_string());
+            }
+
+            for (row, r) in m.iter_mut().zip(&self.rotation) {
+                for (v, x) in row.iter_mut().zip(r) {
+                    *v = x.into();
+                }
+            }
+        }
+
+        Ok(Affine::new(m))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ByteProvider {
+    #[cfg_attr(feature = "serde", serde(rename = "uri"))]
+    Uri(String),
+    // Base64 encoded, optionally gzip-compressed.
+    #[cfg_attr(feature = "serde", serde(rename = "bytes"))]
+    Bytes(String),
+}
+
+// Largest payload accepted, once decompressed, so that a small gzip
+// stream cannot exhaust the memory of the server.
+const MAX_PAYLOAD_SIZE: u64 = 1 << 30;
+
+impl ByteProvider {
+    pub fn bytes(&self) -> Result<Vec<u8>, String> {
+        let bytes = match self {
+            ByteProvider::Uri(uri) => match uri.strip_prefix("file://") {
+                Some(path) => {
+                    let file = std::fs::File::open(path)
+                        .map_err(|e| format!("Unable to read '{}': {}", uri, e))?;
+                    read_limited(file).map_err(|e| format!("Unable to read '{}': {}", uri, e))?
+                }
+                None => {
+                    return Err(format!(
+                        "Unsupported URI '{}', only 'file://' is supported.",
+                        uri
+                    ))
+                }
+            },
+            ByteProvider::Bytes(encoded) => decode(encoded)?,
+        };
+
+        // Transparently decompress gzip streams, as NIfTI files are
+        // commonly stored compressed.
+        if bytes.starts_with(&[0x1f, 0x8b]) {
+            read_limited(GzDecoder::new(bytes.as_slice()))
+                .map_err(|e| format!("Invalid gzip stream: {}", e))
+        } else {
+            Ok(bytes)
+        }
+    }
+
+    /// Check in-line payloads are valid base64, without decompressing
+    /// them.
+    pub fn check(&self) -> Result<(), String> {
+        match self {
+            ByteProvider::Uri(_) => Ok(()),
+            ByteProvider::Bytes(encoded) => decode(encoded).map(|_| ()),
+        }
+    }
+}
+
+fn decode(encoded: &str) -> Result<Vec<u8>, String> {
+    // Allow the payload to be split over multiple lines.
+    let encoded = encoded
+        .chars()
+        .filter(|c| !c.is_ascii_whitespace())
+        .collect::<String>();
+
+    STANDARD
+        .decode(encoded)
+        .map_err(|e| format!("Invalid base64 payload: {}", e))
+}
+
+// Read at most `MAX_PAYLOAD_SIZE` bytes, failing on larger payloads.
+fn read_limited<R: Read>(reader: R) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    reader
+        .take(MAX_PAYLOAD_SIZE + 1)
+        .read_to_end(&mut bytes)
+        .map_err(|e| e.to_string())?;
+
+    if bytes.len() as u64 > MAX_PAYLOAD_SIZE {
+        return Err(format!(
+            "the payload is larger than {} bytes",
+            MAX_PAYLOAD_SIZE
+        ));
+    }
+
+    Ok(bytes)
 }
 
 /**********************************************************************/
 /* SELECTING / FILTERING DATA                                         */
 /**********************************************************************/
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Bag {
     // Bags
-    Distinct(Box<Bag>),
-    Filter(Option<Predicate>, Box<Bag>),
-    Complement(Box<Bag>),
-    Intersection(Box<Bag>, Box<Bag>),
-    Union(Box<Bag>, Box<Bag>),
-    Bag(Vec<Bag>),
-    Inside(Shape),
-    Outside(Shape),
-    //FIXME: ADD A SHAPE VARIANT WHICH JUST RETURNS ALL THE POSITIONS OF THAT SHAPE
-    //Shape(Shape),
+    #[cfg_attr(feature = "serde", serde(rename = "distinct"))]
+    Distinct(Box<Bag>, #[cfg_attr(feature = "serde", serde(skip))] Span),
+    #[cfg_attr(feature = "serde", serde(rename = "filter"))]
+    Filter(
+        Option<Predicate>,
+        Box<Bag>,
+        #[cfg_attr(feature = "serde", serde(skip))] Span,
+    ),
+    #[cfg_attr(feature = "serde", serde(rename = "complement"))]
+    Complement(Box<Bag>, #[cfg_attr(feature = "serde", serde(skip))] Span),
+    #[cfg_attr(feature = "serde", serde(rename = "intersection"))]
+    Intersection(
+        Box<Bag>,
+        Box<Bag>,
+        #[cfg_attr(feature = "serde", serde(skip))] Span,
+    ),
+    #[cfg_attr(feature = "serde", serde(rename = "union"))]
+    Union(
+        Box<Bag>,
+        Box<Bag>,
+        #[cfg_attr(feature = "serde", serde(skip))] Span,
+    ),
+    #[cfg_attr(feature = "serde", serde(rename = "bag"))]
+    Bag(Vec<Bag>, #[cfg_attr(feature = "serde", serde(skip))] Span),
+    #[cfg_attr(feature = "serde", serde(rename = "inside"))]
+    Inside(Shape, #[cfg_attr(feature = "serde", serde(skip))] Span),
+    #[cfg_attr(feature = "serde", serde(rename = "outside"))]
+    Outside(Shape, #[cfg_attr(feature = "serde", serde(skip))] Span),
+    // All the positions of that shape, instead of the objects within it.
+    #[cfg_attr(feature = "serde", serde(rename = "shape"))]
+    Shape(Shape, #[cfg_attr(feature = "serde", serde(skip))] Span),
+    // Paging, in the order of the results of the bag.
+    #[cfg_attr(feature = "serde", serde(rename = "limit"))]
+    Limit(
+        usize,
+        Box<Bag>,
+        #[cfg_attr(feature = "serde", serde(skip))] Span,
+    ),
+    #[cfg_attr(feature = "serde", serde(rename = "offset"))]
+    Offset(
+        usize,
+        Box<Bag>,
+        #[cfg_attr(feature = "serde", serde(skip))] Span,
+    ),
 }
 
 impl Bag {
     pub fn space(&self) -> &String {
         match self {
-            Bag::Distinct(bag) => bag.space(),
-            Bag::Filter(_, bag) => bag.space(),
-            Bag::Complement(bag) => bag.space(),
-            Bag::Intersection(lh, _) => {
+            Bag::Distinct(bag, _) => bag.space(),
+            Bag::Filter(_, bag, _) => bag.space(),
+            Bag::Complement(bag, _) => bag.space(),
+            Bag::Intersection(lh, _, _) => {
                 // We are assuming lh and rh are in the same space.
                 // Checked as part of the validation.
                 lh.space()
             }
-            Bag::Union(lh, _) => {
+            Bag::Union(lh, _, _) => {
                 // We are assuming lh and rh are in the same space.
                 // Checked as part of the validation.
                 lh.space()
             }
-            Bag::Bag(_) => {
+            Bag::Bag(_, _) => {
                 // Bags can be defined in different spaces, thus the output is
                 // always in the universe space.
                 space::Space::universe().name()
             }
-            Bag::Inside(shape) => shape.space(),
-            Bag::Outside(shape) => shape.space(),
+            Bag::Inside(shape, _) => shape.space(),
+            Bag::Outside(shape, _) => shape.space(),
+            Bag::Shape(shape, _) => shape.space(),
+            Bag::Limit(_, bag, _) => bag.space(),
+            Bag::Offset(_, bag, _) => bag.space(),
+        }
+    }
+
+    /// Location of the bag expression in the query.
+    pub fn span(&self) -> Span {
+        match self {
+            Bag::Distinct(_, span)
+            | Bag::Filter(_, _, span)
+            | Bag::Complement(_, span)
+            | Bag::Intersection(_, _, span)
+            | Bag::Union(_, _, span)
+            | Bag::Bag(_, span)
+            | Bag::Inside(_, span)
+            | Bag::Outside(_, span)
+            | Bag::Shape(_, span)
+            | Bag::Limit(_, _, span)
+            | Bag::Offset(_, _, span) => *span,
+        }
+    }
+
+    /// Bags combined by this one, in the order of the expression.
+    pub fn operands(&self) -> Vec<&Bag> {
+        match self {
+            Bag::Distinct(bag, _)
+            | Bag::Filter(_, bag, _)
+            | Bag::Complement(bag, _)
+            | Bag::Limit(_, bag, _)
+            | Bag::Offset(_, bag, _) => vec![bag],
+            Bag::Intersection(lh, rh, _) | Bag::Union(lh, rh, _) => vec![lh, rh],
+            Bag::Bag(bags, _) => bags.iter().collect(),
+            Bag::Inside(_, _) | Bag::Outside(_, _) | Bag::Shape(_, _) => vec![],
+        }
+    }
+
+    /// Whether both bags are the same expression, wherever they were
+    /// written.
+    pub fn same_as(&self, other: &Bag) -> bool {
+        match (self, other) {
+            (Bag::Distinct(x, _), Bag::Distinct(y, _))
+            | (Bag::Complement(x, _), Bag::Complement(y, _)) => x.same_as(y),
+            (Bag::Filter(p, x, _), Bag::Filter(q, y, _)) => p == q && x.same_as(y),
+            (Bag::Intersection(xl, xr, _), Bag::Intersection(yl, yr, _))
+            | (Bag::Union(xl, xr, _), Bag::Union(yl, yr, _)) => xl.same_as(yl) && xr.same_as(yr),
+            (Bag::Bag(x, _), Bag::Bag(y, _)) => {
+                x.len() == y.len() && x.iter().zip(y).all(|(x, y)| x.same_as(y))
+            }
+            (Bag::Inside(a, _), Bag::Inside(b, _))
+            | (Bag::Outside(a, _), Bag::Outside(b, _))
+            | (Bag::Shape(a, _), Bag::Shape(b, _)) => a == b,
+            (Bag::Limit(n, x, _), Bag::Limit(m, y, _))
+            | (Bag::Offset(n, x, _), Bag::Offset(m, y, _)) => n == m && x.same_as(y),
+            _ => false,
         }
     }
 }
 /**********************************************************************/
 /* BAG OPERATORS                                                      */
 /**********************************************************************/
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Predicate {
+    #[cfg_attr(feature = "serde", serde(rename = "less"))]
     Less(Position, LiteralPosition),
+    #[cfg_attr(feature = "serde", serde(rename = "greater"))]
     Greater(Position, LiteralPosition),
+    #[cfg_attr(feature = "serde", serde(rename = "equal"))]
     Equal(Position, LiteralPosition),
+    #[cfg_attr(feature = "serde", serde(rename = "not"))]
     Not(Box<Predicate>),
+    #[cfg_attr(feature = "serde", serde(rename = "and"))]
     And(Box<Predicate>, Box<Predicate>),
+    #[cfg_attr(feature = "serde", serde(rename = "or"))]
     Or(Box<Predicate>, Box<Predicate>),
 }
 
@@ -116,13 +397,19 @@ pub enum Predicate {
 /**********************************************************************/
 /* SHAPES                                                             */
 /**********************************************************************/
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Shape {
+    #[cfg_attr(feature = "serde", serde(rename = "point"))]
     Point(String, LiteralPosition),
+    #[cfg_attr(feature = "serde", serde(rename = "hyperrectangle"))]
     HyperRectangle(String, Vec<LiteralPosition>),
+    #[cfg_attr(feature = "serde", serde(rename = "hypersphere"))]
     HyperSphere(String, LiteralPosition, LiteralNumber),
+    #[cfg_attr(feature = "serde", serde(rename = "label"))]
     Label(String, String),
-    Nifti(String),
+    #[cfg_attr(feature = "serde", serde(rename = "nifti"))]
+    Nifti(Transform, ByteProvider),
 }
 
 impl Shape {
@@ -132,17 +419,17 @@ impl Shape {
             Shape::HyperRectangle(space, _) => space,
             Shape::HyperSphere(space, _, _) => space,
             Shape::Label(space, _) => space,
-            Shape::Nifti(space) => space,
+            Shape::Nifti(transform, _) => &transform.reference,
         }
     }
 
-    pub fn volume(&self) -> f64 {
-        match self {
+    /// Volume of the shape. The extent of labels is only known from the
+    /// database, see the `Predictor`, which looks it up.
+    pub fn volume(&self) -> Result<f64, String> {
+        Ok(match self {
             Shape::Point(_, _) => f64::EPSILON, // The smallest non-zero volume possible
+            Shape::HyperRectangle(_space, pos) if pos.len() != 2 => OrientedBox::new(pos)?.volume(),
             Shape::HyperRectangle(_space, pos) => {
-                //TODO: At this time, only aligned to the axes, defined by two points, hyperrectangles are supported.
-                assert_eq!(pos.len(), 2);
-
                 // We assume the first position is the low point, the second is
                 // the high point, this being true for each dimension. As we add
                 // an even number of points per extra dimension, we assume the
@@ -200,64 +487,365 @@ impl Shape {
 
                 a * radius.powi(i as i32)
             }
-            Shape::Label(_, _) => {
-                // FIXME: Needs to find a way to figure out the approximate volume of this specific ID, or return MAX or MIN..
-                f64::EPSILON
+            Shape::Label(_, id) => {
+                return Err(format!(
+                    "The volume of label '{}' is only known from the database.",
+                    id
+                ))
+            }
+            // Parsing the volume is costly, callers which also need the
+            // mask should use `Mask::volume` instead.
+            Shape::Nifti(_, _) => self.mask()?.volume(),
+        })
+    }
+
+    pub fn mask(&self) -> Result<Mask, String> {
+        match self {
+            Shape::Nifti(transform, provider) => {
+                let volume = Volume::from_bytes(&provider.bytes()?)?;
+                Mask::new(volume, &transform.affine()?)
             }
-            Shape::Nifti(_) => unimplemented!("Nifti"),
+            _ => Err("Only NIfTI shapes define a mask.".to_string()),
         }
     }
+}
+
+// Relative tolerance used when comparing computed coordinates.
+const TOLERANCE: f64 = 1e-9;
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+fn distance(a: &[f64], b: &[f64]) -> f64 {
+    a.iter()
+        .zip(b)
+        .map(|(x, y)| (x - y) * (x - y))
+        .sum::<f64>()
+        .sqrt()
+}
+
+/// Arbitrarily oriented hyperrectangle, a.k.a. orthogonal
+/// parallelotope, defined by one of its vertices and the edges starting
+/// from that vertex.
+#[derive(Clone, Debug)]
+pub struct OrientedBox {
+    origin: Vec<f64>,
+    edges: Vec<Vec<f64>>,
+}
+
+impl OrientedBox {
+    /// Build the box from its 2^k vertices, in any order.
+    pub fn new(vertices: &[LiteralPosition]) -> Result<Self, String> {
+        let points = vertices.iter().map(|v| v.into()).collect::<Vec<Vec<f64>>>();
+
+        let k = match points.first() {
+            None => return Err("HyperRectangle: no vertices provided.".to_string()),
+            Some(p) => p.len(),
+        };
+
+        if points.iter().any(|p| p.len() != k) {
+            return Err("HyperRectangle: vertices have different dimensions.".to_string());
+        }
+
+        if k >= usize::BITS as usize || points.len() != 1 << k {
+            return Err(format!(
+                "HyperRectangle: {} vertices are required in {} dimensions, got {}.",
+                1u64.checked_shl(k as u32).unwrap_or(0),
+                k,
+                points.len()
+            ));
+        }
+
+        let origin = points[0].clone();
+        let mut vectors = points[1..]
+            .iter()
+            .map(|p| {
+                p.iter()
+                    .zip(&origin)
+                    .map(|(x, o)| x - o)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        let scale = vectors.iter().map(|v| dot(v, v).sqrt()).fold(1.0, f64::max);
+        let epsilon = TOLERANCE * scale;
+
+        // Process the vertices from the closest to the farthest from the
+        // origin. A vertex which cannot be reached by combining the edges
+        // found so far defines a new edge.
+        vectors.sort_by(|a, b| dot(a, a).partial_cmp(&dot(b, b)).unwrap_or(Ordering::Equal));
+
+        let mut edges: Vec<Vec<f64>> = vec![];
+        let mut reachable = vec![vec![0.0; k]];
+        for v in vectors {
+            if reachable.iter().any(|r| distance(r, &v) <= epsilon) {
+                continue;
+            }
+
+            let shifted = reachable
+                .iter()
+                .map(|r| r.iter().zip(&v).map(|(a, b)| a + b).collect())
+                .collect::<Vec<_>>();
+            reachable.extend(shifted);
+            edges.push(v);
+
+            if edges.len() > k {
+                break;
+            }
+        }
+
+        if edges.len() != k {
+            return Err("HyperRectangle: the vertices do not define a parallelotope.".to_string());
+        }
+
+        for (i, a) in edges.iter().enumerate() {
+            for b in &edges[i + 1..] {
+                if dot(a, b).abs() > TOLERANCE * dot(a, a).sqrt() * dot(b, b).sqrt() {
+                    return Err("HyperRectangle: the faces are not orthogonal.".to_string());
+                }
+            }
+        }
 
-    pub fn rasterize<'e>(&self) -> mercator_db::ResultSet<'e> {
-        unimplemented!("rasterize")
+        // Every vertex must be a combination of the edges, and vice
+        // versa.
+        let mut matched = vec![false; points.len()];
+        for r in &reachable {
+            let vertex = r
+                .iter()
+                .zip(&origin)
+                .map(|(a, o)| a + o)
+                .collect::<Vec<_>>();
+            match points
+                .iter()
+                .enumerate()
+                .position(|(i, p)| !matched[i] && distance(p, &vertex) <= epsilon)
+            {
+                None => {
+                    return Err(
+                        "HyperRectangle: the vertices do not define a parallelotope.".to_string(),
+                    )
+                }
+                Some(i) => matched[i] = true,
+            }
+        }
+
+        Ok(OrientedBox { origin, edges })
+    }
+
+    pub fn volume(&self) -> f64 {
+        self.edges.iter().map(|e| dot(e, e).sqrt()).product()
+    }
+
+    // Coordinates of the position along each edge, 0 being the origin,
+    // and 1 the end of the edge.
+    fn coordinates(&self, position: &[f64]) -> impl Iterator<Item = f64> + '_ {
+        let d = position
+            .iter()
+            .zip(&self.origin)
+            .map(|(x, o)| x - o)
+            .collect::<Vec<_>>();
+
+        self.edges.iter().map(move |e| dot(&d, e) / dot(e, e))
+    }
+
+    /// Whether the position is inside the box, surface included.
+    pub fn contains(&self, position: &[f64]) -> bool {
+        position.len() == self.origin.len()
+            && self
+                .coordinates(position)
+                .all(|t| (-TOLERANCE..=1.0 + TOLERANCE).contains(&t))
+    }
+
+    /// Whether the position is inside the box, surface excluded.
+    pub fn contains_strictly(&self, position: &[f64]) -> bool {
+        position.len() == self.origin.len()
+            && self
+                .coordinates(position)
+                .all(|t| t > TOLERANCE && t < 1.0 - TOLERANCE)
+    }
+
+    /// Smallest bounding box aligned with the axes, as (low, high).
+    pub fn bounding_box(&self) -> (Vec<f64>, Vec<f64>) {
+        let mut low = self.origin.clone();
+        let mut high = self.origin.clone();
+
+        for e in &self.edges {
+            for ((l, h), x) in low.iter_mut().zip(high.iter_mut()).zip(e) {
+                if *x < 0.0 {
+                    *l += x;
+                } else {
+                    *h += x;
+                }
+            }
+        }
+
+        (low, high)
     }
 }
 
 /**********************************************************************/
 /* POSITIONS                                                          */
 /**********************************************************************/
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Position {
+    #[cfg_attr(feature = "serde", serde(rename = "str_cmp"))]
     StrCmp(LiteralSelector, String),
+    #[cfg_attr(feature = "serde", serde(rename = "str_cmp_ignore_case"))]
+    StrCmpIgnoreCase(LiteralSelector, String),
+    #[cfg_attr(feature = "serde", serde(rename = "selector"))]
     Selector(LiteralSelector),
+    #[cfg_attr(feature = "serde", serde(rename = "literal"))]
     Literal(LiteralPosition),
 }
 
 impl Position {
+    /// Type of the position, for objects of `space` described by
+    /// `schema`. Numbers are promoted to positions with a single
+    /// coordinate.
+    pub fn get_type(&self, schema: &Schema, space: &str) -> Result<LiteralTypes, String> {
+        match self {
+            Position::Literal(literal) => Ok(literal.get_type()),
+            Position::Selector(selector) => match selector.get_type(schema, space)? {
+                t if t == schema.object(space) => Ok(schema.position(space)),
+                t @ LiteralTypes::Vector(_) | t @ LiteralTypes::Coordinates => Ok(t),
+                t @ LiteralTypes::Int | t @ LiteralTypes::Float => {
+                    Ok(LiteralTypes::Vector(vec![t]))
+                }
+                t => Err(format!(
+                    "Selector {:?} is not a position, but '{:?}'",
+                    selector, t
+                )),
+            },
+            Position::StrCmp(selector, _) | Position::StrCmpIgnoreCase(selector, _) => {
+                match selector.get_type(schema, space)? {
+                    LiteralTypes::String => Ok(LiteralTypes::Vector(vec![LiteralTypes::Int])),
+                    t => Err(format!(
+                        "str_cmp: selector {:?} is not a string, but '{:?}'",
+                        selector, t
+                    )),
+                }
+            }
+        }
+    }
+
     pub fn value<'e>(
         &self,
         object: (&'e String, &'e space::Position, &'e Properties),
-    ) -> LiteralPosition {
+    ) -> Result<LiteralPosition, String> {
         match self {
-            Position::Literal(literal) => literal.clone(),
+            Position::Literal(literal) => Ok(literal.clone()),
             Position::Selector(selector) => selector.position(object),
-            Position::StrCmp(selector, literal) => {
-                let x = match (selector.str(object)).cmp(literal) {
-                    Ordering::Equal => 0,
-                    Ordering::Greater => 1,
-                    Ordering::Less => -1,
-                };
-
-                let v = vec![LiteralNumber::Int(x)];
-                LiteralPosition(v)
+            Position::StrCmp(selector, literal) => Ok(str_cmp(selector.str(object)?.cmp(literal))),
+            Position::StrCmpIgnoreCase(selector, literal) => {
+                let value = UniCase::new(selector.str(object)?);
+                Ok(str_cmp(value.cmp(&UniCase::new(literal.as_str()))))
             }
         }
     }
 }
 
+// Encode the result of a string comparison as a position.
+fn str_cmp(ordering: Ordering) -> LiteralPosition {
+    let x = match ordering {
+        Ordering::Equal => 0,
+        Ordering::Greater => 1,
+        Ordering::Less => -1,
+    };
+
+    let v = vec![LiteralNumber::Int(x)];
+    LiteralPosition(v)
+}
+
 /**********************************************************************/
 /* Literals / TOKENS                                                  */
 /**********************************************************************/
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Field(pub String, pub Option<usize>);
 
+/// Replace the escape sequences of a JSON string by the characters they
+/// stand for. The string is assumed to be well-formed, as checked by the
+/// grammar, except for the UTF-16 surrogates of `\u` escapes, which have
+/// to come in pairs.
+pub fn unescape(s: &str) -> Result<String, &'static str> {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+
+    // Code unit of the `\u` escape sequence at the start of `chars`.
+    let code_unit = |chars: &mut std::str::Chars| {
+        let code = chars.by_ref().take(4).collect::<String>();
+        u32::from_str_radix(&code, 16).map_err(|_| "invalid escape sequence")
+    };
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('b') => unescaped.push('\u{8}'),
+            Some('f') => unescaped.push('\u{c}'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some('t') => unescaped.push('\t'),
+            Some('u') => {
+                let mut code = code_unit(&mut chars)?;
+                if (0xD800..0xDC00).contains(&code) {
+                    // High surrogate, which has to be followed by a low
+                    // one.
+                    if chars.next() != Some('\\') || chars.next() != Some('u') {
+                        return Err("unpaired surrogate in escape sequence");
+                    }
+                    let low = code_unit(&mut chars)?;
+                    if !(0xDC00..0xE000).contains(&low) {
+                        return Err("unpaired surrogate in escape sequence");
+                    }
+                    code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                }
+
+                // Low surrogates on their own are not characters.
+                let c = char::from_u32(code).ok_or("unpaired surrogate in escape sequence")?;
+                unescaped.push(c);
+            }
+            Some(c) => unescaped.push(c),
+            None => (),
+        }
+    }
+
+    Ok(unescaped)
+}
+
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum LiteralNumber {
+    #[cfg_attr(feature = "serde", serde(rename = "int"))]
     Int(i64),
+    #[cfg_attr(feature = "serde", serde(rename = "float"))]
     Float(f64),
 }
 
+// Numbers of the queries are integers when they fit, otherwise floating
+// point numbers. Numbers too large to be represented are rejected, as
+// they could not be printed back.
+impl FromStr for LiteralNumber {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(x) = i64::from_str(s) {
+            return Ok(LiteralNumber::Int(x));
+        }
+
+        match f64::from_str(s) {
+            Ok(x) if x.is_finite() => Ok(LiteralNumber::Float(x)),
+            _ => Err("number out of range"),
+        }
+    }
+}
+
 impl From<&LiteralNumber> for f64 {
     fn from(l: &LiteralNumber) -> Self {
         match l {
@@ -304,6 +892,7 @@ impl PartialEq for LiteralNumber {
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct LiteralPosition(pub Vec<LiteralNumber>);
 
 impl LiteralPosition {
@@ -373,7 +962,7 @@ impl From<&Vec<f64>> for LiteralPosition {
 
         let mut lv = Vec::with_capacity(v.len());
         for value in v {
-            lv.push(LiteralNumber::Float(*value));
+            lv.push((*value).into());
         }
 
         LiteralPosition(lv)
@@ -448,48 +1037,194 @@ impl PartialEq for LiteralPosition {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct LiteralSelector(pub Vec<Field>);
 
 impl LiteralSelector {
-    pub fn get_type(&self) -> LiteralTypes {
-        // FIXME: Pretend for now that everything is a number, needs to be actually looked up in data model.
-        LiteralTypes::Int
+    /// Type of the value found at the end of the path, for objects of
+    /// `space` described by `schema`. This follows the same rules as
+    /// `resolve()`.
+    pub fn get_type(&self, schema: &Schema, space: &str) -> Result<LiteralTypes, String> {
+        let LiteralSelector(fields) = self;
+        let root = schema.object(space);
+        let mut t = root.clone();
+        for Field(name, index) in fields {
+            if !name.is_empty() {
+                t = match t.field(name) {
+                    Some(t) => t.clone(),
+                    None => match t {
+                        LiteralTypes::Object(_) => return Err(format!("Unknown field '{}'", name)),
+                        _ => return Err(format!("Field '{}' is not an object", name)),
+                    },
+                };
+            } else if index.is_some() && t == root {
+                // `.[n]` is a shorthand to index the position of the
+                // object.
+                t = schema.position(space);
+            }
+
+            if let Some(index) = index {
+                t = match t {
+                    LiteralTypes::Vector(mut v) if *index < v.len() => v.swap_remove(*index),
+                    LiteralTypes::Coordinates => LiteralTypes::Float,
+                    _ => return Err(format!("Invalid index '{}' for field '{}'", index, name)),
+                };
+            }
+        }
+
+        Ok(t)
     }
 
-    // FIXME: THIS IS SOOO WRONG
-    pub fn position<'e>(
+    /// Walk the fields of the selector over the object, and return the
+    /// value found at the end of the path.
+    ///
+    /// The root of the path is the object, which exposes:
+    ///  * `.reference_space`: the name of the space of the position,
+    ///  * `.position`: the position, with `.position[n]` its n-th
+    ///    coordinate, also available as the shorthand `.[n]`,
+    ///  * `.properties`: the properties, which provides `.id` and
+    ///    `.type`, also available directly on the root as `.id` and
+    ///    `.type`.
+    ///
+    /// Every object provides all these fields, so whether the selector
+    /// resolves only depends on the number of dimensions of the
+    /// positions, the same for all the objects of a space.
+    pub fn resolve<'e>(
         &self,
         object: (&'e String, &'e space::Position, &'e Properties),
-    ) -> LiteralPosition {
-        println!("LiteralSelector.position(): {:?}", self);
-        object.1.into()
-    }
-
-    // FIXME: THIS IS SOOO WRONG
-    pub fn str<'e>(&self, object: (&'e String, &'e space::Position, &'e Properties)) -> &'e str {
-        let LiteralSelector(v) = self;
-        let last = v.last();
-        if let Some(Field(name, _)) = last {
-            if name == "id" {
-                return object.2.id();
-            } else if name == "type" {
-                return object.2.type_name();
-            } else if name == "reference_space" {
-                return object.0;
+    ) -> Result<Value<'e>, String> {
+        let (space_id, position, properties) = object;
+
+        let LiteralSelector(fields) = self;
+        let mut value = Value::Object(object);
+        for Field(name, index) in fields {
+            if !name.is_empty() {
+                value = match (value, name.as_str()) {
+                    (Value::Object(_), "reference_space") => Value::Str(space_id),
+                    (Value::Object(_), "position") => Value::Position(position.into()),
+                    (Value::Object(_), "properties") => Value::Properties(properties),
+                    (Value::Object(_), "id") | (Value::Properties(_), "id") => {
+                        Value::Str(properties.id())
+                    }
+                    (Value::Object(_), "type") | (Value::Properties(_), "type") => {
+                        Value::Str(properties.type_name())
+                    }
+                    (Value::Object(_), _) | (Value::Properties(_), _) => {
+                        return Err(format!("Unknown field '{}'", name))
+                    }
+                    (_, _) => return Err(format!("Field '{}' is not an object", name)),
+                };
+            } else if let (Value::Object(_), Some(_)) = (&value, index) {
+                // `.[n]` is a shorthand to index the position of the
+                // object.
+                value = Value::Position(position.into());
+            }
+
+            if let Some(index) = index {
+                value = match value {
+                    Value::Position(LiteralPosition(mut v)) if *index < v.len() => {
+                        Value::Number(v.swap_remove(*index))
+                    }
+                    _ => return Err(format!("Invalid index '{}' for field '{}'", index, name)),
+                };
             }
         }
 
-        println!("LiteralSelector.str(): {:?}", self);
-        unimplemented!("Unknown Field");
+        Ok(value)
+    }
+
+    /// Resolve the selector to a position. Numbers are promoted to
+    /// positions with a single coordinate, and the object to its
+    /// position.
+    pub fn position<'e>(
+        &self,
+        object: (&'e String, &'e space::Position, &'e Properties),
+    ) -> Result<LiteralPosition, String> {
+        match self.resolve(object)? {
+            // The object itself stands for its position.
+            Value::Object((_, position, _)) => Ok(position.into()),
+            Value::Position(position) => Ok(position),
+            Value::Number(number) => Ok(LiteralPosition(vec![number])),
+            value => Err(format!(
+                "Selector {:?} is not a position, but {}",
+                self,
+                value.kind()
+            )),
+        }
+    }
+
+    /// Resolve the selector to a string.
+    pub fn str<'e>(
+        &self,
+        object: (&'e String, &'e space::Position, &'e Properties),
+    ) -> Result<&'e str, String> {
+        match self.resolve(object)? {
+            Value::Str(s) => Ok(s),
+            value => Err(format!(
+                "Selector {:?} is not a string, but {}",
+                self,
+                value.kind()
+            )),
+        }
+    }
+
+    pub fn json<'e>(
+        &self,
+        object: (&'e String, &'e space::Position, &'e Properties),
+    ) -> Result<serde_json::Value, String> {
+        Ok(self.resolve(object)?.into())
+    }
+}
+
+/// Typed value of a selector, resolved against an object.
+#[derive(Clone, Debug)]
+pub enum Value<'e> {
+    Object((&'e String, &'e space::Position, &'e Properties)),
+    Properties(&'e Properties),
+    Position(LiteralPosition),
+    Number(LiteralNumber),
+    Str(&'e str),
+}
+
+impl<'e> Value<'e> {
+    fn kind(&self) -> &'static str {
+        match self {
+            Value::Object(_) => "an object",
+            Value::Properties(_) => "properties",
+            Value::Position(_) => "a position",
+            Value::Number(_) => "a number",
+            Value::Str(_) => "a string",
+        }
     }
 }
 
-//FIXME: HACK FOR NOW; NEED PROPER TYPE CHECKING!
-pub fn get_type() -> LiteralTypes {
-    LiteralTypes::Vector(vec![
-        LiteralTypes::Float,
-        LiteralTypes::Float,
-        LiteralTypes::Float,
-    ])
+impl<'e> From<Value<'e>> for serde_json::Value {
+    fn from(value: Value<'e>) -> Self {
+        fn properties(properties: &Properties) -> serde_json::Value {
+            serde_json::json!({
+                "id": properties.id(),
+                "type": properties.type_name(),
+            })
+        }
+
+        match value {
+            Value::Object((space_id, position, p)) => {
+                let position: Vec<f64> = position.into();
+                serde_json::json!({
+                    "reference_space": space_id,
+                    "position": position,
+                    "properties": properties(p),
+                })
+            }
+            Value::Properties(p) => properties(p),
+            Value::Position(position) => {
+                let position: Vec<f64> = position.into();
+                position.into()
+            }
+            Value::Number(LiteralNumber::Int(i)) => i.into(),
+            Value::Number(LiteralNumber::Float(f)) => f.into(),
+            Value::Str(s) => s.into(),
+        }
+    }
 }
@@ -500,6 +500,17 @@ mod parsing {
             assert!(p.parse("inside(point{[0]})").is_ok());
         }
 
+        #[test]
+        fn shape() {
+            let p = filters_parser();
+
+            assert!(p.parse("shape()").is_err());
+
+            assert!(p.parse("shape(point{[0]})").is_ok());
+            assert!(p.parse("shape(hypersphere{[0], 1})").is_ok());
+            assert!(p.parse("bag{shape(point{[0]}), inside(point{[0]})}").is_ok());
+        }
+
         /* Not useful to test this rule
         #[test]
         fn shapes() {
@@ -561,14 +572,42 @@ mod parsing {
 
         #[test]
         fn nifti() {
-            let _p = filters_parser();
-            unimplemented!();
+            let p = filters_parser();
+            let uri = "uri(\"file:///a.nii\")";
+            let rotation = "[[1, 0, 0], [0, 1, 0], [0, 0, 1]]";
+
+            assert!(p.parse("inside(nifti{})").is_err());
+            assert!(p.parse("inside(nifti{[1, 2, 3]})").is_err());
+            assert!(p.parse(format!("inside(nifti{{{}, [1, 2, 3]}})", uri).as_str()).is_err());
+            assert!(p.parse(format!("inside(nifti{{{}, {}}})", rotation, uri).as_str()).is_err());
+
+            assert!(p.parse(format!("inside(nifti{{{}}})", uri).as_str()).is_ok());
+            assert!(p.parse(format!("inside(nifti{{{}, \"space\"}})", uri).as_str()).is_ok());
+            assert!(p.parse(format!("inside(nifti{{[1, 2, 3], {}}})", uri).as_str()).is_ok());
+            assert!(p.parse(format!("inside(nifti{{{}, {}}})", rotation, uri).as_str()).is_ok());
+            assert!(p
+                .parse(format!("inside(nifti{{[1, 2, 3], {}, {}, \"space\"}})", rotation, uri).as_str())
+                .is_ok());
+            assert!(p.parse(format!("outside(nifti{{{}}})", uri).as_str()).is_ok());
         }
 
         #[test]
         fn byte_provider() {
-            let _p = filters_parser();
-            unimplemented!();
+            let p = filters_parser();
+
+            assert!(p.parse("inside(nifti{uri()})").is_err());
+            assert!(p.parse("inside(nifti{uri(0)})").is_err());
+            assert!(p.parse("inside(nifti{\"file:///a.nii\"})").is_err());
+
+            assert!(p.parse("inside(nifti{uri(\"\")})").is_ok());
+            assert!(p.parse("inside(nifti{uri(\"file:///a.nii\")})").is_ok());
+
+            assert!(p.parse("inside(nifti{bytes()})").is_err());
+            assert!(p.parse("inside(nifti{bytes(0)})").is_err());
+
+            assert!(p.parse("inside(nifti{bytes(\"\")})").is_ok());
+            assert!(p.parse("inside(nifti{bytes(\"AAEC\")})").is_ok());
+            assert!(p.parse("inside(nifti{bytes(\"AAEC\"), \"space\"})").is_ok());
         }
 
         /* Not useful to test this rule
@@ -953,3 +992,2119 @@ mod parsing {
         }
     }
 }
+
+#[cfg(test)]
+mod nifti {
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    use crate::nifti::Affine;
+    use crate::nifti::Mask;
+    use crate::nifti::Volume;
+    use crate::symbols::ByteProvider;
+    use crate::symbols::Shape;
+    use crate::symbols::Transform;
+
+    #[test]
+    fn volume() {
+        assert!(Volume::new(vec![], &[], vec![]).is_err());
+        assert!(Volume::new(vec![1, 1, 1, 1], &[0.0; 4], vec![1.0; 4]).is_err());
+        assert!(Volume::new(vec![1, 0, 1], &[0.0; 3], vec![1.0; 3]).is_err());
+        assert!(Volume::new(vec![1, 1], &[0.0; 3], vec![1.0; 3]).is_err());
+
+        let mut v = Volume::new(vec![2, 3, 4], &[0.0; 3], vec![1.0; 3]).unwrap();
+        assert_eq!(v.get(&[1, 2, 3]), Some(0.0));
+        assert_eq!(v.get(&[2, 2, 3]), None);
+        assert_eq!(v.get(&[1, 2]), None);
+
+        assert!(v.set(&[1, 2, 3], 4.0).is_ok());
+        assert!(v.set(&[1, 3, 3], 4.0).is_err());
+        assert_eq!(v.get(&[1, 2, 3]), Some(4.0));
+    }
+
+    #[test]
+    fn to_bytes() {
+        let mut v = Volume::new(vec![2, 3, 4], &[1.0, 2.0, 3.0], vec![0.5; 3]).unwrap();
+        v.set(&[1, 0, 0], 2.0).unwrap();
+        let bytes = v.to_bytes();
+
+        // Header, extension and 24 voxels of 4 bytes.
+        assert_eq!(bytes.len(), 352 + 24 * 4);
+        assert_eq!(&bytes[0..4], &348i32.to_le_bytes());
+        assert_eq!(&bytes[344..348], b"n+1\0");
+
+        // dim[0..4]
+        assert_eq!(&bytes[40..42], &3i16.to_le_bytes());
+        assert_eq!(&bytes[42..44], &2i16.to_le_bytes());
+        assert_eq!(&bytes[44..46], &3i16.to_le_bytes());
+        assert_eq!(&bytes[46..48], &4i16.to_le_bytes());
+
+        // srow_x translation
+        assert_eq!(&bytes[292..296], &1f32.to_le_bytes());
+
+        // Data, x-fastest
+        assert_eq!(&bytes[352..356], &0f32.to_le_bytes());
+        assert_eq!(&bytes[356..360], &2f32.to_le_bytes());
+    }
+
+    #[test]
+    fn from_bytes() {
+        assert!(Volume::from_bytes(&[]).is_err());
+        assert!(Volume::from_bytes(&[0; 400]).is_err());
+
+        let mut v = Volume::new(vec![2, 3, 4], &[1.0, 2.0, 3.0], vec![0.5; 3]).unwrap();
+        v.set(&[1, 2, 3], 2.0).unwrap();
+
+        let r = Volume::from_bytes(&v.to_bytes()).unwrap();
+        assert_eq!(r.affine(), v.affine());
+        assert_eq!(r.get(&[1, 2, 3]), Some(2.0));
+        assert_eq!(r.get(&[0, 2, 3]), Some(0.0));
+        assert_eq!(r.non_zero().collect::<Vec<_>>(), vec![[1, 2, 3]]);
+
+        // The qform gives the same transformation as the sform.
+        let qform = |mut bytes: Vec<u8>| {
+            bytes[254..256].copy_from_slice(&0i16.to_le_bytes());
+            Volume::from_bytes(&bytes).unwrap()
+        };
+        assert_eq!(qform(v.to_bytes()).affine(), v.affine());
+
+        // Rotated by 90 degrees around the third axis, with or without a
+        // reflection along the third axis.
+        for z in [0.5f32, -0.5] {
+            let mut bytes = v.to_bytes();
+            let srows = [
+                [0.0, -0.5, 0.0, 1.0],
+                [0.5, 0.0, 0.0, 2.0],
+                [0.0, 0.0, z, 3.0],
+            ];
+            for (r, row) in srows.iter().enumerate() {
+                for (c, x) in row.iter().enumerate() {
+                    let offset = 280 + r * 16 + c * 4;
+                    bytes[offset..offset + 4].copy_from_slice(&x.to_le_bytes());
+                }
+            }
+
+            let rotated = Volume::from_bytes(&bytes).unwrap();
+            let read = qform(rotated.to_bytes());
+            for p in [[0.0, 0.0, 0.0], [1.0, 2.0, 3.0]] {
+                let (a, b) = (rotated.affine().apply(&p), read.affine().apply(&p));
+                assert!(a.iter().zip(&b).all(|(a, b)| (a - b).abs() < 1e-6));
+            }
+        }
+
+        // A header claiming more voxels than the data holds is rejected
+        // before allocating them.
+        let mut huge = v.to_bytes();
+        for offset in [42, 44, 46] {
+            huge[offset..offset + 2].copy_from_slice(&i16::MAX.to_le_bytes());
+        }
+        assert_eq!(
+            Volume::from_bytes(&huge).unwrap_err(),
+            "NIfTI: truncated data."
+        );
+    }
+
+    #[test]
+    fn affine() {
+        let a = Affine::new([
+            [0.0, -2.0, 0.0, 1.0],
+            [1.0, 0.0, 0.0, 2.0],
+            [0.0, 0.0, 3.0, 3.0],
+        ]);
+        assert_eq!(a.determinant(), 6.0);
+
+        let p = a.apply(&[1.0, 2.0, 3.0]);
+        assert_eq!(p, [-3.0, 3.0, 12.0]);
+        assert_eq!(a.inverse().unwrap().apply(&p), [1.0, 2.0, 3.0]);
+        assert_eq!(a.then(&a.inverse().unwrap()), Affine::identity());
+
+        let singular = Affine::new([[1.0, 0.0, 0.0, 0.0]; 3]);
+        assert!(singular.inverse().is_err());
+    }
+
+    #[test]
+    fn mask() {
+        let mut v = Volume::new(vec![2, 2, 2], &[0.0; 3], vec![1.0; 3]).unwrap();
+        v.set(&[1, 1, 1], 1.0).unwrap();
+
+        // Move the volume by [10, 0, 0]
+        let translation = Affine::new([
+            [1.0, 0.0, 0.0, 10.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+        ]);
+        let mask = Mask::new(v, &translation).unwrap();
+
+        assert!(mask.contains(&[11.0, 1.0, 1.0]));
+        assert!(mask.contains(&[11.4, 0.6, 1.0]));
+        assert!(!mask.contains(&[10.0, 0.0, 0.0]));
+        assert!(!mask.contains(&[1.0, 1.0, 1.0]));
+        assert!(!mask.contains(&[12.0, 1.0, 1.0]));
+        assert!(!mask.contains(&[11.0, 1.0, 1.0, 0.0]));
+
+        // Positions with fewer dimensions have their other coordinates
+        // at zero.
+        assert!(!mask.contains(&[11.0, 1.0]));
+        let mut plane = Volume::new(vec![2, 2], &[0.0; 2], vec![1.0; 2]).unwrap();
+        plane.set(&[1, 1], 1.0).unwrap();
+        let plane = Mask::new(plane, &translation).unwrap();
+        assert!(plane.contains(&[11.0, 1.0]));
+        assert!(plane.contains(&[11.0, 1.0, 0.0]));
+        assert!(!plane.contains(&[11.0, 0.0]));
+
+        assert_eq!(mask.volume(), 1.0);
+        assert_eq!(
+            mask.bounding_box(),
+            (vec![9.5, -0.5, -0.5], vec![11.5, 1.5, 1.5])
+        );
+    }
+
+    #[test]
+    fn byte_provider() {
+        assert_eq!(ByteProvider::Bytes("AAEC".to_string()).bytes(), Ok(vec![0, 1, 2]));
+        assert_eq!(ByteProvider::Bytes("AA\nEC".to_string()).bytes(), Ok(vec![0, 1, 2]));
+        assert_eq!(ByteProvider::Bytes("".to_string()).bytes(), Ok(vec![]));
+        assert!(ByteProvider::Bytes("AAE".to_string()).bytes().is_err());
+        assert!(ByteProvider::Bytes("AA*C".to_string()).bytes().is_err());
+
+        // gzip-compressed payload, "H4sI" being the base64 encoding of
+        // the gzip magic number.
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&[0, 1, 2]).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let encoded = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, compressed);
+        assert!(encoded.starts_with("H4sI"));
+        assert_eq!(ByteProvider::Bytes(encoded.clone()).bytes(), Ok(vec![0, 1, 2]));
+
+        // Only the base64 encoding is checked by the validation.
+        assert_eq!(ByteProvider::Bytes(encoded).check(), Ok(()));
+        assert!(ByteProvider::Bytes("AA*C".to_string()).check().is_err());
+        assert_eq!(ByteProvider::Uri("file:///does/not/exist.nii".to_string()).check(), Ok(()));
+
+        assert!(ByteProvider::Uri("http://a.nifti.file".to_string()).bytes().is_err());
+        assert!(ByteProvider::Uri("file:///does/not/exist.nii".to_string()).bytes().is_err());
+    }
+
+    #[test]
+    fn shape_volume() {
+        let mut v = Volume::new(vec![2, 2, 2], &[0.0; 3], vec![1.0; 3]).unwrap();
+        v.set(&[1, 1, 1], 1.0).unwrap();
+        let encoded =
+            base64::Engine::encode(&base64::engine::general_purpose::STANDARD, v.to_bytes());
+
+        let transform = Transform {
+            reference: "std".to_string(),
+            offset: vec![],
+            rotation: vec![],
+        };
+        let shape = Shape::Nifti(transform.clone(), ByteProvider::Bytes(encoded));
+        assert_eq!(shape.volume(), Ok(1.0));
+
+        // Invalid volumes are reported, instead of being assumed empty.
+        let invalid = Shape::Nifti(transform, ByteProvider::Bytes("AAEC".to_string()));
+        assert!(invalid.volume().is_err());
+    }
+}
+
+#[cfg(test)]
+mod validation {
+    use crate::queries;
+    use crate::Error;
+    use crate::Schema;
+    use crate::Validator;
+
+    fn validate(query: &str) -> bool {
+        queries::FiltersParser::new()
+            .parse(query)
+            .unwrap()
+            .validate()
+            .is_ok()
+    }
+
+    #[test]
+    fn hyperrectangle() {
+        // Aligned with the axes
+        assert!(validate("inside(hyperrectangle{[0, 0], [1, 1]})"));
+
+        // Square rotated by 45°, vertices in any order
+        assert!(validate(
+            "inside(hyperrectangle{[0, 0], [1, 1], [-1, 1], [0, 2]})"
+        ));
+        assert!(validate(
+            "inside(hyperrectangle{[0, 2], [-1, 1], [1, 1], [0, 0]})"
+        ));
+        assert!(validate(
+            "inside(hyperrectangle{[0, 0], [1.5, 1.5], [-1, 1], [0.5, 2.5]})"
+        ));
+
+        // Cube
+        assert!(validate(
+            "inside(hyperrectangle{[0, 0, 0], [1, 0, 0], [0, 1, 0], [1, 1, 0], [0, 0, 1], [1, 0, 1], [0, 1, 1], [1, 1, 1]})"
+        ));
+
+        // Parallelogram
+        assert!(!validate(
+            "inside(hyperrectangle{[0, 0], [1, 0], [1, 1], [2, 1]})"
+        ));
+
+        // Not a parallelotope
+        assert!(!validate(
+            "inside(hyperrectangle{[0, 0], [1, 0], [0, 1], [2, 2]})"
+        ));
+
+        // Wrong number of vertices for the dimensions
+        assert!(!validate(
+            "inside(hyperrectangle{[0, 0, 0], [1, 0, 0], [0, 1, 0], [1, 1, 0]})"
+        ));
+
+        // Degenerated
+        assert!(!validate(
+            "inside(hyperrectangle{[0, 0], [1, 1], [0, 0], [1, 1]})"
+        ));
+    }
+
+    #[test]
+    fn vertices() {
+        use crate::LiteralPosition;
+        use crate::Shape;
+
+        // Built or deserialized shapes may have too few vertices.
+        for n in 0..2 {
+            let vertices = vec![LiteralPosition::from(vec![0, 0]); n];
+            let shape = Shape::HyperRectangle("std".to_string(), vertices);
+            assert!(shape.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn selectors() {
+        assert!(validate("filter(<(.position, [1, 2, 3]), inside(point{[0, 0, 0]}))"));
+        assert!(validate("filter(<(., [1, 2, 3]), inside(point{[0, 0, 0]}))"));
+        assert!(validate("filter(>(.[0], [1]), inside(point{[0, 0, 0]}))"));
+        assert!(validate("filter(=(str_cmp(.properties.type, \"a\"), [0]), inside(point{[0, 0, 0]}))"));
+
+        // Unknown fields and mismatched types
+        assert!(!validate("filter(=(.id, [0]), inside(point{[0, 0, 0]}))"));
+        assert!(!validate("filter(<(.unknown, [1]), inside(point{[0, 0, 0]}))"));
+        assert!(!validate("filter(=(str_cmp(.position, \"a\"), [0]), inside(point{[0, 0, 0]}))"));
+
+        // Invalid indices and dimensions, when they are known
+        let validate_3d = |query: &str| {
+            queries::FiltersParser::new()
+                .parse(query)
+                .unwrap()
+                .validate_with(&Schema::new(3))
+                .is_ok()
+        };
+        assert!(!validate_3d("filter(<(.position[3], [1]), inside(point{[0, 0, 0]}))"));
+        assert!(!validate_3d("filter(<(.position, [1, 2]), inside(point{[0, 0, 0]}))"));
+        assert!(validate("filter(<(.position[3], [1]), inside(point{[0, 0, 0]}))"));
+        assert!(validate("filter(<(.position, [1, 2]), inside(point{[0, 0, 0]}))"));
+    }
+
+    #[test]
+    fn schema() {
+        let bag = queries::FiltersParser::new()
+            .parse("filter(<(.position, [1, 2]), inside(point{[0, 0]}))")
+            .unwrap();
+
+        // Positions have any number of dimensions by default.
+        assert!(bag.validate().is_ok());
+        assert!(bag.validate_with(&Schema::new(3)).is_err());
+        assert!(bag.validate_with(&Schema::new(2)).is_ok());
+        assert!(bag
+            .validate_with(&Schema::new(3).with_space(bag.space(), 2))
+            .is_ok());
+    }
+
+    #[test]
+    fn dimensions() {
+        let schema = Schema::default().with_space("2d", 2);
+        let validate = |query: &str| {
+            queries::FiltersParser::new()
+                .parse(query)
+                .unwrap()
+                .validate_with(&schema)
+                .is_ok()
+        };
+
+        assert!(validate("inside(point{[0, 0], \"2d\"})"));
+        assert!(validate("inside(hypersphere{[0, 0], 1, \"2d\"})"));
+        assert!(!validate("inside(point{[0, 0, 0], \"2d\"})"));
+        assert!(!validate("inside(hyperrectangle{[0, 0], [1, 1, 1], \"2d\"})"));
+
+        // Spaces not described by the schema accept any dimensions.
+        assert!(validate("inside(point{[0, 0]})"));
+
+        // NIfTI volumes fit in spaces of at most 3 dimensions.
+        let uri = "uri(\"file:///a.nii\")";
+        assert!(validate(&format!("inside(nifti{{{}, \"2d\"}})", uri)));
+        assert!(validate(&format!("inside(nifti{{{}}})", uri)));
+        let schema = schema.with_space("4d", 4);
+        let nifti = queries::FiltersParser::new()
+            .parse(&format!("inside(nifti{{{}, \"4d\"}})", uri))
+            .unwrap();
+        assert!(nifti.validate_with(&schema).is_err());
+    }
+
+    #[test]
+    fn projections() {
+        let validate = |query: &str| {
+            queries::QueryParser::new()
+                .parse(query)
+                .unwrap()
+                .unwrap()
+                .validate()
+                .is_ok()
+        };
+
+        assert!(validate("json({\"id\": .id, \"x\": .[0]}, inside(point{[0]}))"));
+        assert!(validate("json([count(.), sum(.[0]), max(.position[1])], inside(point{[0]}))"));
+        assert!(validate("json({\"n\": count(distinct .type), \"a\": 1}, inside(point{[0]}))"));
+
+        // Selectors of the templates are checked.
+        assert!(!validate("json(.unknown, inside(point{[0]}))"));
+        assert!(!validate("json([.id, {\"a\": .position.x}], inside(point{[0]}))"));
+        assert!(!validate("json(count(.unknown), inside(point{[0]}))"));
+
+        // Only numbers are aggregated, and selectors have to be within
+        // aggregations when the template has aggregations.
+        assert!(!validate("json(sum(.id), inside(point{[0]}))"));
+        assert!(!validate("json(min(.position), inside(point{[0]}))"));
+        assert!(!validate("json([count(.), .id], inside(point{[0]}))"));
+    }
+
+    #[test]
+    fn types() {
+        use crate::symbols::LiteralTypes;
+
+        // Equality is symmetric.
+        let types = vec![
+            LiteralTypes::String,
+            LiteralTypes::Int,
+            LiteralTypes::Float,
+            LiteralTypes::Vector(vec![LiteralTypes::Int, LiteralTypes::Float]),
+            LiteralTypes::Vector(vec![LiteralTypes::Float]),
+            LiteralTypes::Vector(vec![LiteralTypes::String]),
+            LiteralTypes::Coordinates,
+            LiteralTypes::Array(2, Box::new(LiteralTypes::Int)),
+            LiteralTypes::Object(vec![("a".to_string(), LiteralTypes::Float)]),
+        ];
+        for a in &types {
+            for b in &types {
+                assert_eq!(a == b, b == a, "{:?} vs {:?}", a, b);
+            }
+        }
+
+        // Numbers are compatible, and positions of unknown dimensions
+        // with any vector of numbers.
+        assert_eq!(LiteralTypes::Int, LiteralTypes::Float);
+        assert_eq!(LiteralTypes::Coordinates, types[3]);
+        assert_ne!(LiteralTypes::Coordinates, types[5]);
+    }
+
+    #[test]
+    fn shapes() {
+        assert!(validate("shape(hypersphere{[0, 0, 0], 1})"));
+
+        // Labels are only known from the objects.
+        assert!(validate("inside(label{\"a\"})"));
+        assert!(!validate("shape(label{\"a\"})"));
+    }
+
+    #[test]
+    fn spans() {
+        let query = "intersection(inside(point{[0]}),\n  inside(point{[0], \"a\"}))";
+        let error = queries::FiltersParser::new()
+            .parse(query)
+            .unwrap()
+            .validate()
+            .unwrap_err();
+
+        let span = error.span();
+        assert_eq!(&query[span.start..span.end], "inside(point{[0], \"a\"})");
+        assert_eq!(span.line_column(query), (2, 3));
+
+        let error = Error::from(
+            queries::FiltersParser::new()
+                .parse("inside(point{[0]]})")
+                .unwrap_err(),
+        );
+        assert_eq!((error.span().start, error.span().end), (16, 17));
+        assert!(matches!(error, Error::Parse { .. }));
+    }
+}
+
+#[cfg(test)]
+mod geometry {
+    use crate::symbols::LiteralPosition;
+    use crate::symbols::OrientedBox;
+
+    fn vertices(v: &[Vec<f64>]) -> Vec<LiteralPosition> {
+        v.iter().map(|p| p.into()).collect()
+    }
+
+    #[test]
+    fn oriented_box() {
+        let b = OrientedBox::new(&vertices(&[
+            vec![0.0, 0.0],
+            vec![1.0, 1.0],
+            vec![-1.0, 1.0],
+            vec![0.0, 2.0],
+        ]))
+        .unwrap();
+
+        assert!((b.volume() - 2.0).abs() < 1e-12);
+        assert_eq!(b.bounding_box(), (vec![-1.0, 0.0], vec![1.0, 2.0]));
+
+        assert!(b.contains(&[0.0, 1.0]));
+        assert!(b.contains_strictly(&[0.0, 1.0]));
+
+        // On the surface
+        assert!(b.contains(&[0.5, 0.5]));
+        assert!(!b.contains_strictly(&[0.5, 0.5]));
+
+        // Within the bounding box, but outside the box
+        assert!(!b.contains(&[0.9, 0.1]));
+        assert!(!b.contains(&[0.0, 1.0, 0.0]));
+
+        assert!(OrientedBox::new(&[]).is_err());
+        assert!(OrientedBox::new(&vertices(&[vec![0.0, 0.0], vec![1.0, 1.0], vec![1.0, 0.0]])).is_err());
+    }
+}
+
+#[cfg(test)]
+mod selectors {
+    use mercator_db::space;
+    use mercator_db::Properties;
+
+    use crate::symbols::*;
+
+    fn selector(fields: &[(&str, Option<usize>)]) -> LiteralSelector {
+        LiteralSelector(
+            fields
+                .iter()
+                .map(|(name, index)| Field(name.to_string(), *index))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn resolve() {
+        let space_id = "space".to_string();
+        let position: space::Position = LiteralPosition::from(vec![1.0, 2.0, 3.0]).into();
+        let properties = Properties::Feature("object".to_string());
+        let object = (&space_id, &position, &properties);
+
+        assert_eq!(selector(&[("id", None)]).str(object), Ok("object"));
+        assert_eq!(
+            selector(&[("properties", None), ("id", None)]).str(object),
+            Ok("object")
+        );
+        assert_eq!(
+            selector(&[("reference_space", None)]).str(object),
+            Ok("space")
+        );
+
+        assert!(selector(&[("", Some(1))]).position(object) == Ok(LiteralPosition::from(vec![2.0])));
+        assert!(selector(&[("position", Some(2))]).position(object) == Ok(LiteralPosition::from(vec![3.0])));
+        assert!(selector(&[("position", None)]).position(object) == Ok(LiteralPosition::from(vec![1.0, 2.0, 3.0])));
+
+        // Unknown fields, invalid indices and mismatched types are errors.
+        assert!(selector(&[("unknown", None)]).resolve(object).is_err());
+        assert!(selector(&[("properties", None), ("unknown", None)]).resolve(object).is_err());
+        assert!(selector(&[("position", Some(3))]).resolve(object).is_err());
+        assert!(selector(&[("id", None), ("type", None)]).resolve(object).is_err());
+        assert!(selector(&[("id", None)]).position(object).is_err());
+        assert!(selector(&[("position", None)]).str(object).is_err());
+    }
+
+    #[test]
+    fn str_cmp_ignore_case() {
+        let space_id = "space".to_string();
+        let position: space::Position = LiteralPosition::from(vec![0.0]).into();
+        let properties = Properties::Feature("Ärger".to_string());
+        let object = (&space_id, &position, &properties);
+
+        let cmp = |literal: &str| {
+            Position::StrCmpIgnoreCase(selector(&[("id", None)]), literal.to_string())
+                .value(object)
+                .unwrap()
+        };
+
+        assert!(cmp("äRGER") == LiteralPosition(vec![LiteralNumber::Int(0)]));
+        assert!(cmp("ÄRGERN") == LiteralPosition(vec![LiteralNumber::Int(-1)]));
+        assert!(cmp("ärge") == LiteralPosition(vec![LiteralNumber::Int(1)]));
+
+        let cmp = Position::StrCmp(selector(&[("id", None)]), "äRGER".to_string());
+        assert!(cmp.value(object).unwrap() != LiteralPosition(vec![LiteralNumber::Int(0)]));
+    }
+}
+
+#[cfg(test)]
+mod diagnostics {
+    use crate::queries;
+    use crate::Error;
+    use crate::Span;
+
+    fn render(query: &str) -> String {
+        let error = Error::from(queries::FiltersParser::new().parse(query).unwrap_err());
+        error.render(query)
+    }
+
+    #[test]
+    fn caret() {
+        let out = render("inside(point{[0]]})");
+
+        assert!(out.starts_with("error: unexpected token ']'\n"));
+        assert!(out.contains("1 | inside(point{[0]]})\n"));
+        assert!(out.contains(&format!("  | {}^\n", " ".repeat(16))));
+    }
+
+    #[test]
+    fn expected() {
+        let out = render("inside()");
+
+        assert!(out.contains("expected a shape: point, hyperrectangle, hypersphere, label or nifti"));
+    }
+
+    #[test]
+    fn suggestions() {
+        assert!(render("inside(hypersfere{[0], 1})").contains("did you mean `hypersphere`?"));
+        assert!(render("intersect(inside(point{[0]}), inside(point{[0]}))")
+            .contains("did you mean `intersection`?"));
+        assert!(!render("inside(point{[0]]})").contains("did you mean"));
+    }
+
+    #[test]
+    fn tokens() {
+        let span = |query: &str| match Error::from(
+            queries::FiltersParser::new().parse(query).unwrap_err(),
+        ) {
+            Error::Parse { span, .. } => span,
+            error => panic!("unexpected error {:?}", error),
+        };
+
+        assert_eq!(span("limit(1.5, inside(point{[0]}))"), Span::new(6, 9));
+        assert_eq!(span("inside(label{\"\\ud800\"})"), Span::new(13, 21));
+        let out = render("limit(1.5, inside(point{[0]}))");
+        assert!(out.contains(&format!("  | {}^^^", " ".repeat(6))));
+    }
+}
+
+#[cfg(test)]
+mod conformance {
+    use crate::queries;
+
+    // Cases of the corpus, as (expected to be accepted, query).
+    fn corpus(text: &str) -> Vec<(bool, String)> {
+        let mut cases: Vec<(bool, String)> = vec![];
+
+        for line in text.lines() {
+            if let Some(expectation) = line.strip_prefix("#> ") {
+                cases.push((expectation.trim() == "accept", String::new()));
+            } else if let Some((_, query)) = cases.last_mut() {
+                query.push_str(line);
+                query.push('\n');
+            }
+        }
+
+        cases
+    }
+
+    #[test]
+    fn filters() {
+        let p = queries::FiltersParser::new();
+        let cases = corpus(include_str!("../Grammars/conformance/filters.txt"));
+
+        assert!(!cases.is_empty());
+        for (accept, query) in cases {
+            assert_eq!(
+                p.parse(&query).is_ok(),
+                accept,
+                "filters.g4 {} the query:\n{}",
+                if accept { "accepts" } else { "rejects" },
+                query
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod printing {
+    use crate::queries;
+    use crate::Bag;
+    use crate::LiteralPosition;
+    use crate::LiteralSelector;
+    use crate::Position;
+    use crate::Predicate;
+    use crate::Shape;
+
+    // Cases accepted by the parser, from the conformance corpus.
+    fn filters() -> Vec<String> {
+        include_str!("../Grammars/conformance/filters.txt")
+            .split("\n#> ")
+            .skip(1)
+            .filter_map(|case| case.strip_prefix("accept\n"))
+            .map(|query| query.to_string())
+            .chain(
+                [
+                    "filter(=(str_cmp_ignore_case(.properties.type, \"A \\\"quoted\\\" \\\\ name\\n\"), [0]), inside(point{[0]}))",
+                    "filter(|(<(.[0], [-1.5]), >(., [1e-7, 2.0, -0.0])), inside(label{\"id\", \"space\"}))",
+                    "filter(inside(hyperrectangle{[0, 0], [1, 1], [-1, 1], [0, 2]}))",
+                    "filter(=(.id, [1]))",
+                    "inside(nifti{[1, 2, 3], [[1, 0, 0], [0, 1, 0], [0, 0, 1]], bytes(\"AAEC\"), \"space\"})",
+                    "complement(bag{distinct(inside(point{[0]})), shape(hypersphere{[0.25], 2.5, \"space\"})})",
+                ]
+                .iter()
+                .map(|query| query.to_string()),
+            )
+            .collect()
+    }
+
+    #[test]
+    fn bags() {
+        let p = queries::FiltersParser::new();
+
+        for query in filters() {
+            let ast = p.parse(&query).unwrap();
+
+            let text = ast.to_string();
+            assert!(p.parse(&text).unwrap().same_as(&ast), "{}", text);
+            // The text is canonical.
+            assert_eq!(p.parse(&text).unwrap().to_string(), text);
+
+            let text = format!("{:#}", ast);
+            assert!(p.parse(&text).unwrap().same_as(&ast), "{}", text);
+        }
+    }
+
+    #[test]
+    fn projections() {
+        let p = queries::QueryParser::new();
+
+        for query in &[
+            "nifti(inside(point{[0]}))",
+            "nifti(.position[0], inside(point{[0]}), \"space\")",
+            "json({\"a\": [1, -2.5, true, false, null, \"s\"], \"b\": .id}, inside(point{[0]}))",
+            "json([count(distinct .id), count(.id), sum(.[0]), min(.[1]), max(.[2])], union(inside(point{[0]}), outside(point{[1]})), \"space\")",
+        ] {
+            let ast = p.parse(query).unwrap().unwrap();
+
+            let text = ast.to_string();
+            assert_eq!(&text, query);
+            assert!(p.parse(&text).unwrap().unwrap().same_as(&ast));
+
+            let text = format!("{:#}", ast);
+            assert!(p.parse(&text).unwrap().unwrap().same_as(&ast), "{}", text);
+        }
+    }
+
+    #[test]
+    fn canonical() {
+        let p = queries::FiltersParser::new();
+        let ast = p
+            .parse("  union( inside(point{[0],\"space\"}) ,/* c */outside(point{[ 1.50 ]}))")
+            .unwrap();
+
+        assert_eq!(
+            ast.to_string(),
+            "union(inside(point{[0], \"space\"}), outside(point{[1.5]}))"
+        );
+        assert_eq!(
+            format!("{:#}", ast),
+            "union(\n    inside(point{[0], \"space\"}),\n    outside(point{[1.5]})\n)"
+        );
+
+        // Numbers which cannot be printed back are rejected.
+        assert!(p.parse("inside(point{[1e300]})").is_ok());
+        assert!(p.parse("inside(point{[1e400]})").is_err());
+        assert!(p.parse("inside(point{[-1e400]})").is_err());
+
+        // Spans are part of the expressions, which are the same
+        // nonetheless.
+        let canonical = p.parse(&ast.to_string()).unwrap();
+        assert_ne!(canonical, ast);
+        assert!(canonical.same_as(&ast));
+    }
+
+    // Deterministic pseudo-random generator of expressions, built with
+    // the builders instead of being parsed.
+    struct Generator(u64);
+
+    impl Generator {
+        fn next(&mut self) -> u64 {
+            self.0 = self
+                .0
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            self.0 >> 11
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+
+        fn float(&mut self) -> f64 {
+            match self.below(4) {
+                0 => -0.0,
+                1 => (self.below(2001) as f64 - 1000.0) / 8.0,
+                _ => loop {
+                    let x = f64::from_bits(self.next() ^ (self.next() << 53));
+                    if x.is_finite() {
+                        break x;
+                    }
+                },
+            }
+        }
+
+        fn position(&mut self) -> LiteralPosition {
+            let k = 1 + self.below(3) as usize;
+            if self.below(2) == 0 {
+                (0..k)
+                    .map(|_| self.next() as i64 - (1 << 52))
+                    .collect::<Vec<_>>()
+                    .into()
+            } else {
+                (0..k).map(|_| self.float()).collect::<Vec<_>>().into()
+            }
+        }
+
+        fn string(&mut self) -> String {
+            let chars: Vec<_> = "aZ0 \"\\\n\t\u{1}\u{e9}\u{1f600}".chars().collect();
+            (0..self.below(6))
+                .map(|_| chars[self.below(chars.len() as u64) as usize])
+                .collect()
+        }
+
+        fn selector(&mut self) -> LiteralSelector {
+            let mut selector = LiteralSelector::root();
+            for _ in 0..self.below(3) {
+                selector = match self.below(2) {
+                    0 => selector.field(["id", "properties", "type"][self.below(3) as usize]),
+                    _ => selector.index(self.below(4) as usize),
+                };
+            }
+            selector
+        }
+
+        fn shape(&mut self) -> Shape {
+            let shape = match self.below(4) {
+                0 => Shape::point(self.position()),
+                1 => Shape::hyperrectangle(self.position(), self.position()),
+                2 => Shape::sphere(self.position(), self.float()),
+                _ => Shape::label(&self.string()),
+            };
+            match self.below(2) {
+                0 => shape,
+                _ => shape.in_space(&self.string()),
+            }
+        }
+
+        fn predicate(&mut self, depth: usize) -> Predicate {
+            let position = match self.below(3) {
+                0 => Position::from(self.selector()),
+                1 => Position::str_cmp(self.selector(), &self.string()),
+                _ => Position::str_cmp_ignore_case(self.selector(), &self.string()),
+            };
+            match self.below(if depth == 0 { 3 } else { 6 }) {
+                0 => Predicate::less(position, self.position()),
+                1 => Predicate::greater(position, self.position()),
+                2 => Predicate::equal(position, self.position()),
+                3 => !self.predicate(depth - 1),
+                4 => self.predicate(depth - 1).and(self.predicate(depth - 1)),
+                _ => self.predicate(depth - 1).or(self.predicate(depth - 1)),
+            }
+        }
+
+        fn bag(&mut self, depth: usize) -> Bag {
+            match self.below(if depth == 0 { 3 } else { 12 }) {
+                0 => Bag::inside(self.shape()),
+                1 => Bag::outside(self.shape()),
+                2 => Bag::shape(self.shape()),
+                3 => {
+                    let n = 1 + self.below(3);
+                    Bag::bag((0..n).map(|_| self.bag(depth - 1)).collect())
+                }
+                4 => self.bag(depth - 1).distinct(),
+                5 => self.bag(depth - 1).limit(self.below(100) as usize),
+                6 => self.bag(depth - 1).offset(self.below(100) as usize),
+                7 => self.bag(depth - 1).complement(),
+                8 => self.bag(depth - 1).intersection(self.bag(depth - 1)),
+                9 => self.bag(depth - 1).union(self.bag(depth - 1)),
+                _ => self.bag(depth - 1).filter(self.predicate(2)),
+            }
+        }
+    }
+
+    #[test]
+    fn generated() {
+        let p = queries::FiltersParser::new();
+        let mut generator = Generator(42);
+
+        for _ in 0..1000 {
+            let ast = generator.bag(4);
+
+            let text = ast.to_string();
+            assert!(p.parse(&text).unwrap().same_as(&ast), "{}", text);
+
+            let text = format!("{:#}", ast);
+            assert!(p.parse(&text).unwrap().same_as(&ast), "{}", text);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid number 'NaN'")]
+    fn non_finite() {
+        Shape::sphere(vec![0.0], f64::NAN);
+    }
+
+    #[test]
+    fn escapes() {
+        let p = queries::FiltersParser::new();
+        let label = |query: &str| match p.parse(query) {
+            Ok(Bag::Inside(Shape::Label(_, id), _)) => Ok(id),
+            Ok(bag) => panic!("unexpected expression {}", bag),
+            Err(_) => Err(()),
+        };
+
+        assert_eq!(
+            label(r#"inside(label{"a\u00e9\n"})"#),
+            Ok("a\u{e9}\n".to_string())
+        );
+        // Surrogate pairs stand for a single character.
+        assert_eq!(
+            label(r#"inside(label{"\ud83d\ude00"})"#),
+            Ok("\u{1f600}".to_string())
+        );
+        assert_eq!(
+            label(r#"inside(label{"\uD83D\uDE00"})"#),
+            Ok("\u{1f600}".to_string())
+        );
+
+        // Unpaired surrogates.
+        assert!(label(r#"inside(label{"\ud83d"})"#).is_err());
+        assert!(label(r#"inside(label{"\ud83dx"})"#).is_err());
+        assert!(label(r#"inside(label{"\ud83d\u0041"})"#).is_err());
+        assert!(label(r#"inside(label{"\ude00"})"#).is_err());
+    }
+}
+
+#[cfg(test)]
+mod builders {
+    use std::convert::TryFrom;
+
+    use crate::queries;
+    use crate::*;
+
+    // Built expressions have no spans, unlike the parsed ones.
+    fn assert_same(bag: &Bag, query: &str) {
+        let parsed = queries::FiltersParser::new().parse(query).unwrap();
+        assert!(bag.same_as(&parsed), "{} is not {}", bag, parsed);
+    }
+
+    fn assert_same_projection(projection: &Projection, query: &str) {
+        let parsed = queries::QueryParser::new().parse(query).unwrap().unwrap();
+        assert!(
+            projection.same_as(&parsed),
+            "{} is not {}",
+            projection,
+            parsed
+        );
+    }
+
+    #[test]
+    fn bags() {
+        assert_same(
+            &Bag::inside(Shape::sphere(vec![0.0, 1.5], 2.0).in_space("s")),
+            "inside(hypersphere{[0.0, 1.5], 2.0, \"s\"})",
+        );
+        assert_same(
+            &Bag::inside(Shape::point(vec![0]))
+                .union(Bag::outside(Shape::hyperrectangle(vec![0, 0], vec![1, 1])))
+                .intersection(Bag::shape(Shape::label("id")).complement())
+                .distinct(),
+            "distinct(intersection(union(inside(point{[0]}), outside(hyperrectangle{[0, 0], [1, 1]})), complement(shape(label{\"id\"}))))",
+        );
+        assert_same(
+            &Bag::bag(vec![
+                Bag::inside(Shape::nifti(ByteProvider::Uri("file:///a.nii".to_string()))),
+                Bag::inside(Shape::point(vec![0])),
+            ]),
+            "bag{inside(nifti{uri(\"file:///a.nii\")}), inside(point{[0]})}",
+        );
+    }
+
+    #[test]
+    fn predicates() {
+        let id = LiteralSelector::root().field("properties").field("id");
+        let predicate = Predicate::less(LiteralSelector::root().index(0), vec![1])
+            .and(!Predicate::equal(Position::str_cmp(id, "a \"b\""), vec![0]))
+            .or(Predicate::greater(vec![1.0], vec![0.5]));
+
+        let bag = Bag::inside(Shape::point(vec![0])).filter(predicate);
+        assert_same(
+            &bag,
+            "filter(|(&(<(.[0], [1]), !(=(str_cmp(.properties.id, \"a \\\"b\\\"\"), [0]))), >([1.0], [0.5])), inside(point{[0]}))",
+        );
+
+        // Strings are escaped when printed.
+        assert_same(&bag, &bag.to_string());
+    }
+
+    #[test]
+    fn fields() {
+        let selector = LiteralSelector::root().field("_a1").field("B");
+        assert_eq!(selector.to_string(), "._a1.B");
+
+        // Fields which cannot be written in a query are refused.
+        for name in ["", "1a", "a b", "a.b", "a\"", "é"] {
+            let root = LiteralSelector::root();
+            assert!(root.clone().try_field(name).is_err(), "{:?}", name);
+            let field = std::panic::catch_unwind(|| root.field(name));
+            assert!(field.is_err(), "{:?}", name);
+        }
+        let id = LiteralSelector::root().try_field("id");
+        assert_eq!(id, Ok(LiteralSelector::root().field("id")));
+    }
+
+    #[test]
+    fn oriented_hyperrectangles() {
+        let square = vec![vec![0, 0], vec![1, 1], vec![-1, 1], vec![0, 2]];
+        let shape = Shape::oriented_hyperrectangle(square).unwrap();
+        assert_same(
+            &Bag::inside(shape),
+            "inside(hyperrectangle{[0, 0], [1, 1], [-1, 1], [0, 2]})",
+        );
+
+        // Only hyperrectangles can be built.
+        let empty: Vec<Vec<i64>> = vec![];
+        assert!(Shape::oriented_hyperrectangle(empty).is_err());
+        let parallelogram = vec![vec![0, 0], vec![1, 0], vec![1, 1], vec![2, 1]];
+        assert!(Shape::oriented_hyperrectangle(parallelogram).is_err());
+        let triangle = vec![vec![0, 0], vec![1, 0], vec![0, 1]];
+        assert!(Shape::oriented_hyperrectangle(triangle).is_err());
+    }
+
+    #[test]
+    fn projections() {
+        let bag = Bag::inside(Shape::point(vec![0]));
+
+        assert_same_projection(
+            &Projection::nifti(None, bag.clone()),
+            "nifti(inside(point{[0]}))",
+        );
+        assert_same_projection(
+            &Projection::json(
+                JsonValue::try_from(serde_json::json!({ "a": [1, 2.5, null], "b": "c" })).unwrap(),
+                bag.clone(),
+            )
+            .in_space("s"),
+            "json({\"a\": [1, 2.5, null], \"b\": \"c\"}, inside(point{[0]}), \"s\")",
+        );
+        assert_same_projection(
+            &Projection::json(
+                Aggregation::count_distinct(LiteralSelector::root().field("id")),
+                bag,
+            ),
+            "json(count(distinct .id), inside(point{[0]}))",
+        );
+    }
+}
+
+#[cfg(test)]
+mod optimizer {
+    use crate::queries;
+    use crate::*;
+
+    fn filters(query: &str) -> Bag {
+        queries::FiltersParser::new().parse(query).unwrap()
+    }
+
+    fn optimized(query: &str) -> String {
+        filters(query).optimize().to_string()
+    }
+
+    #[test]
+    fn bags() {
+        let x = "inside(point{[0]})";
+        let y = "inside(hypersphere{[0], 1})";
+
+        // Not equivalent, see the executor.
+        let complements = format!("complement(complement({}))", x);
+        assert_eq!(optimized(&complements), complements);
+        assert_eq!(
+            optimized(&format!("distinct(distinct({}))", x)),
+            format!("distinct({})", x)
+        );
+        assert_eq!(optimized(&format!("intersection({0}, {0})", x)), x);
+        assert_eq!(
+            optimized(&format!("distinct(union({0}, {0}))", x)),
+            format!("distinct({})", x)
+        );
+        assert_eq!(
+            optimized(&format!("bag{{{0}, bag{{{1}, bag{{{0}}}}}}}", x, y)),
+            format!("bag{{{0}, {1}, {0}}}", x, y)
+        );
+
+        // Duplicates are kept without distinct.
+        let union = format!("union({0}, {0})", x);
+        assert_eq!(optimized(&union), union);
+    }
+
+    #[test]
+    fn filters_chains() {
+        let x = "inside(point{[0]})";
+        let p = "<(.[0], [1])";
+        let q = ">(.[0], [0])";
+
+        assert_eq!(optimized(&format!("filter({})", x)), x);
+        assert_eq!(
+            optimized(&format!("filter({}, filter({}, {}))", p, q, x)),
+            format!("filter(&({}, {}), {})", q, p, x)
+        );
+
+        // Constant predicates.
+        assert_eq!(optimized(&format!("filter(<([0], [1]), {})", x)), x);
+        assert_eq!(
+            optimized(&format!("filter(&(>([2], [1]), {}), {})", p, x)),
+            format!("filter({}, {})", p, x)
+        );
+    }
+
+    #[test]
+    fn push_down() {
+        let x = "inside(point{[0]})";
+        let y = "inside(hypersphere{[0], 1})";
+        let p = "<(.[0], [1])";
+
+        assert_eq!(
+            optimized(&format!("filter({}, union({}, {}))", p, x, y)),
+            format!("union(filter({0}, {1}), filter({0}, {2}))", p, x, y)
+        );
+        assert_eq!(
+            optimized(&format!("filter({}, bag{{{}, {}}})", p, x, y)),
+            format!("bag{{filter({0}, {1}), filter({0}, {2})}}", p, x, y)
+        );
+        assert_eq!(
+            optimized(&format!("filter({}, distinct({}))", p, x)),
+            format!("distinct(filter({}, {}))", p, x)
+        );
+
+        // Intersections keep the objects of either side.
+        let intersection = format!("filter({}, intersection({}, {}))", p, x, y);
+        assert_eq!(optimized(&intersection), intersection);
+
+        // Predicates are not merged into the shapes.
+        let inside = format!("filter({}, inside(hyperrectangle{{[0], [2]}}))", p);
+        assert_eq!(optimized(&inside), inside);
+    }
+
+    #[test]
+    fn hyperrectangles() {
+        assert_eq!(
+            optimized(
+                "intersection(inside(hyperrectangle{[0, 0], [2, 2]}), inside(hyperrectangle{[3, 1], [1, 3]}))"
+            ),
+            "inside(hyperrectangle{[1, 1], [2, 2]})"
+        );
+        assert_eq!(
+            optimized(
+                "distinct(union(inside(hyperrectangle{[1, 1], [2, 2]}), inside(hyperrectangle{[0, 0], [3, 3]})))"
+            ),
+            "distinct(inside(hyperrectangle{[0, 0], [3, 3]}))"
+        );
+
+        // Disjoint, or in different spaces.
+        for query in &[
+            "intersection(inside(hyperrectangle{[0, 0], [1, 1]}), inside(hyperrectangle{[2, 2], [3, 3]}))",
+            "intersection(inside(hyperrectangle{[0, 0], [2, 2]}), inside(hyperrectangle{[1, 1], [3, 3], \"s\"}))",
+        ] {
+            assert_eq!(&optimized(query), query);
+        }
+    }
+
+    #[test]
+    fn predicates() {
+        let x = "inside(point{[0]})";
+        let p = "<(.[0], [1])";
+        let q = ">(.[0], [0])";
+
+        assert_eq!(
+            optimized(&format!("filter(!(!({})), {})", p, x)),
+            format!("filter({}, {})", p, x)
+        );
+        assert_eq!(
+            optimized(&format!("filter(!(&(!({}), !({}))), {})", p, q, x)),
+            format!("filter(|({}, {}), {})", p, q, x)
+        );
+        assert_eq!(
+            optimized(&format!("filter(&(!({}), !({})), {})", p, q, x)),
+            format!("filter(!(|({}, {})), {})", p, q, x)
+        );
+        assert_eq!(
+            optimized(&format!("filter(|({0}, {0}), {1})", p, x)),
+            format!("filter({}, {})", p, x)
+        );
+    }
+
+    #[test]
+    fn projections() {
+        let p = queries::QueryParser::new();
+        let query = p
+            .parse("json(count(.), intersection(inside(point{[0]}), inside(point{[0]})))")
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            query.optimize().to_string(),
+            "json(count(.), inside(point{[0]}))"
+        );
+    }
+}
+
+#[cfg(test)]
+mod explain {
+    use crate::*;
+
+    fn plan(operation: &str, prediction: f64, calls: &[&str], operands: Vec<Plan>) -> Plan {
+        Plan {
+            operation: operation.to_string(),
+            prediction: Prediction::exact(prediction),
+            estimate: None,
+            strategy: None,
+            calls: calls.iter().map(|c| c.to_string()).collect(),
+            operands,
+        }
+    }
+
+    #[test]
+    fn display() {
+        let mut intersection = plan(
+            "intersection",
+            1.0,
+            &[],
+            vec![
+                plan(
+                    "inside(point{[0]})",
+                    f64::EPSILON,
+                    &["get_by_shape(Point, \"universe\")"],
+                    vec![],
+                ),
+                plan(
+                    "inside(label{\"id\"})",
+                    1234.0,
+                    &["get_by_label(\"id\")"],
+                    vec![],
+                ),
+            ],
+        );
+        intersection.strategy = Some("hash left".to_string());
+
+        assert_eq!(
+            plan("distinct", 1.0, &[], vec![intersection]).to_string(),
+            "distinct [predicted: 1.000e0]
+    intersection [predicted: 1.000e0] [hash left]
+        inside(point{[0]}) [predicted: 2.220e-16]
+            -> get_by_shape(Point, \"universe\")
+        inside(label{\"id\"}) [predicted: 1.234e3]
+            -> get_by_label(\"id\")"
+        );
+    }
+
+    #[test]
+    fn statistics() {
+        use std::time::Duration;
+
+        let inside = Statistics {
+            operation: "inside(point{[0]})".to_string(),
+            time: Duration::from_micros(1500),
+            objects: vec![("a".to_string(), 2), ("b".to_string(), 1)],
+            lookups: 0,
+            operands: vec![],
+        };
+        let complement = Statistics {
+            operation: "complement".to_string(),
+            time: Duration::from_millis(2),
+            objects: vec![],
+            lookups: 3,
+            operands: vec![inside],
+        };
+
+        assert_eq!(complement.operands[0].count(), 3);
+        assert_eq!(
+            complement.to_string(),
+            "complement [time: 2ms, objects: 0, lookups: 3]
+    inside(point{[0]}) [time: 1.5ms, objects: 3 (\"a\": 2, \"b\": 1)]"
+        );
+    }
+
+    #[test]
+    fn ranges() {
+        let prediction = Prediction::new(3.0, 1.0);
+        assert_eq!(prediction, Prediction { min: 1.0, max: 3.0 });
+        assert_eq!(prediction.value(), 2.0);
+        assert_eq!(prediction.to_string(), "1.000e0..3.000e0");
+        assert_eq!(Prediction::exact(2.0).to_string(), "2.000e0");
+    }
+}
+
+#[cfg(test)]
+mod estimation {
+    use crate::*;
+
+    fn rectangle(low: f64, high: f64) -> Bag {
+        Bag::inside(Shape::hyperrectangle(vec![low, low], vec![high, high]).in_space("s"))
+    }
+
+    // Objects clustered in a corner of the space, and one far away.
+    fn histograms() -> Histograms {
+        let mut histogram = Histogram::new(vec![0.0, 0.0], vec![64.0, 64.0]);
+        for _ in 0..100 {
+            histogram.add(&[1.5, 1.5]);
+        }
+        histogram.add(&[50.5, 50.5]);
+
+        let mut histograms = Histograms::default();
+        histograms.insert("s", histogram);
+        histograms
+    }
+
+    #[test]
+    fn histogram() {
+        let mut histogram = Histogram::new(vec![0.0], vec![4096.0]);
+        for _ in 0..10 {
+            histogram.add(&[0.5]);
+        }
+        histogram.add(&[100.5]);
+        // Outside of the space, counted in the last bin.
+        histogram.add(&[5000.0]);
+
+        assert_eq!(histogram.total(), 12);
+        assert_eq!(histogram.count(&[0.0], &[1.0]), 10.0);
+        assert_eq!(histogram.count(&[0.0], &[0.5]), 5.0);
+        assert_eq!(histogram.count(&[0.0], &[4096.0]), 12.0);
+        assert_eq!(histogram.density(&[0.5]), 10.0);
+    }
+
+    #[test]
+    fn bags() {
+        let h = Catalog::default().with_histograms(histograms());
+        let near = rectangle(0.0, 10.0);
+        let far = rectangle(40.0, 60.0);
+
+        // The volumes would order them the other way around.
+        assert_eq!(near.estimate(&h), Ok(100.0));
+        assert_eq!(far.estimate(&h), Ok(1.0));
+
+        assert_eq!(near.clone().complement().estimate(&h), Ok(1.0));
+        assert_eq!(near.clone().union(far.clone()).estimate(&h), Ok(101.0));
+        assert_eq!(near.clone().intersection(far).estimate(&h), Ok(1.0));
+        assert_eq!(
+            near.filter(Predicate::equal(
+                LiteralSelector::root().index(0),
+                vec![1.5]
+            ))
+            .estimate(&h),
+            Ok(10.0)
+        );
+
+        // No histogram for the universe.
+        assert!(Bag::inside(Shape::point(vec![0.0, 0.0]))
+            .estimate(&h)
+            .is_err());
+    }
+
+    #[test]
+    fn shapes() {
+        let h = Catalog::default().with_histograms(histograms());
+
+        // Objects of the bin of the point, not a density.
+        let point = |x: f64, y: f64| Shape::point(vec![x, y]).in_space("s");
+        assert_eq!(Bag::inside(point(1.2, 1.7)).estimate(&h), Ok(100.0));
+        assert_eq!(Bag::inside(point(30.0, 30.0)).estimate(&h), Ok(0.0));
+
+        // Positions generated from the shapes, not their volumes.
+        let shape = |low: Vec<f64>, high: Vec<f64>| {
+            Bag::shape(Shape::hyperrectangle(low, high).in_space("s"))
+        };
+        assert_eq!(Bag::shape(point(1.5, 1.5)).estimate(&h), Ok(1.0));
+        assert_eq!(shape(vec![0.0, 0.0], vec![2.0, 3.0]).estimate(&h), Ok(12.0));
+        assert_eq!(shape(vec![0.5, 0.0], vec![1.5, 0.0]).estimate(&h), Ok(1.0));
+
+        // On the grid of the voxels of the space.
+        let mut histograms = Histograms::default();
+        let histogram = Histogram::new(vec![0.0, 0.0], vec![64.0, 64.0]);
+        histograms.insert("s", histogram.with_voxel(vec![0.5, 0.5]));
+        let h = Catalog::default().with_histograms(histograms);
+        assert_eq!(shape(vec![0.0, 0.0], vec![2.0, 3.0]).estimate(&h), Ok(35.0));
+    }
+
+    #[test]
+    fn catalog() {
+        assert_eq!(Catalog::default().histograms(), None);
+
+        let catalog = Catalog::default().with_histograms(histograms());
+        assert_eq!(catalog.histograms(), Some(&histograms()));
+    }
+
+    #[test]
+    fn labels() {
+        let extent = LabelExtent {
+            low: vec![0.0, 1.0],
+            high: vec![2.0, 4.0],
+            positions: 5,
+            objects: 7,
+        };
+
+        assert_eq!(extent.volume(), 6.0);
+        assert_eq!(LabelExtent::default().volume(), f64::EPSILON);
+
+        let mut catalog = Catalog::default().with_histograms(histograms());
+        let label = Bag::inside(Shape::label("id").in_space("s"));
+        // Without its extent, a fraction of all the objects.
+        assert_eq!(catalog.label("id"), None);
+        assert_eq!(label.estimate(&catalog), Ok(101.0 * 0.01));
+
+        catalog.insert_label("id", extent.clone());
+        assert_eq!(catalog.label("id"), Some(&extent));
+        assert_eq!(label.estimate(&catalog), Ok(7.0));
+
+        // Only the latest extents are kept.
+        for i in 0..2000 {
+            catalog.insert_label(&i.to_string(), LabelExtent::default());
+        }
+        assert_eq!(catalog.label("id"), None);
+        assert_eq!(catalog.label("1999"), Some(&LabelExtent::default()));
+    }
+}
+
+#[cfg(test)]
+mod paging {
+    use crate::queries;
+    use crate::*;
+
+    fn filters(query: &str) -> Bag {
+        queries::FiltersParser::new().parse(query).unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        let p = queries::FiltersParser::new();
+        let x = Bag::inside(Shape::point(vec![0]));
+
+        let parsed = filters("limit(10, offset(20, inside(point{[0]})))");
+        assert!(parsed.same_as(&x.offset(20).limit(10)));
+        assert!(p.parse("limit(0, inside(point{[0]}))").is_ok());
+        assert!(p.parse("limit(1.5, inside(point{[0]}))").is_err());
+        assert!(p.parse("limit(-1, inside(point{[0]}))").is_err());
+        assert!(p.parse("offset(inside(point{[0]}))").is_err());
+    }
+
+    #[test]
+    fn optimize() {
+        let x = "inside(point{[0]})";
+
+        assert_eq!(
+            filters(&format!("limit(10, limit(5, {}))", x))
+                .optimize()
+                .to_string(),
+            format!("limit(5, {})", x)
+        );
+        assert_eq!(
+            filters(&format!("offset(10, offset(5, {}))", x))
+                .optimize()
+                .to_string(),
+            format!("offset(15, {})", x)
+        );
+        // Filters are not moved across the paging.
+        let query = format!("filter(=(.id, [1]), limit(5, {}))", x);
+        assert_eq!(filters(&query).optimize().to_string(), query);
+    }
+
+    #[test]
+    fn cursors() {
+        let query = filters("inside(point{[0]})");
+        let other = filters("inside(point{[1]})");
+
+        let cursor = Cursor::start(&query).advance(100).advance(50);
+        assert_eq!(cursor.offset, 150);
+
+        let token = cursor.token();
+        assert_eq!(Cursor::resume(&query, &token), Ok(cursor.clone()));
+        assert!(Cursor::resume(&other, &token).is_err());
+        assert!(Cursor::resume(&query, "not a token").is_err());
+        assert!(Cursor::resume(&query, &token[..16]).is_err());
+
+        // The optimized query is another query, when it is rewritten.
+        let rewritten = filters("limit(10, limit(5, inside(point{[0]})))");
+        let token = Cursor::start(&rewritten.clone().optimize()).token();
+        assert!(Cursor::resume(&rewritten, &token).is_err());
+
+        assert_eq!(
+            query.page(&cursor, 10).to_string(),
+            "limit(10, offset(150, inside(point{[0]})))"
+        );
+    }
+}
+
+#[cfg(test)]
+mod execution {
+    use std::collections::HashSet;
+    use std::convert::TryFrom;
+
+    use mercator_db::space;
+    use mercator_db::CoreQueryParameters;
+    use mercator_db::DataBase;
+
+    use crate::*;
+
+    // Core shipped with the repository, whose objects are in the space
+    // "std".
+    const CORE: &str = "10k";
+    const SPACE: &str = "std";
+
+    fn database() -> DataBase {
+        DataBase::load(&[&format!("{}.index", CORE)]).unwrap()
+    }
+
+    fn parameters(db: &DataBase) -> CoreQueryParameters {
+        CoreQueryParameters {
+            db,
+            output_space: None,
+            threshold_volume: None,
+            view_port: &None,
+            resolution: &None,
+        }
+    }
+
+    // Number of objects of the bag.
+    fn count(bag: &Bag, parameters: &CoreQueryParameters) -> usize {
+        bag.execute(CORE, parameters)
+            .unwrap()
+            .into_iter()
+            .map(|(_, objects)| objects.count())
+            .sum()
+    }
+
+    #[test]
+    fn filter() {
+        let db = database();
+        let parameters = parameters(&db);
+
+        // Every object, except the ones at the origin.
+        let objects = Bag::outside(Shape::point(vec![0.0, 0.0, 0.0]).in_space(SPACE));
+        assert!(count(&objects, &parameters) > 0);
+
+        // The positions do not have an eighth coordinate, which is
+        // reported instead of excluding all the objects.
+        let seventh = Predicate::less(LiteralSelector::root().index(7), vec![0.0]);
+        let invalid = objects.clone().filter(seventh.clone());
+        assert!(invalid.execute(CORE, &parameters).is_err());
+
+        // Also when the comparison is not needed for the first object.
+        let never = Predicate::greater(LiteralSelector::root().index(0), vec![f64::MAX]);
+        let invalid = objects.clone().filter(never.and(seventh));
+        assert!(invalid.execute(CORE, &parameters).is_err());
+
+        // Whether a selector resolves does not depend on the object, so
+        // checking the first one is enough.
+        let selectors = ["id", "type", "position", "unknown"]
+            .iter()
+            .map(|name| LiteralSelector::root().field(name))
+            .chain((0..4).map(|i| LiteralSelector::root().index(i)))
+            .collect::<Vec<_>>();
+        for (space_id, objects) in objects.execute(CORE, &parameters).unwrap() {
+            let mut resolved = None;
+            for (position, properties) in objects {
+                let ok = selectors
+                    .iter()
+                    .map(|s| s.resolve((space_id, &position, properties)).is_ok())
+                    .collect::<Vec<_>>();
+                assert_eq!(resolved.get_or_insert_with(|| ok.clone()), &ok);
+            }
+        }
+    }
+
+    // Positions of the objects of the bag.
+    fn positions(bag: &Bag, parameters: &CoreQueryParameters) -> HashSet<space::Position> {
+        bag.execute(CORE, parameters)
+            .unwrap()
+            .into_iter()
+            .flat_map(|(_, objects)| objects.map(|(position, _)| position))
+            .collect()
+    }
+
+    // Position of one of the objects, in the space of the objects.
+    fn center(db: &DataBase) -> Vec<f64> {
+        let parameters = CoreQueryParameters {
+            output_space: Some(SPACE),
+            ..parameters(db)
+        };
+        let objects = Bag::outside(Shape::point(vec![0.0, 0.0, 0.0]).in_space(SPACE));
+        let position = positions(&objects, &parameters).into_iter().next().unwrap();
+
+        Vec::<f64>::from(&position)
+    }
+
+    #[test]
+    fn complement() {
+        let db = database();
+        let universe = space::Space::universe().name();
+
+        for output_space in [None, Some(SPACE), Some(universe.as_str())] {
+            let parameters = CoreQueryParameters {
+                output_space,
+                ..parameters(&db)
+            };
+
+            let inside = Bag::inside(Shape::sphere(center(&db), 1.0).in_space(SPACE));
+
+            // The shape is tested directly, instead of hashing the
+            // positions of the operand, which has to give the same
+            // objects, whatever the output space.
+            let tested = positions(&inside.clone().complement(), &parameters);
+            let hashed = positions(&inside.clone().distinct().complement(), &parameters);
+            assert_eq!(tested, hashed);
+            assert!(tested.is_disjoint(&positions(&inside, &parameters)));
+        }
+    }
+
+    #[test]
+    fn bounding_box() {
+        let db = database();
+        let universe = space::Space::universe().name();
+
+        // Box rotated by 45 degrees around the last axis, around one of
+        // the objects. Its bounding box is queried, and the objects
+        // within it are tested against the box itself.
+        let c = center(&db);
+        let v = |x: f64, y: f64, z: f64| vec![c[0] + x, c[1] + y, c[2] + z];
+        let vertices = vec![
+            v(-1.0, 0.0, -1.0),
+            v(0.0, -1.0, -1.0),
+            v(0.0, 1.0, -1.0),
+            v(1.0, 0.0, -1.0),
+            v(-1.0, 0.0, 1.0),
+            v(0.0, -1.0, 1.0),
+            v(0.0, 1.0, 1.0),
+            v(1.0, 0.0, 1.0),
+        ];
+        let shape = Shape::oriented_hyperrectangle(vertices).unwrap();
+        let inside = Bag::inside(shape.in_space(SPACE));
+
+        // The same objects are found, whatever the output space.
+        let expected = count(&inside, &parameters(&db));
+        assert!(expected > 0);
+        for output_space in [Some(SPACE), Some(universe.as_str())] {
+            let parameters = CoreQueryParameters {
+                output_space,
+                ..parameters(&db)
+            };
+            assert_eq!(count(&inside, &parameters), expected);
+        }
+    }
+
+    #[test]
+    fn catalog() {
+        let db = database();
+        let parameters = parameters(&db);
+
+        let histograms = Histograms::build(CORE, &parameters, &[SPACE]).unwrap();
+        let catalog = Catalog::default().with_histograms(histograms);
+
+        // The catalog changes how the intersection is computed, not its
+        // results.
+        let c = center(&db);
+        let near = Bag::inside(Shape::sphere(c.clone(), 1.0).in_space(SPACE));
+        let far = Bag::outside(Shape::sphere(c, 0.5).in_space(SPACE));
+        let both = near.intersection(far);
+
+        let expected = positions(&both, &parameters);
+        let results = both
+            .execute_with(CORE, &parameters, &catalog)
+            .unwrap()
+            .into_iter()
+            .flat_map(|(_, objects)| objects.map(|(position, _)| position))
+            .collect::<HashSet<_>>();
+        assert_eq!(results, expected);
+    }
+
+    #[test]
+    fn labels() {
+        let db = database();
+        let parameters = parameters(&db);
+
+        // The label of one of the objects.
+        let near = Bag::inside(Shape::sphere(center(&db), 1.0).in_space(SPACE));
+        let id = near
+            .execute(CORE, &parameters)
+            .unwrap()
+            .into_iter()
+            .flat_map(|(_, objects)| objects.map(|(_, properties)| properties.id().to_string()))
+            .next()
+            .unwrap();
+        let label = Bag::inside(Shape::label(&id).in_space(SPACE));
+
+        // The extent of the label is looked up, without filling a catalog.
+        let extent = LabelExtent::lookup(CORE, &parameters, &id).unwrap();
+        let plan = label.explain(CORE, &parameters).unwrap();
+        assert_eq!(plan.prediction, Prediction::exact(extent.volume()));
+
+        // A box of less than half of the space, which would be hashed if
+        // the label could cover the whole space.
+        let (low, high) = db.space(SPACE).unwrap().bounding_box();
+        let (low, high) = (Vec::<f64>::from(&low), Vec::<f64>::from(&high));
+        let at = |t: f64| -> Vec<f64> {
+            let position = low.iter().zip(&high).map(|(l, h)| l + t * (h - l));
+            position.collect()
+        };
+        let wide = Bag::inside(Shape::hyperrectangle(at(0.1), at(0.85)).in_space(SPACE));
+        let n = count(&wide, &parameters);
+        assert!(n > count(&label, &parameters));
+
+        // The smaller label is hashed, and probed by the objects of the
+        // box.
+        let query = label.intersection(wide);
+        let plan = query.explain(CORE, &parameters).unwrap();
+        let strategy = plan.strategy.unwrap();
+        assert!(strategy.starts_with("hash the positions of the left"));
+        let (results, statistics) = query.analyze(CORE, &parameters).unwrap();
+        assert_eq!(statistics.lookups, n);
+        let found = results.into_iter().map(|(_, objects)| objects.count());
+        assert_eq!(found.sum::<usize>(), count(&query, &parameters));
+    }
+
+    #[test]
+    fn explain() {
+        let db = database();
+        let parameters = parameters(&db);
+
+        let near = Bag::inside(Shape::sphere(center(&db), 1.0).in_space(SPACE));
+        let label = Bag::inside(Shape::label("unknown").in_space(SPACE));
+        let query = near.clone().intersection(label).union(near).distinct();
+
+        // The catalog used by the explanation, with the labels it looks
+        // up.
+        let catalog = Catalog::default();
+        let catalog = catalog.with_labels_of(CORE, &parameters, &query);
+
+        // The costs of the nodes are computed from the ones of their
+        // operands, as they are predicted for the whole expression.
+        let plan = query.explain_with(CORE, &parameters, &catalog).unwrap();
+        let union = &plan.operands[0];
+        let intersection = &union.operands[0];
+        assert_eq!(
+            plan.prediction,
+            query.predict_with(CORE, &parameters, &catalog).unwrap()
+        );
+        assert_eq!(
+            intersection.prediction,
+            query.operands()[0].operands()[0]
+                .predict_with(CORE, &parameters, &catalog)
+                .unwrap()
+        );
+        let operands = [intersection.prediction, union.operands[1].prediction];
+        assert_eq!(
+            union.prediction,
+            query.operands()[0]
+                .predict_from(&operands, CORE, &parameters, &catalog)
+                .unwrap()
+        );
+
+        // Operands are required to predict a bag from them.
+        assert!(query
+            .predict_from(&[], CORE, &parameters, &catalog)
+            .is_err());
+
+        // The left operand is the smaller one, but the results of paged
+        // queries are always in the order of the left operand.
+        let left = "hash the positions of the left operand, probe with the right one";
+        let right = "hash the positions of the right operand, probe with the left one";
+        assert_eq!(intersection.strategy.as_deref(), Some(left));
+
+        let paged = query.limit(10);
+        assert!(paged.is_paged());
+        let plan = paged.explain_with(CORE, &parameters, &catalog).unwrap();
+        let intersection = &plan.operands[0].operands[0].operands[0];
+        assert_eq!(intersection.strategy.as_deref(), Some(right));
+    }
+
+    #[test]
+    fn analyze() {
+        let db = database();
+        let parameters = parameters(&db);
+
+        let near = Bag::inside(Shape::sphere(center(&db), 1.0).in_space(SPACE));
+        let query = near.clone().union(near).distinct();
+
+        // Same results as the executor, with the statistics of each node.
+        let (results, statistics) = query.analyze(CORE, &parameters).unwrap();
+        let analyzed = results
+            .into_iter()
+            .map(|(_, objects)| objects.count())
+            .sum::<usize>();
+        assert_eq!(analyzed, count(&query, &parameters));
+
+        assert_eq!(statistics.operation, "distinct");
+        assert_eq!(statistics.count(), analyzed);
+        let union = &statistics.operands[0];
+        assert_eq!(union.operation, "union");
+        assert_eq!(union.operands.len(), 2);
+        assert_eq!(union.count(), 2 * union.operands[0].count());
+        assert!(union.operands[0].count() > 0);
+    }
+
+    #[test]
+    fn shapes() {
+        let db = database();
+        let parameters = parameters(&db);
+        let rasterized = |shape: Shape, parameters: &CoreQueryParameters| {
+            ordered(&Bag::shape(shape.in_space(SPACE)), parameters)
+        };
+
+        let point = rasterized(Shape::point(vec![1.0, 2.0, 3.0]), &parameters);
+        let expected: space::Position = LiteralPosition::from(vec![1.0, 2.0, 3.0]).into();
+        assert_eq!(point, vec![expected]);
+
+        // Positions on the grid of the voxels of the space, within the
+        // shapes, each level of resolution doubling the voxels.
+        let voxel = crate::executors::voxel_size(db.space(SPACE).unwrap(), 3, &[]);
+        let rectangle = Shape::hyperrectangle(
+            vec![0.0, 0.0, 0.0],
+            vec![4.0 * voxel[0], 2.0 * voxel[1], 2.0 * voxel[2]],
+        );
+        let positions = rasterized(rectangle.clone(), &parameters);
+        assert_eq!(positions.len(), 45);
+        for position in positions {
+            let position = Vec::<f64>::from(&position);
+            for (x, v) in position.iter().zip(&voxel) {
+                assert!(((x / v).round() - x / v).abs() < 1e-6);
+            }
+        }
+        let resolution = Some(vec![1]);
+        let coarser = CoreQueryParameters {
+            resolution: &resolution,
+            ..parameters
+        };
+        assert_eq!(rasterized(rectangle, &coarser).len(), 12);
+
+        let sphere = Shape::sphere(vec![0.0, 0.0, 0.0], 2.0 * voxel[0]);
+        let n = rasterized(sphere.clone(), &parameters).len();
+        assert!(n > 0);
+
+        // Positions are expressed in the output space.
+        let universe = space::Space::universe().name();
+        let output = CoreQueryParameters {
+            output_space: Some(universe.as_str()),
+            ..parameters
+        };
+        let from = db.space(SPACE).unwrap();
+        let to = db.space(universe).unwrap();
+        let expected = rasterized(sphere.clone(), &parameters)
+            .iter()
+            .map(|position| space::Space::change_base(position, from, to).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(rasterized(sphere.clone(), &output), expected);
+
+        // Positions can be combined with the ones of other bags.
+        let bag = Bag::shape(sphere.in_space(SPACE));
+        assert_eq!(count(&bag.clone().union(bag.clone()), &parameters), 2 * n);
+        assert_eq!(count(&bag.clone().intersection(bag), &parameters), n);
+
+        // The positions of labels are only known from the objects.
+        let label = Bag::shape(Shape::label("unknown").in_space(SPACE));
+        assert!(label.execute(CORE, &parameters).is_err());
+    }
+
+    // Volume produced by the NIfTI projection of the bag.
+    fn project(bag: Bag, parameters: &CoreQueryParameters) -> crate::nifti::Volume {
+        let projection = Projection::nifti(None, bag).in_space(SPACE);
+        match projection.execute(CORE, parameters).unwrap() {
+            ProjectionResult::Nifti(bytes) => crate::nifti::Volume::from_bytes(&bytes).unwrap(),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn nifti() {
+        let db = database();
+        let parameters = parameters(&db);
+
+        // One voxel set per position of the shape.
+        let voxel = crate::executors::voxel_size(db.space(SPACE).unwrap(), 3, &[]);
+        let rectangle = Shape::hyperrectangle(
+            vec![0.0, 0.0, 0.0],
+            vec![2.0 * voxel[0], voxel[1], voxel[2]],
+        );
+        let volume = project(Bag::shape(rectangle.in_space(SPACE)), &parameters);
+        assert_eq!(volume.non_zero().count(), 12);
+        assert_eq!(volume.get(&[2, 1, 1]), Some(1.0));
+        assert_eq!(volume.get(&[3, 1, 1]), None);
+
+        // Voxels of the size of the ones of the space, the first one
+        // starting at the origin.
+        let centre = volume.affine().apply(&[2.0, 1.0, 1.0]);
+        let expected = [2.5 * voxel[0], 1.5 * voxel[1], 1.5 * voxel[2]];
+        for (x, e) in centre.iter().zip(&expected) {
+            assert!((x - e).abs() < 1e-6 * e.abs().max(1.0));
+        }
+
+        // At most one voxel per object.
+        let objects = Bag::inside(Shape::sphere(center(&db), 1.0).in_space(SPACE));
+        let n = count(&objects, &parameters);
+        let voxels = project(objects, &parameters).non_zero().count();
+        assert!(n > 0);
+        assert!(0 < voxels && voxels <= n);
+
+        // Two objects far apart need a volume too large to be built.
+        let (low, high) = db.space(SPACE).unwrap().bounding_box();
+        let (low, high) = (Vec::<f64>::from(&low), Vec::<f64>::from(&high));
+        let at = |t: f64| {
+            let position = low.iter().zip(&high).map(|(l, h)| l + t * (h - l));
+            Bag::shape(Shape::point(position.collect::<Vec<_>>()).in_space(SPACE))
+        };
+        let voxel = crate::executors::voxel_size(db.space(SPACE).unwrap(), 3, &[]);
+        let voxels: f64 = low
+            .iter()
+            .zip(&high)
+            .zip(&voxel)
+            .map(|((l, h), v)| 0.8 * (h - l) / v)
+            .product();
+        assert!(voxels > (1u64 << 24) as f64);
+
+        let sparse = Projection::nifti(None, at(0.1).union(at(0.9))).in_space(SPACE);
+        let error = sparse.execute(CORE, &parameters).unwrap_err();
+        assert!(error.contains("too many voxels"), "{}", error);
+    }
+
+    // Positions of the objects of the bag, in the order of the results.
+    fn ordered(bag: &Bag, parameters: &CoreQueryParameters) -> Vec<space::Position> {
+        bag.execute(CORE, parameters)
+            .unwrap()
+            .into_iter()
+            .flat_map(|(_, objects)| objects.map(|(position, _)| position))
+            .collect()
+    }
+
+    #[test]
+    fn paging() {
+        let db = database();
+        let parameters = parameters(&db);
+
+        let objects = Bag::outside(Shape::point(vec![0.0, 0.0, 0.0]).in_space(SPACE));
+        let all = ordered(&objects.clone().offset(0), &parameters);
+        assert!(all.len() > 2);
+
+        assert_eq!(ordered(&objects.clone().limit(2), &parameters), all[..2]);
+        assert_eq!(ordered(&objects.clone().offset(2), &parameters), all[2..]);
+        assert!(ordered(&objects.clone().offset(all.len()), &parameters).is_empty());
+
+        // Reading the results one page after the other gives all of
+        // them, in the same order.
+        let size = all.len() / 3 + 1;
+        let mut cursor = Cursor::start(&objects);
+        let mut pages = vec![];
+        loop {
+            let page = ordered(&objects.clone().page(&cursor, size), &parameters);
+            cursor = cursor.advance(page.len());
+            let last = page.len() < size;
+            pages.extend(page);
+            if last {
+                break;
+            }
+        }
+        assert_eq!(pages, all);
+    }
+
+    // Documents produced by the JSON projection of the bag.
+    fn documents<J: Into<JsonValue>>(
+        format: J,
+        bag: Bag,
+        parameters: &CoreQueryParameters,
+    ) -> Vec<serde_json::Value> {
+        let projection = Projection::json(format, bag).in_space(SPACE);
+        match projection.execute(CORE, parameters).unwrap() {
+            ProjectionResult::Json(documents) => documents,
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn projections() {
+        let db = database();
+        let parameters = parameters(&db);
+
+        // Positions are expressed in the space of the projection.
+        let output = CoreQueryParameters {
+            output_space: Some(SPACE),
+            ..parameters
+        };
+        let objects = Bag::inside(Shape::sphere(center(&db), 1.0).in_space(SPACE));
+        let expected = objects
+            .execute(CORE, &output)
+            .unwrap()
+            .into_iter()
+            .flat_map(|(space_id, objects)| {
+                objects.map(move |(position, properties)| {
+                    serde_json::json!({
+                        "id": properties.id(),
+                        "type": properties.type_name(),
+                        "space": space_id,
+                        "position": Vec::<f64>::from(&position),
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        assert!(!expected.is_empty());
+
+        // One document per object, with the selectors resolved on it.
+        let root = LiteralSelector::root();
+        let format = JsonValue::Object(vec![
+            ("id".to_string(), root.clone().field("id").into()),
+            ("type".to_string(), root.clone().field("type").into()),
+            (
+                "space".to_string(),
+                root.clone().field("reference_space").into(),
+            ),
+            (
+                "position".to_string(),
+                root.clone().field("position").into(),
+            ),
+        ]);
+        assert_eq!(documents(format, objects.clone(), &parameters), expected);
+
+        // The properties are also available under `.properties`.
+        let id = root.clone().field("properties").field("id");
+        let ids = expected.iter().map(|d| d["id"].clone()).collect::<Vec<_>>();
+        assert_eq!(documents(id, objects.clone(), &parameters), ids);
+
+        // Constants are kept as they are.
+        let constant = serde_json::json!({ "a": [1, 2.5, null], "b": "c" });
+        let template = JsonValue::try_from(constant.clone()).unwrap();
+        let constants = documents(template, objects.clone(), &parameters);
+        assert_eq!(constants, vec![constant; expected.len()]);
+
+        // Unknown fields are reported.
+        let unknown = Projection::json(root.field("unknown"), objects).in_space(SPACE);
+        assert!(unknown.execute(CORE, &parameters).is_err());
+    }
+
+    #[test]
+    fn aggregations() {
+        let db = database();
+        let parameters = parameters(&db);
+
+        let objects = Bag::inside(Shape::sphere(center(&db), 1.0).in_space(SPACE));
+        let n = count(&objects, &parameters);
+        assert!(n > 0);
+
+        // A single document, computed over all the objects.
+        let x = LiteralSelector::root().field("position").index(0);
+        let format = JsonValue::Object(vec![
+            (
+                "n".to_string(),
+                Aggregation::count(LiteralSelector::root()).into(),
+            ),
+            (
+                "x".to_string(),
+                JsonValue::Array(vec![
+                    Aggregation::sum(x.clone()).into(),
+                    Aggregation::min(x.clone()).into(),
+                    Aggregation::max(x.clone()).into(),
+                ]),
+            ),
+        ]);
+        let aggregated = documents(format, objects.clone(), &parameters);
+        assert_eq!(aggregated.len(), 1);
+        assert_eq!(aggregated[0]["n"], serde_json::json!(n));
+
+        let xs = documents(x.clone(), objects.clone(), &parameters)
+            .iter()
+            .map(|x| x.as_f64().unwrap())
+            .collect::<Vec<_>>();
+        let sum = aggregated[0]["x"][0].as_f64().unwrap();
+        assert!((sum - xs.iter().sum::<f64>()).abs() < 1e-6 * sum.abs().max(1.0));
+        let min = xs.iter().cloned().fold(f64::INFINITY, f64::min);
+        let max = xs.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        assert_eq!(aggregated[0]["x"][1].as_f64(), Some(min));
+        assert_eq!(aggregated[0]["x"][2].as_f64(), Some(max));
+
+        // Objects read twice are counted once when distinct.
+        let twice = objects.clone().union(objects);
+        let position = LiteralSelector::root().field("position");
+        let every = Aggregation::count(position.clone());
+        let once = Aggregation::count_distinct(position);
+        let all = documents(every, twice.clone(), &parameters);
+        let distinct = documents(once, twice, &parameters);
+        assert_eq!(all, vec![serde_json::json!(2 * n)]);
+        assert_eq!(distinct, vec![serde_json::json!(n)]);
+
+        // Numbers are compared by value.
+        use crate::executors::same_value;
+        use serde_json::json;
+        assert!(same_value(&json!(1), &json!(1.0)));
+        assert!(same_value(&json!(-0.0), &json!(0)));
+        assert!(same_value(
+            &json!([1, {"a": 2.0, "b": null}]),
+            &json!([1.0, {"b": null, "a": 2}])
+        ));
+        assert!(!same_value(&json!(1), &json!(1.5)));
+        assert!(!same_value(&json!(1), &json!("1")));
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod serialization {
+    use crate::queries;
+    use crate::serialization::*;
+    use crate::Bag;
+    use crate::Projection;
+
+    #[test]
+    fn json() {
+        let bag = queries::FiltersParser::new()
+            .parse("filter(<(.[0], [1.5]), union(inside(point{[0], \"s\"}), outside(label{\"a\\\"b\", \"s\"})))")
+            .unwrap();
+
+        let encoded = to_json(&bag).unwrap();
+        assert!(encoded.starts_with(&format!("{{\"version\":{},", VERSION)));
+        assert!(encoded.contains("{\"query\":{\"filter\":[{\"less\":"));
+        assert!(!encoded.contains("start"));
+        assert!(from_json::<Bag>(&encoded).unwrap().same_as(&bag));
+
+        // Newer, or unknown encodings are rejected.
+        let newer = encoded.replacen(
+            &format!("\"version\":{}", VERSION),
+            &format!("\"version\":{}", VERSION + 1),
+            1,
+        );
+        assert!(from_json::<Bag>(&newer).is_err());
+        assert!(from_json::<Bag>("{\"query\": null}").is_err());
+
+        // Queries without paging are encoded the same way since the
+        // first version.
+        let first = encoded.replacen(&format!("\"version\":{}", VERSION), "\"version\":1", 1);
+        assert!(from_json::<Bag>(&first).unwrap().same_as(&bag));
+    }
+
+    #[test]
+    fn bytes() {
+        let projection = queries::QueryParser::new()
+            .parse("json({\"n\": count(distinct .id)}, inside(hypersphere{[0, 0], 2.5}), \"s\")")
+            .unwrap()
+            .unwrap();
+
+        let encoded = to_bytes(&projection).unwrap();
+        assert!(from_bytes::<Projection>(&encoded)
+            .unwrap()
+            .same_as(&projection));
+
+        let mut newer = encoded.clone();
+        newer[0] = (VERSION + 1) as u8;
+        assert!(from_bytes::<Projection>(&newer).is_err());
+        assert!(from_bytes::<Projection>(&encoded[..encoded.len() - 1]).is_err());
+    }
+}
@@ -1,46 +1,194 @@
+use std::collections::HashMap;
+
+use mercator_db::space::Space;
+use mercator_db::DataBase;
+
+use super::symbols::LiteralPosition;
+
 #[derive(Clone, Debug)]
 pub enum LiteralTypes {
     String,
     Int,
     Float,
-    Bag(Vec<LiteralTypes>),          // List of types (heterogeneous)
-    Vector(Vec<LiteralTypes>),       // List of coordinates types (heterogeneous)
-    Array(usize, Box<LiteralTypes>), // Length, homogeneous type
+    Bag(Vec<LiteralTypes>),              // List of types (heterogeneous)
+    Vector(Vec<LiteralTypes>),           // List of coordinates types (heterogeneous)
+    Coordinates,                         // Position of unknown dimensions
+    Array(usize, Box<LiteralTypes>),     // Length, homogeneous type
+    Object(Vec<(String, LiteralTypes)>), // Named fields
 }
 
+// Integers and floats are compatible, as are positions of unknown
+// dimensions with any vector of numbers.
 impl PartialEq for LiteralTypes {
     fn eq(&self, other: &Self) -> bool {
+        fn number(t: &LiteralTypes) -> bool {
+            matches!(t, LiteralTypes::Int | LiteralTypes::Float)
+        }
+
+        match (self, other) {
+            (LiteralTypes::String, LiteralTypes::String) => true,
+            (LiteralTypes::Int, _) | (LiteralTypes::Float, _) => number(other),
+            (LiteralTypes::Bag(_), LiteralTypes::Bag(_)) => true,
+            (LiteralTypes::Vector(v), LiteralTypes::Vector(ov)) => v == ov,
+            (LiteralTypes::Coordinates, LiteralTypes::Coordinates) => true,
+            (LiteralTypes::Coordinates, LiteralTypes::Vector(v))
+            | (LiteralTypes::Vector(v), LiteralTypes::Coordinates) => v.iter().all(number),
+            (LiteralTypes::Array(n, t), LiteralTypes::Array(on, ot)) => n == on && t == ot,
+            (LiteralTypes::Object(fields), LiteralTypes::Object(ofields)) => {
+                fields.len() == ofields.len()
+                    && fields
+                        .iter()
+                        .zip(ofields)
+                        .all(|((n, t), (on, ot))| n == on && t == ot)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl LiteralTypes {
+    /// Type of the field `name`, when this is an object providing it.
+    pub fn field(&self, name: &str) -> Option<&LiteralTypes> {
         match self {
-            LiteralTypes::String => matches!(other, LiteralTypes::String),
-            LiteralTypes::Int => matches!(other, LiteralTypes::Int),
-            LiteralTypes::Float => matches!(other, LiteralTypes::Float | LiteralTypes::Int),
-            LiteralTypes::Bag(_) => matches!(other, LiteralTypes::Bag(_)),
-            LiteralTypes::Vector(v) => match other {
-                LiteralTypes::Vector(ov) => {
-                    let n = v.len();
-                    if n != ov.len() {
-                        false
-                    } else {
-                        for i in 0..n {
-                            if v[i] != ov[i] {
-                                return false;
-                            }
-                        }
-                        true
-                    }
-                }
-                _ => false,
-            },
-            LiteralTypes::Array(n, t) => match other {
-                LiteralTypes::Array(on, ot) => {
-                    if on == n {
-                        t == ot
-                    } else {
-                        false
-                    }
+            LiteralTypes::Object(fields) => fields.iter().find(|(n, _)| n == name).map(|(_, t)| t),
+            _ => None,
+        }
+    }
+}
+
+/// Description of the fields available on the indexed objects, used to
+/// infer the types of selectors.
+///
+/// The only variable part of the data model is the dimensionality of the
+/// positions, which depends on the reference space. It is unknown, and
+/// not checked, unless given or read from a database. When a database is
+/// provided, the reference spaces are checked to exist, and literal
+/// positions to fit within their bounds.
+#[derive(Clone, Debug, Default)]
+pub struct Schema<'d> {
+    dimensions: HashMap<String, usize>,
+    default_dimensions: Option<usize>,
+    database: Option<&'d DataBase>,
+}
+
+impl<'d> Schema<'d> {
+    /// Schema where positions have `dimensions` coordinates, in every
+    /// reference space.
+    pub fn new(dimensions: usize) -> Self {
+        Schema {
+            dimensions: HashMap::new(),
+            default_dimensions: Some(dimensions),
+            database: None,
+        }
+    }
+
+    /// Set the number of dimensions of the positions in `space`.
+    pub fn with_space(mut self, space: &str, dimensions: usize) -> Self {
+        self.dimensions.insert(space.to_string(), dimensions);
+        self
+    }
+
+    /// Use the reference spaces defined in `db`.
+    pub fn with_database(mut self, db: &'d DataBase) -> Self {
+        self.database = Some(db);
+        self
+    }
+
+    // The universe is not stored in the database, and accepts any
+    // position.
+    fn space(&self, space: &str) -> Option<&'d Space> {
+        match self.database {
+            Some(db) if space != Space::universe().name() => db.space(space).ok(),
+            _ => None,
+        }
+    }
+
+    // Bounds of the space, as (low, high), when known.
+    fn bounds(&self, space: &str) -> Option<(Vec<f64>, Vec<f64>)> {
+        let (low, high) = self.space(space)?.bounding_box();
+        let low: LiteralPosition = low.into();
+        let high: LiteralPosition = high.into();
+
+        Some((low.into(), high.into()))
+    }
+
+    // Number of dimensions of the space, when known.
+    fn known_dimensions(&self, space: &str) -> Option<usize> {
+        match self.dimensions.get(space) {
+            Some(dimensions) => Some(*dimensions),
+            None => self.bounds(space).map(|(low, _)| low.len()),
+        }
+    }
+
+    /// Number of dimensions of the positions in `space`, if known.
+    pub fn dimensions(&self, space: &str) -> Option<usize> {
+        self.known_dimensions(space).or(self.default_dimensions)
+    }
+
+    /// Check `space` is a reference space of the database, if any.
+    pub fn check_space(&self, space: &str) -> Result<(), String> {
+        match self.database {
+            Some(_) if space != Space::universe().name() && self.space(space).is_none() => {
+                Err(format!("Unknown reference space '{}'", space))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check `position` has the dimensions of `space`, and is within its
+    /// bounds, when they are known.
+    pub fn check_position(&self, space: &str, position: &[f64]) -> Result<(), String> {
+        self.check_space(space)?;
+
+        if let Some(dimensions) = self.known_dimensions(space) {
+            if position.len() != dimensions {
+                return Err(format!(
+                    "Position {:?} has {} dimensions, but space '{}' has {}",
+                    position,
+                    position.len(),
+                    space,
+                    dimensions
+                ));
+            }
+        }
+
+        if let Some((low, high)) = self.bounds(space) {
+            for (i, ((x, l), h)) in position.iter().zip(&low).zip(&high).enumerate() {
+                if x < l || x > h {
+                    return Err(format!(
+                        "Position {:?} is outside of space '{}': coordinate {} is {}, but has to be within [{}, {}]",
+                        position, space, i, x, l, h
+                    ));
                 }
-                _ => false,
-            },
+            }
         }
+
+        Ok(())
+    }
+
+    /// Type of the positions in `space`.
+    pub fn position(&self, space: &str) -> LiteralTypes {
+        match self.dimensions(space) {
+            Some(dimensions) => LiteralTypes::Vector(vec![LiteralTypes::Float; dimensions]),
+            None => LiteralTypes::Coordinates,
+        }
+    }
+
+    /// Type of the objects in `space`, the root of the selectors.
+    pub fn object(&self, space: &str) -> LiteralTypes {
+        LiteralTypes::Object(vec![
+            ("reference_space".to_string(), LiteralTypes::String),
+            ("position".to_string(), self.position(space)),
+            (
+                "properties".to_string(),
+                LiteralTypes::Object(vec![
+                    ("id".to_string(), LiteralTypes::String),
+                    ("type".to_string(), LiteralTypes::String),
+                ]),
+            ),
+            // Shorthands for the properties
+            ("id".to_string(), LiteralTypes::String),
+            ("type".to_string(), LiteralTypes::String),
+        ])
     }
 }
@@ -1,20 +1,42 @@
+use super::error::Error;
+use super::error::Span;
 use super::expressions::Validator;
 use super::symbols::*;
 
-pub type ValidationResult = Result<LiteralTypes, String>;
+pub type ValidationResult = Result<LiteralTypes, Error>;
 
 impl Validator for Projection {
     type ValidationResult = self::ValidationResult;
 
-    fn validate(&self) -> ValidationResult {
+    fn validate_with(&self, schema: &Schema) -> ValidationResult {
+        let error = |message| Error::validation(self.span(), message);
+
         match self {
-            Projection::Nifti(_, _, _) => Err("not yet implemented".to_string()),
-            Projection::Json(_, _format, bag) => bag.validate(),
-            //FIXME: Add support for projections
-            /* match format.validate() {
-                Ok(_) => bag.validate(),
-                Err(_) => Err(()),
-            }*/
+            Projection::Nifti(space_id, selector, bag, _) => {
+                schema.check_space(space_id).map_err(error)?;
+
+                let LiteralSelector(fields) = selector;
+                if !fields.is_empty() {
+                    match selector.get_type(schema, bag.space()).map_err(error)? {
+                        LiteralTypes::Int | LiteralTypes::Float => (),
+                        t => {
+                            return Err(error(format!(
+                                "Nifti: the selector has to resolve to a number, not '{:?}'",
+                                t
+                            )))
+                        }
+                    }
+                }
+
+                bag.validate_with(schema)
+            }
+            Projection::Json(space_id, format, bag, _) => {
+                schema.check_space(space_id).map_err(error)?;
+                format
+                    .validate(schema, bag.space(), format.has_aggregation())
+                    .map_err(error)?;
+                bag.validate_with(schema)
+            }
         }
     }
 }
@@ -22,18 +44,21 @@ impl Validator for Projection {
 impl Validator for Bag {
     type ValidationResult = self::ValidationResult;
 
-    fn validate(&self) -> ValidationResult {
-        fn compare_bag_types(lh: &Bag, rh: &Bag) -> ValidationResult {
+    fn validate_with(&self, schema: &Schema) -> ValidationResult {
+        fn compare_bag_types(lh: &Bag, rh: &Bag, schema: &Schema) -> ValidationResult {
             if lh.space().cmp(rh.space()) != std::cmp::Ordering::Equal {
-                return Err(format!(
-                    "left and right sets are defined in different reference spaces: '{}' vs '{}'.",
-                    lh.space(),
-                    rh.space()
+                return Err(Error::validation(
+                    rh.span(),
+                    format!(
+                        "left and right sets are defined in different reference spaces: '{}' vs '{}'.",
+                        lh.space(),
+                        rh.space()
+                    ),
                 ));
             }
 
-            let l = lh.validate();
-            let r = rh.validate();
+            let l = lh.validate_with(schema);
+            let r = rh.validate_with(schema);
 
             match &l {
                 Err(_) => l,
@@ -41,9 +66,12 @@ impl Validator for Bag {
                     e @ Err(_) => e,
                     Ok(tr) => {
                         if tl != &tr {
-                            Err(format!(
-                                "Incoherent types between left and right sets: '{:?}' vs '{:?}'",
-                                tl, &tr
+                            Err(Error::validation(
+                                rh.span(),
+                                format!(
+                                    "Incoherent types between left and right sets: '{:?}' vs '{:?}'",
+                                    tl, &tr
+                                ),
                             ))
                         } else {
                             l
@@ -53,119 +81,206 @@ impl Validator for Bag {
             }
         }
 
+        // Errors found at this level point to the whole expression.
+        fn shape(shape: &Shape, schema: &Schema, span: Span) -> ValidationResult {
+            shape
+                .validate_with(schema)
+                .map_err(|message| Error::validation(span, message))
+        }
+
         match self {
-            Bag::Distinct(bag) => bag.validate(),
-            Bag::Filter(_, bag) => bag.validate(),
-            Bag::Complement(bag) => bag.validate(),
-            Bag::Intersection(lh, rh) => compare_bag_types(lh, rh),
-            Bag::Union(lh, rh) => compare_bag_types(lh, rh),
-            Bag::Bag(bags) => {
+            Bag::Distinct(bag, _) => bag.validate_with(schema),
+            Bag::Limit(_, bag, _) | Bag::Offset(_, bag, _) => bag.validate_with(schema),
+            Bag::Filter(predicate, bag, span) => {
+                let t = bag.validate_with(schema)?;
+                if let Some(predicate) = predicate {
+                    predicate
+                        .validate(schema, bag.space())
+                        .map_err(|message| Error::validation(*span, message))?;
+                }
+
+                Ok(t)
+            }
+            Bag::Complement(bag, _) => bag.validate_with(schema),
+            Bag::Intersection(lh, rh, _) => compare_bag_types(lh, rh, schema),
+            Bag::Union(lh, rh, _) => compare_bag_types(lh, rh, schema),
+            Bag::Bag(bags, _) => {
                 for b in bags {
-                    b.validate()?;
+                    b.validate_with(schema)?;
                 }
 
-                Ok(get_type())
+                Ok(schema.position(self.space()))
             }
-            Bag::Inside(shape) => shape.validate(),
-            Bag::Outside(shape) => shape.validate(),
+            Bag::Inside(s, span) => shape(s, schema, *span),
+            Bag::Outside(s, span) => shape(s, schema, *span),
+            Bag::Shape(Shape::Label(_, id), span) => Err(Error::validation(
+                *span,
+                format!(
+                    "the positions of label '{}' are only known from the objects, use inside() instead.",
+                    id
+                ),
+            )),
+            Bag::Shape(s, span) => shape(s, schema, *span),
         }
     }
 }
 
 impl Validator for Shape {
-    type ValidationResult = self::ValidationResult;
+    type ValidationResult = Result<LiteralTypes, String>;
 
-    fn validate(&self) -> ValidationResult {
+    fn validate_with(&self, schema: &Schema) -> Self::ValidationResult {
+        // The reference space has to exist, and the literal positions
+        // have to be valid in it.
         match self {
-            Shape::Point(_, v) => v.validate(),
-            Shape::HyperRectangle(_space, pos) => {
-                let first = pos[0].get_type();
-                match pos.len() {
-                    2 => {
-                        if first != pos[1].get_type() {
-                            Err(format!(
-                                "HyperRectangle: Incompatible types in points definitions: '{:?}' vs '{:?}'",
-                                first,
-                                pos[1].get_type()
-                            ))
-                        } else {
-                            Ok(first)
-                        }
-                    }
-                    _ => {
-                        //FIXME: Implement arbitrary hypercube definition support. For now reject.
-                        Err("not yet implemented".to_string())
-                        /*
-                        fn check_orthogonal(pos: &Vec<LiteralPosition>) -> bool {
-                            let k = pos.len();
-                            let mut raw_pos = vec![];
-
-                            for s in pos {
-                                match s {
-                                    LiteralTypes::Vector(n) => {
-                                        let mut v = vec![];
-                                        for c in n {
-                                            v.push(match c {
-                                                LiteralNumber::Int(x) => x as f64,
-                                                LiteralNumber::Float(x) => x,
-                                            });
-                                        }
-                                        raw_pos.push(v)
-                                    }
-                                    _ => pass,
-                                }
-                            }
-
-                            for p in raw_pos {}
-                            true
-                        }
-                        match &first {
-                            LiteralTypes::Vector(n) => {
-                                if 2usize.pow(n.len() as u32) == pos.len() {
-                                    // Check we have coherent types for the coordinates.
-                                    for point in pos {
-                                        if first != point.get_type() {
-                                            return Err(());
-                                        }
-                                    }
-
-                                    // We need to check the points define a shape with orthogonal faces.
-                                    if check_orthogonal(pos) {
-                                        Ok(first)
-                                    } else {
-                                        Err(())
-                                    }
-                                } else {
-                                    Err(())
-                                }
-                            }
-                            _ => Err(()),
-                        }*/
+            Shape::Point(space, position) | Shape::HyperSphere(space, position, _) => {
+                schema.check_position(space, &Vec::from(position))?
+            }
+            Shape::HyperRectangle(space, vertices) => {
+                for position in vertices {
+                    schema.check_position(space, &Vec::from(position))?;
+                }
+            }
+            Shape::Label(space, _) => schema.check_space(space)?,
+            Shape::Nifti(transform, _) => {
+                schema.check_space(&transform.reference)?;
+
+                if let Some(dimensions) = schema.dimensions(&transform.reference) {
+                    if dimensions == 0 || dimensions > 3 {
+                        return Err(format!(
+                            "Nifti: volumes have at most 3 dimensions, but space '{}' has {}",
+                            transform.reference, dimensions
+                        ));
                     }
                 }
             }
-            Shape::HyperSphere(_, pos, _) => pos.validate(),
-            Shape::Label(_, _) => {
-                // FIXME: Quick Hack, we need to fix this and return the effective type of the object Id.
-                Ok(LiteralPosition(vec![]).get_type())
+        }
+
+        match self {
+            Shape::Point(_, v) => v.validate_with(schema),
+            Shape::HyperRectangle(_space, pos) => match pos.as_slice() {
+                [] | [_] => Err(format!(
+                    "HyperRectangle: at least 2 vertices are required, got {}",
+                    pos.len()
+                )),
+                [low, high] => {
+                    if low.get_type() != high.get_type() {
+                        Err(format!(
+                            "HyperRectangle: Incompatible types in points definitions: '{:?}' vs '{:?}'",
+                            low.get_type(),
+                            high.get_type()
+                        ))
+                    } else {
+                        Ok(low.get_type())
+                    }
+                }
+                [first, ..] => {
+                    // We need to check the points define a shape with
+                    // orthogonal faces, which also checks all the
+                    // points have the same number of dimensions.
+                    OrientedBox::new(pos)?;
+
+                    // Vertices of arbitrary hyperrectangles are
+                    // computed, so mixing integers and floats is
+                    // expected.
+                    Ok(LiteralTypes::Vector(vec![
+                        LiteralTypes::Float;
+                        first.dimensions()
+                    ]))
+                }
+            },
+            Shape::HyperSphere(_, pos, _) => pos.validate_with(schema),
+            Shape::Label(space, _) => Ok(schema.position(space)),
+            Shape::Nifti(transform, provider) => {
+                transform.affine()?;
+
+                // In-line payloads are available, so check they are well
+                // formed. They are only decompressed at execution.
+                provider.check()?;
+
+                Ok(schema.position(&transform.reference))
             }
-            Shape::Nifti(_) => Err("not yet implemented".to_string()),
         }
     }
 }
 
 impl Validator for LiteralPosition {
-    type ValidationResult = self::ValidationResult;
+    type ValidationResult = Result<LiteralTypes, String>;
 
-    fn validate(&self) -> ValidationResult {
+    fn validate_with(&self, _schema: &Schema) -> Self::ValidationResult {
         Ok(self.get_type())
     }
 }
 
-impl Validator for LiteralSelector {
-    type ValidationResult = self::ValidationResult;
+impl JsonValue {
+    // Check the selectors of the template resolve on the objects of
+    // `space`, and the aggregations are computed over numbers, when they
+    // have to. Templates with aggregations produce a single document, so
+    // they cannot contain selectors outside of the aggregations.
+    fn validate(&self, schema: &Schema, space: &str, aggregated: bool) -> Result<(), String> {
+        match self {
+            JsonValue::Selector(selector) => {
+                selector.get_type(schema, space)?;
+                if aggregated {
+                    return Err(format!(
+                        "Proj-Json: selector {} cannot be used outside of an aggregation when aggregations are used",
+                        selector
+                    ));
+                }
 
-    fn validate(&self) -> ValidationResult {
-        Ok(self.get_type())
+                Ok(())
+            }
+            JsonValue::Aggregation(Aggregation::Count(_, selector)) => {
+                selector.get_type(schema, space).map(|_| ())
+            }
+            JsonValue::Aggregation(Aggregation::Sum(selector))
+            | JsonValue::Aggregation(Aggregation::Min(selector))
+            | JsonValue::Aggregation(Aggregation::Max(selector)) => {
+                match selector.get_type(schema, space)? {
+                    LiteralTypes::Int | LiteralTypes::Float => Ok(()),
+                    t => Err(format!(
+                        "Proj-Json: selector {} has to resolve to a number, not '{:?}'",
+                        selector, t
+                    )),
+                }
+            }
+            JsonValue::Object(pairs) => pairs
+                .iter()
+                .try_for_each(|(_, value)| value.validate(schema, space, aggregated)),
+            JsonValue::Array(values) => values
+                .iter()
+                .try_for_each(|value| value.validate(schema, space, aggregated)),
+            JsonValue::String(_)
+            | JsonValue::JsonNumber(_)
+            | JsonValue::Bool(_)
+            | JsonValue::Null => Ok(()),
+        }
+    }
+}
+
+impl Predicate {
+    // Check the selectors of the predicate resolve to values comparable
+    // to the literals, for objects of `space`.
+    fn validate(&self, schema: &Schema, space: &str) -> Result<(), String> {
+        match self {
+            Predicate::Not(predicate) => predicate.validate(schema, space),
+            Predicate::And(lh, rh) | Predicate::Or(lh, rh) => {
+                lh.validate(schema, space)?;
+                rh.validate(schema, space)
+            }
+            Predicate::Less(position, literal)
+            | Predicate::Greater(position, literal)
+            | Predicate::Equal(position, literal) => {
+                let t = position.get_type(schema, space)?;
+                if t != literal.get_type() {
+                    Err(format!(
+                        "Predicate: Incompatible types in comparison: '{:?}' vs '{:?}'",
+                        t,
+                        literal.get_type()
+                    ))
+                } else {
+                    Ok(())
+                }
+            }
+        }
     }
 }